@@ -0,0 +1,303 @@
+//! ## Allow list
+//!
+//! Every file in a package is checked before anything is written. The metadata json and the files named in it are expected.
+//! Other files can be allowed with glob patterns relative to the package folder, such as `images/*.png`.
+//! Anything else is unexpected, and the package fails, or the file is dropped or kept, depending on the policy.
+//! Files outside the package folder are always unexpected. If they are kept, they are copied to the same path
+//! in the anonymised package by both processors.
+//!
+//! A decision is logged for every file and listed in the audit record, or in the plan for a dry run.
+use crate::error::AnonymiserError;
+use crate::payload::PackageModel;
+use crate::reference::BatchReference;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// # What happens to files in the package which are not expected
+#[derive(clap::ValueEnum, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnexpectedFileAction {
+    /// Don't anonymise the package
+    #[default]
+    Fail,
+    /// Leave the file out of the anonymised package
+    Drop,
+    /// Copy the file to the anonymised package unchanged and log a warning
+    Keep,
+}
+
+/// # The files which are allowed in a package, on top of the files named in the metadata json
+#[derive(Clone, Debug)]
+pub struct AllowList {
+    patterns: Vec<String>,
+    glob_set: GlobSet,
+    /// What happens to files which are neither named in the metadata json nor allowed
+    pub action: UnexpectedFileAction,
+}
+
+impl Default for AllowList {
+    fn default() -> Self {
+        AllowList {
+            patterns: Vec::new(),
+            glob_set: GlobSet::empty(),
+            action: UnexpectedFileAction::default(),
+        }
+    }
+}
+
+/// # What happened to a file in the package
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileDecision {
+    /// The file is the metadata json or is named in it
+    Expected,
+    /// The file matches a pattern in the allow list
+    Allowed,
+    /// The file was unexpected and left out of the anonymised package
+    Dropped,
+    /// The file was unexpected and copied unchanged
+    Kept,
+    /// The file was unexpected, so the package wasn't anonymised
+    Failed,
+}
+
+/// # The decision for a file in the package
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileReport {
+    /// The path of the file in the input package
+    pub path: String,
+    pub decision: FileDecision,
+}
+
+impl Display for FileDecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let decision: &str = match self {
+            FileDecision::Expected => "expected",
+            FileDecision::Allowed => "allowed",
+            FileDecision::Dropped => "unexpected, dropped",
+            FileDecision::Kept => "unexpected, kept",
+            FileDecision::Failed => "unexpected",
+        };
+        write!(f, "{decision}")
+    }
+}
+
+impl Display for FileReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.decision)
+    }
+}
+
+impl AllowList {
+    /// # Build an allow list from glob patterns
+    ///
+    /// `*` doesn't match `/`, so `**` is needed to match files in subfolders.
+    pub fn new(
+        patterns: &[String],
+        action: UnexpectedFileAction,
+    ) -> Result<AllowList, AnonymiserError> {
+        let mut glob_set: GlobSetBuilder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    AnonymiserError::InvalidPolicy(format!("Invalid allowed file pattern: {e}"))
+                })?;
+            glob_set.add(glob);
+        }
+        Ok(AllowList {
+            patterns: patterns.to_vec(),
+            glob_set: glob_set
+                .build()
+                .map_err(|e| AnonymiserError::InvalidPolicy(e.to_string()))?,
+            action,
+        })
+    }
+
+    /// # The glob patterns of the allowed files
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// # Decide what happens to each file in the package
+    ///
+    /// `file_paths` are the paths of the files in the tar.gz, without any folders.
+    pub(crate) fn decide(
+        &self,
+        file_paths: &[PathBuf],
+        batch_reference: &BatchReference,
+        model: &PackageModel,
+    ) -> Vec<FileReport> {
+        let folder: &Path = batch_reference.folder();
+        let metadata_file_name: String = batch_reference.metadata_file_name();
+        let mut expected: Vec<&str> = model.file_names();
+        expected.push(&metadata_file_name);
+        file_paths
+            .iter()
+            .map(|file_path| {
+                let decision: FileDecision = match file_path.strip_prefix(folder) {
+                    Ok(relative_path)
+                        if expected.iter().any(|name| relative_path == Path::new(name)) =>
+                    {
+                        FileDecision::Expected
+                    }
+                    Ok(relative_path) if self.glob_set.is_match(relative_path) => {
+                        FileDecision::Allowed
+                    }
+                    _ => match self.action {
+                        UnexpectedFileAction::Fail => FileDecision::Failed,
+                        UnexpectedFileAction::Drop => FileDecision::Dropped,
+                        UnexpectedFileAction::Keep => FileDecision::Kept,
+                    },
+                };
+                FileReport {
+                    path: file_path.to_string_lossy().to_string(),
+                    decision,
+                }
+            })
+            .collect()
+    }
+}
+
+/// # Log each decision, then fail if the policy fails packages with unexpected files and there are any
+///
+/// Unexpected files are logged as warnings.
+pub(crate) fn check_file_reports(file_reports: &[FileReport]) -> Result<(), AnonymiserError> {
+    for report in file_reports {
+        match report.decision {
+            FileDecision::Expected | FileDecision::Allowed => log::info!("{report}"),
+            _ => log::warn!("{report}"),
+        }
+    }
+    let paths: Vec<String> = file_reports
+        .iter()
+        .filter(|report| report.decision == FileDecision::Failed)
+        .map(|report| report.path.clone())
+        .collect();
+    if paths.is_empty() {
+        return Ok(());
+    }
+    Err(AnonymiserError::UnexpectedFiles { paths })
+}
+
+/// # The paths of the files the policy drops from the package
+pub(crate) fn dropped_files(file_reports: &[FileReport]) -> Vec<PathBuf> {
+    file_reports
+        .iter()
+        .filter(|report| report.decision == FileDecision::Dropped)
+        .map(|report| PathBuf::from(&report.path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn decisions(allow_list: &AllowList, file_paths: &[&str]) -> Vec<(String, FileDecision)> {
+        let batch_reference = BatchReference::parse("TDR-2023").unwrap();
+        let metadata = json!({"parameters": {"TRE": {"payload": {
+            "filename": "test.docx",
+            "images": ["images/image1.png"]
+        }}}});
+        let model = PackageModel::from_metadata(&metadata, &batch_reference).unwrap();
+        let file_paths: Vec<PathBuf> = file_paths.iter().map(PathBuf::from).collect();
+        allow_list
+            .decide(&file_paths, &batch_reference, &model)
+            .into_iter()
+            .map(|report| (report.path, report.decision))
+            .collect()
+    }
+
+    #[test]
+    fn test_decide_expected_and_allowed_files() {
+        let allow_list = AllowList::new(
+            &[String::from("images/*.png"), String::from("notes/**")],
+            UnexpectedFileAction::Fail,
+        )
+        .unwrap();
+        assert_eq!(
+            decisions(
+                &allow_list,
+                &[
+                    "TDR-2023/TRE-TDR-2023-metadata.json",
+                    "TDR-2023/test.docx",
+                    "TDR-2023/images/image1.png",
+                    "TDR-2023/images/image2.png",
+                    "TDR-2023/images/large/image3.png",
+                    "TDR-2023/notes/a/b.txt",
+                    "TDR-2023/extra.txt",
+                    "other/test.docx"
+                ]
+            ),
+            vec![
+                (
+                    String::from("TDR-2023/TRE-TDR-2023-metadata.json"),
+                    FileDecision::Expected
+                ),
+                (String::from("TDR-2023/test.docx"), FileDecision::Expected),
+                (
+                    String::from("TDR-2023/images/image1.png"),
+                    FileDecision::Expected
+                ),
+                (
+                    String::from("TDR-2023/images/image2.png"),
+                    FileDecision::Allowed
+                ),
+                (
+                    String::from("TDR-2023/images/large/image3.png"),
+                    FileDecision::Failed
+                ),
+                (
+                    String::from("TDR-2023/notes/a/b.txt"),
+                    FileDecision::Allowed
+                ),
+                (String::from("TDR-2023/extra.txt"), FileDecision::Failed),
+                (String::from("other/test.docx"), FileDecision::Failed)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decide_unexpected_files_with_each_action() {
+        for (action, decision) in [
+            (UnexpectedFileAction::Fail, FileDecision::Failed),
+            (UnexpectedFileAction::Drop, FileDecision::Dropped),
+            (UnexpectedFileAction::Keep, FileDecision::Kept),
+        ] {
+            let allow_list = AllowList::new(&[], action).unwrap();
+            assert_eq!(
+                decisions(&allow_list, &["TDR-2023/extra.txt"]),
+                vec![(String::from("TDR-2023/extra.txt"), decision)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_file_reports() {
+        let report = |path: &str, decision: FileDecision| FileReport {
+            path: String::from(path),
+            decision,
+        };
+        assert!(check_file_reports(&[report("a", FileDecision::Kept)]).is_ok());
+        let err = check_file_reports(&[
+            report("a", FileDecision::Failed),
+            report("b", FileDecision::Expected),
+            report("c", FileDecision::Failed),
+        ])
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected files in the package: a, c");
+    }
+
+    #[test]
+    fn test_allow_list_error_if_a_pattern_is_invalid() {
+        let err =
+            AllowList::new(&[String::from("images/[")], UnexpectedFileAction::Fail).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid allowed file pattern: error parsing glob 'images/['"));
+    }
+}
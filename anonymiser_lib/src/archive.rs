@@ -0,0 +1,495 @@
+//! ## Reading packages without extracting them
+//!
+//! Packages come from an upstream system, so every entry is checked before it is read or extracted.
+//! Absolute paths, `..` components, duplicate paths, links and special files such as devices are refused,
+//! and the number and size of the entries are limited so a small tar.gz can't fill the disk.
+//! Pax and GNU header entries, such as the global header `git archive` writes, only describe the other entries,
+//! so they are skipped.
+use crate::error::{AnonymiserError, WithPath};
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Entry, EntryType};
+
+/// The default limit on the total size of the entries in a package, in bytes
+pub const DEFAULT_MAX_TOTAL_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The default limit on the number of entries in a package, including folders
+pub const DEFAULT_MAX_ENTRIES: u64 = 10_000;
+
+/// The default limit on the size of each entry in a package, in bytes
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// # Limits on the entries of the packages which are read
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct ExtractionLimits {
+    /// The largest total size of the entries, in bytes
+    pub max_total_size: u64,
+    /// The largest number of entries, including folders
+    pub max_entries: u64,
+    /// The largest size of each entry, in bytes
+    pub max_entry_size: u64,
+}
+
+impl Default for ExtractionLimits {
+    fn default() -> Self {
+        ExtractionLimits {
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+}
+
+/// # Checks each entry of a tar.gz file against the extraction limits
+pub(crate) struct EntryChecker<'a> {
+    file: &'a Path,
+    limits: ExtractionLimits,
+    entries: u64,
+    total_size: u64,
+    /// The paths of the entries checked so far
+    seen: HashSet<PathBuf>,
+}
+
+impl EntryChecker<'_> {
+    pub(crate) fn new(file: &Path, limits: ExtractionLimits) -> EntryChecker<'_> {
+        EntryChecker {
+            file,
+            limits,
+            entries: 0,
+            total_size: 0,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// # Check the next entry of the tar.gz file
+    ///
+    /// The size in the header is checked, so the contents don't need to be read.
+    /// Header entries aren't counted towards the limits.
+    pub(crate) fn check<R: Read>(&mut self, entry: &Entry<R>) -> Result<(), AnonymiserError> {
+        if is_header_entry(entry.header().entry_type()) {
+            return Ok(());
+        }
+        let path: PathBuf = self.file.to_path_buf();
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(AnonymiserError::TooManyEntries {
+                path,
+                limit: self.limits.max_entries,
+            });
+        }
+        let entry_path: PathBuf = entry.path().map_err(bad_archive(self.file))?.to_path_buf();
+        let is_unsafe = |component: Component| {
+            matches!(
+                component,
+                Component::Prefix(_) | Component::RootDir | Component::ParentDir
+            )
+        };
+        if entry_path.components().any(is_unsafe) {
+            return Err(AnonymiserError::UnsafeEntryPath {
+                path,
+                entry: entry_path,
+            });
+        }
+        if !self.seen.insert(entry_path.clone()) {
+            return Err(AnonymiserError::DuplicateEntry {
+                path,
+                entry: entry_path,
+            });
+        }
+        match entry.header().entry_type() {
+            EntryType::Symlink | EntryType::Link => {
+                return Err(AnonymiserError::LinkEntry {
+                    path,
+                    entry: entry_path,
+                })
+            }
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                return Err(AnonymiserError::SpecialEntry {
+                    path,
+                    entry: entry_path,
+                })
+            }
+            _ => (),
+        }
+        let size: u64 = entry.size();
+        if size > self.limits.max_entry_size {
+            return Err(AnonymiserError::EntryTooLarge {
+                path,
+                entry: entry_path,
+                limit: self.limits.max_entry_size,
+            });
+        }
+        self.total_size = self.total_size.saturating_add(size);
+        if self.total_size > self.limits.max_total_size {
+            return Err(AnonymiserError::ArchiveTooLarge {
+                path,
+                limit: self.limits.max_total_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// # Whether an entry is a pax or GNU header which describes the entries after it, rather than a file
+fn is_header_entry(entry_type: EntryType) -> bool {
+    entry_type.is_pax_global_extensions()
+        || entry_type.is_pax_local_extensions()
+        || entry_type.is_gnu_longname()
+        || entry_type.is_gnu_longlink()
+}
+
+/// # Extract a tar.gz file, checking each entry before it is extracted
+///
+/// The archive is read once. If an entry fails the checks, the entries before it have already been extracted,
+/// so `output_path` should be a scratch folder which is removed if this fails.
+///
+/// Returns the paths of the entries, in the order of the tar.gz.
+pub(crate) fn unpack_archive(
+    file: &Path,
+    output_path: &Path,
+    limits: ExtractionLimits,
+) -> Result<PackageEntries, AnonymiserError> {
+    let mut checker: EntryChecker = EntryChecker::new(file, limits);
+    let mut archive: Archive<GzDecoder<File>> = open_archive(file)?;
+    let mut entries: PackageEntries = PackageEntries::default();
+    for entry in archive_entries(&mut archive, file)? {
+        let mut entry = entry?;
+        checker.check(&entry)?;
+        entries.push(&entry, file)?;
+        entry.unpack_in(output_path).map_err(bad_archive(file))?;
+    }
+    Ok(entries)
+}
+
+/// # The paths of the entries of a package
+#[derive(Debug, Default)]
+pub(crate) struct PackageEntries {
+    pub(crate) entry_paths: Vec<PathBuf>,
+    /// The paths of the entries which aren't folders
+    pub(crate) file_paths: Vec<PathBuf>,
+}
+
+impl PackageEntries {
+    fn push<R: Read>(&mut self, entry: &Entry<R>, file: &Path) -> Result<(), AnonymiserError> {
+        let entry_path: PathBuf = entry.path().map_err(bad_archive(file))?.to_path_buf();
+        if !entry.header().entry_type().is_dir() {
+            self.file_paths.push(entry_path.clone());
+        }
+        self.entry_paths.push(entry_path);
+        Ok(())
+    }
+}
+
+/// # The entry paths and metadata json of a package
+pub(crate) struct PackageIndex {
+    pub(crate) entries: PackageEntries,
+    pub(crate) metadata_json_value: Value,
+}
+
+/// # Open a tar.gz file for reading
+pub(crate) fn open_archive(file: &Path) -> Result<Archive<GzDecoder<File>>, AnonymiserError> {
+    let tar_gz: File = File::open(file).with_path(file)?;
+    Ok(Archive::new(GzDecoder::new(tar_gz)))
+}
+
+/// # Helper function to wrap an io error from reading a tar.gz file
+pub(crate) fn bad_archive(file: &Path) -> impl Fn(io::Error) -> AnonymiserError + Copy + '_ {
+    move |source| AnonymiserError::BadArchive {
+        path: file.to_path_buf(),
+        source,
+    }
+}
+
+/// # Iterate over the entries of a tar.gz file
+///
+/// An error reading the next entry says that iterating over the archive failed, so it reads the same wherever it happens.
+/// Header entries are left out, so they aren't extracted or copied to the anonymised package.
+pub(crate) fn archive_entries<'a>(
+    archive: &'a mut Archive<GzDecoder<File>>,
+    file: &'a Path,
+) -> Result<
+    impl Iterator<Item = Result<Entry<'a, GzDecoder<File>>, AnonymiserError>> + 'a,
+    AnonymiserError,
+> {
+    let iterate_error = move |e: io::Error| {
+        bad_archive(file)(io::Error::new(
+            e.kind(),
+            format!("failed to iterate over archive: {e}"),
+        ))
+    };
+    Ok(archive
+        .entries()
+        .map_err(iterate_error)?
+        .map(move |entry| entry.map_err(iterate_error))
+        .filter(
+            |entry| !matches!(entry, Ok(entry) if is_header_entry(entry.header().entry_type())),
+        ))
+}
+
+/// # Read the entry paths and parse the metadata json from a tar.gz file
+///
+/// Each entry is checked against the extraction limits, so the later passes over the file can trust it.
+pub(crate) fn read_package_index(
+    file: &Path,
+    metadata_path: &Path,
+    limits: ExtractionLimits,
+) -> Result<PackageIndex, AnonymiserError> {
+    let mut checker: EntryChecker = EntryChecker::new(file, limits);
+    let mut archive: Archive<GzDecoder<File>> = open_archive(file)?;
+    let mut entries: PackageEntries = PackageEntries::default();
+    let mut metadata_json_value: Option<Value> = None;
+    for entry in archive_entries(&mut archive, file)? {
+        let entry = entry?;
+        checker.check(&entry)?;
+        entries.push(&entry, file)?;
+        if entry.path().map_err(bad_archive(file))? == metadata_path {
+            metadata_json_value = Some(serde_json::from_reader(entry).map_err(|source| {
+                AnonymiserError::JsonParse {
+                    path: metadata_path.to_path_buf(),
+                    source,
+                }
+            })?);
+        }
+    }
+    let metadata_json_value: Value = metadata_json_value.ok_or(AnonymiserError::MissingEntry {
+        path: metadata_path.to_path_buf(),
+    })?;
+    Ok(PackageIndex {
+        entries,
+        metadata_json_value,
+    })
+}
+
+/// # Read the contents of several entries from a tar.gz file in one pass
+///
+/// Paths without an entry are left out of the result.
+pub(crate) fn read_entries(
+    file: &Path,
+    entry_paths: &[PathBuf],
+) -> Result<HashMap<PathBuf, Vec<u8>>, AnonymiserError> {
+    let mut contents: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    if entry_paths.is_empty() {
+        return Ok(contents);
+    }
+    let mut archive: Archive<GzDecoder<File>> = open_archive(file)?;
+    for entry in archive_entries(&mut archive, file)? {
+        let mut entry = entry?;
+        let entry_path: PathBuf = entry.path().map_err(bad_archive(file))?.to_path_buf();
+        if entry_paths.contains(&entry_path) {
+            let mut entry_contents: Vec<u8> = Vec::new();
+            entry
+                .read_to_end(&mut entry_contents)
+                .map_err(bad_archive(file))?;
+            contents.insert(entry_path, entry_contents);
+        }
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, Header};
+
+    /// # Write a tar.gz file with raw entry names, so unsafe paths can be written too
+    fn write_archive(dir: &Path, entries: &[(&str, EntryType, &[u8])]) -> PathBuf {
+        let file: PathBuf = dir.join("test.tar.gz");
+        let tar_gz: File = File::create(&file).unwrap();
+        let mut builder = Builder::new(GzEncoder::new(tar_gz, Compression::default()));
+        for (name, entry_type, contents) in entries {
+            let mut header: Header = Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            if entry_type.is_symlink() || entry_type.is_hard_link() {
+                header.set_link_name("TDR-2023/test.docx").unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        file
+    }
+
+    fn check(entries: &[(&str, EntryType, &[u8])], limits: ExtractionLimits) -> AnonymiserError {
+        let dir = TempDir::new().unwrap();
+        let file: PathBuf = write_archive(dir.path(), entries);
+        let output_dir = TempDir::new().unwrap();
+        unpack_archive(&file, &output_dir, limits).unwrap_err()
+    }
+
+    #[test]
+    fn test_unpack_archive_extracts_files_and_folders() {
+        let dir = TempDir::new().unwrap();
+        let file: PathBuf = write_archive(
+            dir.path(),
+            &[
+                ("TDR-2023/", EntryType::Directory, b""),
+                ("TDR-2023/test.docx", EntryType::Regular, b"docx"),
+            ],
+        );
+        let limits = ExtractionLimits {
+            max_total_size: 4,
+            max_entries: 2,
+            max_entry_size: 4,
+        };
+        let output_dir = TempDir::new().unwrap();
+        let entries: PackageEntries = unpack_archive(&file, &output_dir, limits).unwrap();
+        assert_eq!(
+            std::fs::read(output_dir.join("TDR-2023/test.docx")).unwrap(),
+            b"docx"
+        );
+        assert_eq!(
+            entries.entry_paths,
+            vec![
+                PathBuf::from("TDR-2023/"),
+                PathBuf::from("TDR-2023/test.docx")
+            ]
+        );
+        assert_eq!(
+            entries.file_paths,
+            vec![PathBuf::from("TDR-2023/test.docx")]
+        );
+    }
+
+    #[test]
+    fn test_unpack_archive_skips_pax_global_headers() {
+        let dir = TempDir::new().unwrap();
+        let file: PathBuf = write_archive(
+            dir.path(),
+            &[
+                (
+                    "pax_global_header",
+                    EntryType::XGlobalHeader,
+                    b"17 comment=abcde\n",
+                ),
+                ("TDR-2023/", EntryType::Directory, b""),
+                ("TDR-2023/test.docx", EntryType::Regular, b"docx"),
+            ],
+        );
+        let limits = ExtractionLimits {
+            max_total_size: 4,
+            max_entries: 2,
+            max_entry_size: 4,
+        };
+        let output_dir = TempDir::new().unwrap();
+        let entries: PackageEntries = unpack_archive(&file, &output_dir, limits).unwrap();
+        assert_eq!(
+            entries.entry_paths,
+            vec![
+                PathBuf::from("TDR-2023/"),
+                PathBuf::from("TDR-2023/test.docx")
+            ]
+        );
+        assert!(!output_dir.join("pax_global_header").exists());
+    }
+
+    #[test]
+    fn test_unpack_archive_error_if_an_entry_path_is_unsafe() {
+        for name in ["TDR-2023/../../evil.txt", "/etc/evil.txt"] {
+            let err = check(
+                &[(name, EntryType::Regular, b"evil")],
+                ExtractionLimits::default(),
+            );
+            assert!(
+                matches!(&err, AnonymiserError::UnsafeEntryPath { entry, .. } if entry == Path::new(name))
+            );
+        }
+    }
+
+    #[test]
+    fn test_unpack_archive_error_if_not_a_tar_gz() {
+        let dir = TempDir::new().unwrap();
+        let file: PathBuf = dir.join("test.tar.gz");
+        std::fs::write(&file, "test").unwrap();
+        let err = unpack_archive(&file, &dir, ExtractionLimits::default()).unwrap_err();
+        assert!(matches!(err, AnonymiserError::BadArchive { .. }));
+        assert_eq!(
+            err.to_string()
+                .matches("failed to iterate over archive")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_unpack_archive_error_if_an_entry_is_a_link() {
+        for entry_type in [EntryType::Symlink, EntryType::Link] {
+            let err = check(
+                &[
+                    ("TDR-2023/test.docx", EntryType::Regular, b"docx"),
+                    ("TDR-2023/link.docx", entry_type, b""),
+                ],
+                ExtractionLimits::default(),
+            );
+            assert!(matches!(err, AnonymiserError::LinkEntry { .. }));
+            assert!(err
+                .to_string()
+                .starts_with("'TDR-2023/link.docx' in the archive "));
+        }
+    }
+
+    #[test]
+    fn test_unpack_archive_error_if_an_entry_is_a_device() {
+        for entry_type in [EntryType::Char, EntryType::Block, EntryType::Fifo] {
+            let err = check(
+                &[("TDR-2023/device", entry_type, b"")],
+                ExtractionLimits::default(),
+            );
+            assert!(matches!(err, AnonymiserError::SpecialEntry { .. }));
+        }
+    }
+
+    #[test]
+    fn test_unpack_archive_error_if_a_limit_is_exceeded() {
+        let entries: &[(&str, EntryType, &[u8])] = &[
+            ("TDR-2023/a.txt", EntryType::Regular, b"aaaa"),
+            ("TDR-2023/b.txt", EntryType::Regular, b"bbbb"),
+        ];
+        let limits = ExtractionLimits {
+            max_total_size: 8,
+            max_entries: 2,
+            max_entry_size: 4,
+        };
+        assert!(matches!(
+            check(
+                entries,
+                ExtractionLimits {
+                    max_entries: 1,
+                    ..limits
+                }
+            ),
+            AnonymiserError::TooManyEntries { limit: 1, .. }
+        ));
+        assert!(matches!(
+            check(
+                entries,
+                ExtractionLimits {
+                    max_entry_size: 3,
+                    ..limits
+                }
+            ),
+            AnonymiserError::EntryTooLarge { limit: 3, .. }
+        ));
+        assert!(matches!(
+            check(
+                entries,
+                ExtractionLimits {
+                    max_total_size: 7,
+                    ..limits
+                }
+            ),
+            AnonymiserError::ArchiveTooLarge { limit: 7, .. }
+        ));
+    }
+}
@@ -0,0 +1,260 @@
+//! ## Audit records
+//!
+//! Every processed package gets an audit record, which is written as a json sidecar next to the output tar.gz.
+//! It records what was changed and which policy was used, without any of the original values.
+use crate::allow_list::{FileReport, UnexpectedFileAction};
+use crate::archive::ExtractionLimits;
+use crate::docx::DocxReplacement;
+use crate::error::{AnonymiserError, WithPath};
+use crate::parser_log::ParserLogMode;
+use crate::pii::{PiiAction, PiiFinding};
+use crate::publish::PendingFile;
+use crate::replacement::Replacement;
+use crate::rules::RedactionRules;
+use crate::xml::XmlReplacement;
+use crate::Policy;
+use serde::Serialize;
+use sha256::try_digest;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The version of the anonymiser which produced the package
+pub const ANONYMISER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// # A record of the changes made to a package
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub anonymiser_version: String,
+    pub input_file: String,
+    pub input_sha256: String,
+    pub output_file: String,
+    pub output_sha256: String,
+    pub input_batch_reference: String,
+    pub output_batch_reference: String,
+    /// The json pointers of the metadata fields which were changed
+    pub metadata_changes: Vec<String>,
+    /// The entries which were not copied to the output package
+    pub removed: Vec<String>,
+    /// The entries which were replaced with new content
+    pub regenerated: Vec<String>,
+    /// The json pointers of the metadata values where the batch reference was replaced
+    pub reference_rewrites: Vec<String>,
+    /// The metadata values which look like personal data, without the values
+    pub pii_findings: Vec<PiiFinding>,
+    /// Images and attachments which couldn't be anonymised or were missing from the package
+    pub unexpected: Vec<String>,
+    /// What happened to each file in the input package
+    pub files: Vec<FileReport>,
+    pub policy: PolicySummary,
+}
+
+/// # The settings of the policy used for a package
+///
+/// The pseudonymisation key is never included.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PolicySummary {
+    pub rules: RedactionRules,
+    pub replacement: String,
+    pub docx: String,
+    pub xml: String,
+    pub parser_log: ParserLogMode,
+    pub pii: PiiAction,
+    /// The name of the reference mapping. The key and the mapping itself are not included
+    pub reference_mapping: String,
+    /// The glob patterns of the files allowed in each package
+    pub allowed_files: Vec<String>,
+    pub unexpected_files: UnexpectedFileAction,
+    pub limits: ExtractionLimits,
+    /// The seed used for fake values and scrambled text, if the policy uses one
+    pub seed: Option<u64>,
+}
+
+impl From<&Policy> for PolicySummary {
+    fn from(policy: &Policy) -> Self {
+        let (replacement, replacement_seed) = match &policy.replacement {
+            Replacement::Mask => ("mask", None),
+            Replacement::Pseudonymise(_) => ("pseudonymise", None),
+            Replacement::Fake(generator) => ("fake", Some(generator.seed())),
+        };
+        let (docx, docx_seed) = match &policy.docx {
+            DocxReplacement::Regenerate => ("regenerate", None),
+            DocxReplacement::Scrub(scrambler) => ("scrub", Some(scrambler.seed())),
+        };
+        let (xml, xml_seed) = match &policy.xml {
+            XmlReplacement::Delete => ("delete", None),
+            XmlReplacement::Anonymise(scrambler) => ("anonymise", Some(scrambler.seed())),
+        };
+        PolicySummary {
+            rules: policy.rules.clone(),
+            replacement: replacement.to_string(),
+            docx: docx.to_string(),
+            xml: xml.to_string(),
+            parser_log: policy.parser_log,
+            pii: policy.pii,
+            reference_mapping: policy.reference_mapping.name().to_string(),
+            allowed_files: policy.allow_list.patterns().to_vec(),
+            unexpected_files: policy.allow_list.action,
+            limits: policy.limits,
+            seed: replacement_seed.or(docx_seed).or(xml_seed),
+        }
+    }
+}
+
+/// # The changes made to the entries of a package while it is processed
+#[derive(Default)]
+pub(crate) struct PackageChanges {
+    pub(crate) metadata_changes: Vec<String>,
+    pub(crate) removed: Vec<String>,
+    pub(crate) regenerated: Vec<String>,
+    pub(crate) reference_rewrites: Vec<String>,
+    pub(crate) pii_findings: Vec<PiiFinding>,
+    pub(crate) unexpected: Vec<String>,
+    pub(crate) files: Vec<FileReport>,
+}
+
+/// # The result of processing a package
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessedPackage {
+    /// The path of the anonymised tar.gz file
+    pub output_path: PathBuf,
+    /// The path of the audit record json
+    pub audit_path: PathBuf,
+    pub audit: AuditRecord,
+}
+
+impl AuditRecord {
+    /// # Create the audit record once the output tar.gz has been written
+    pub(crate) fn new(
+        input_file: &Path,
+        output_tar_gz_path: &Path,
+        input_batch_reference: &str,
+        output_batch_reference: &str,
+        mut changes: PackageChanges,
+        policy: &Policy,
+    ) -> Result<AuditRecord, AnonymiserError> {
+        changes.removed.sort();
+        changes.regenerated.sort();
+        changes
+            .files
+            .sort_by(|first, second| first.path.cmp(&second.path));
+        Ok(AuditRecord {
+            anonymiser_version: ANONYMISER_VERSION.to_string(),
+            input_file: file_name(input_file),
+            input_sha256: try_digest(input_file).with_path(input_file)?,
+            output_file: file_name(output_tar_gz_path),
+            output_sha256: try_digest(output_tar_gz_path).with_path(output_tar_gz_path)?,
+            input_batch_reference: input_batch_reference.to_string(),
+            output_batch_reference: output_batch_reference.to_string(),
+            metadata_changes: changes.metadata_changes,
+            removed: changes.removed,
+            regenerated: changes.regenerated,
+            reference_rewrites: changes.reference_rewrites,
+            pii_findings: changes.pii_findings,
+            unexpected: changes.unexpected,
+            files: changes.files,
+            policy: PolicySummary::from(policy),
+        })
+    }
+
+    /// # Write the audit record next to the output tar.gz
+    ///
+    /// The record for `TST-xxx.tar.gz` is written to `TST-xxx.audit.json`.
+    pub(crate) fn write(
+        self,
+        output_tar_gz_path: &Path,
+    ) -> Result<ProcessedPackage, AnonymiserError> {
+        let audit_path: PathBuf = audit_path(output_tar_gz_path);
+        let audit_json: String =
+            serde_json::to_string_pretty(&self).expect("The audit record is always valid json");
+        let audit_file: PendingFile = PendingFile::new(&audit_path)?;
+        audit_file
+            .as_file()
+            .write_all(audit_json.as_bytes())
+            .with_path(&audit_path)?;
+        audit_file.publish()?;
+        Ok(ProcessedPackage {
+            output_path: output_tar_gz_path.to_path_buf(),
+            audit_path,
+            audit: self,
+        })
+    }
+}
+
+/// # The path of the audit record for an output tar.gz
+pub fn audit_path(output_tar_gz_path: &Path) -> PathBuf {
+    let file_name: String = file_name(output_tar_gz_path);
+    let stem: &str = file_name.strip_suffix(".tar.gz").unwrap_or(&file_name);
+    output_tar_gz_path.with_file_name(format!("{stem}.audit.json"))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{FakeValueGenerator, TextScrambler};
+    use crate::reference::ReferenceMapping;
+    use crate::replacement::PseudonymisationKey;
+    use serde_json::json;
+
+    #[test]
+    fn test_audit_path() {
+        assert_eq!(
+            audit_path(Path::new("/output/TST-2023.tar.gz")),
+            Path::new("/output/TST-2023.audit.json")
+        );
+    }
+
+    #[test]
+    fn test_policy_summary_does_not_include_the_key() {
+        let policy = Policy {
+            replacement: Replacement::Pseudonymise(PseudonymisationKey::new(b"test-key").unwrap()),
+            reference_mapping: ReferenceMapping::KeyedHash(
+                PseudonymisationKey::new(b"test-key").unwrap(),
+            ),
+            ..Policy::default()
+        };
+        let summary = serde_json::to_value(PolicySummary::from(&policy)).unwrap();
+        assert_eq!(
+            summary,
+            json!({
+                "rules": [
+                    {"pointer": "/parameters/TDR/Contact-Email", "action": "mask"},
+                    {"pointer": "/parameters/TDR/Contact-Name", "action": "mask"}
+                ],
+                "replacement": "pseudonymise",
+                "docx": "regenerate",
+                "xml": "delete",
+                "parser_log": "drop",
+                "pii": "warn",
+                "reference_mapping": "keyed_hash",
+                "allowed_files": [],
+                "unexpected_files": "fail",
+                "limits": {
+                    "max_total_size": 4294967296u64,
+                    "max_entries": 10000,
+                    "max_entry_size": 1073741824
+                },
+                "seed": null
+            })
+        );
+        assert!(!summary.to_string().contains("test-key"));
+    }
+
+    #[test]
+    fn test_policy_summary_includes_the_seed() {
+        let policy = Policy {
+            replacement: Replacement::Fake(FakeValueGenerator::new(1)),
+            docx: DocxReplacement::Scrub(TextScrambler::new(1)),
+            ..Policy::default()
+        };
+        let summary = PolicySummary::from(&policy);
+        assert_eq!(summary.replacement, "fake");
+        assert_eq!(summary.docx, "scrub");
+        assert_eq!(summary.seed, Some(1));
+    }
+}
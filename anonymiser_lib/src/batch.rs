@@ -0,0 +1,471 @@
+//! ## Batch processing
+//!
+//! The script anonymises every package in the input folder. By default, a package which fails doesn't stop the others,
+//! and a summary of the packages which succeeded, failed or were skipped is printed at the end.
+//! With `--fail-fast`, the first failure stops the run instead.
+//!
+//! With `--jobs`, several packages are processed at once by a pool of named worker threads,
+//! and the summary is always in the order of the input files. A package which panics is recorded as failed,
+//! so it doesn't stop the rest of the batch.
+//!
+//! Without `--mirror`, every package is written to the top of the output folder. If packages in different subfolders
+//! have the same file name, the first one is processed and the others fail, so no output is overwritten.
+use crate::audit::ProcessedPackage;
+use crate::error::{AnonymiserError, WithPath};
+use crate::stream::process_package_streaming;
+use crate::{process_package, Policy};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// # How a batch of packages is processed
+#[derive(Clone, Copy, Debug)]
+pub struct BatchOptions {
+    /// Use `process_package_streaming` instead of `process_package`
+    pub streaming: bool,
+    /// Stop at the first package which fails
+    pub fail_fast: bool,
+    /// The number of packages processed at once
+    pub jobs: NonZeroUsize,
+    /// Write each package to the same subfolder of the output folder as it is in the input folder
+    pub mirror: bool,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            streaming: false,
+            fail_fast: false,
+            jobs: NonZeroUsize::MIN,
+            mirror: false,
+        }
+    }
+}
+
+/// # What happened to a file in a batch
+enum Outcome {
+    Succeeded,
+    Failed(AnonymiserError),
+    Skipped(String),
+}
+
+/// # A file which failed or was skipped, and why
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BatchItem {
+    pub file: String,
+    pub reason: String,
+    /// Whether the file failed with an error which might not happen if it is tried again
+    #[serde(skip)]
+    pub retryable: bool,
+}
+
+/// # What happened to each file in a batch
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct BatchSummary {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<BatchItem>,
+    pub skipped: Vec<BatchItem>,
+}
+
+impl BatchSummary {
+    /// # Whether any package in the batch failed
+    pub fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+}
+
+impl Display for BatchSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Summary: {} succeeded, {} failed, {} skipped",
+            self.succeeded.len(),
+            self.failed.len(),
+            self.skipped.len()
+        )?;
+        for failed in &self.failed {
+            writeln!(f, "  Failed: {}: {}", failed.file, failed.reason)?;
+        }
+        for skipped in &self.skipped {
+            writeln!(f, "  Skipped: {}: {}", skipped.file, skipped.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// # Anonymise each package in a batch
+///
+/// Files which aren't tar.gz files are skipped. Each package which fails is logged and added to the summary,
+/// unless `fail_fast` is set. Then no more packages are started, and the error of the first file which failed is returned
+/// once the packages already started have finished.
+///
+/// Up to `jobs` packages are processed at once, on worker threads named `anonymiser-worker-<n>`.
+/// A package which panics is recorded as failed with the panic message.
+/// Files are named in the summary by their paths relative to the input folder.
+/// Without `mirror`, a package with the same file name as an earlier one fails instead of overwriting its output.
+pub fn process_batch(
+    dir_input: &Path,
+    dir_output: &Path,
+    files: &[PathBuf],
+    policy: &Policy,
+    options: BatchOptions,
+) -> Result<BatchSummary, AnonymiserError> {
+    let outcomes: Vec<Mutex<Option<Outcome>>> = files.iter().map(|_| Mutex::new(None)).collect();
+    let collisions: Vec<Option<&PathBuf>> = output_collisions(files, options.mirror);
+    let next_file: AtomicUsize = AtomicUsize::new(0);
+    let stopped: AtomicBool = AtomicBool::new(false);
+    let workers: usize = options.jobs.get().min(files.len());
+    thread::scope(|scope| -> Result<(), AnonymiserError> {
+        for worker in 1..=workers {
+            thread::Builder::new()
+                .name(format!("anonymiser-worker-{worker}"))
+                .spawn_scoped(scope, || {
+                    while !stopped.load(Ordering::SeqCst) {
+                        let index: usize = next_file.fetch_add(1, Ordering::SeqCst);
+                        let Some(file) = files.get(index) else {
+                            break;
+                        };
+                        let outcome: Outcome = match collisions[index] {
+                            Some(other) => {
+                                let err = AnonymiserError::OutputCollision {
+                                    path: file.to_path_buf(),
+                                    other: other.to_path_buf(),
+                                };
+                                log::error!("Error: {}", err);
+                                Outcome::Failed(err)
+                            }
+                            None => catch_panic(file, || {
+                                process_file(dir_input, dir_output, file, policy, options)
+                            }),
+                        };
+                        if options.fail_fast && matches!(outcome, Outcome::Failed(_)) {
+                            stopped.store(true, Ordering::SeqCst);
+                        }
+                        *outcomes[index].lock().unwrap() = Some(outcome);
+                    }
+                })
+                .with_path(dir_input)?;
+        }
+        Ok(())
+    })?;
+
+    let mut summary: BatchSummary = BatchSummary::default();
+    for (file, outcome) in files.iter().zip(outcomes) {
+        let file_name: String = relative_name(dir_input, file);
+        match outcome.into_inner().unwrap() {
+            Some(Outcome::Succeeded) => summary.succeeded.push(file_name),
+            Some(Outcome::Failed(err)) if options.fail_fast => return Err(err),
+            Some(Outcome::Failed(err)) => summary.failed.push(BatchItem {
+                file: file_name,
+                reason: err.to_string(),
+                retryable: err.is_retryable(),
+            }),
+            Some(Outcome::Skipped(reason)) => summary.skipped.push(BatchItem {
+                file: file_name,
+                reason,
+                retryable: false,
+            }),
+            None => (),
+        }
+    }
+    Ok(summary)
+}
+
+/// # For each file, the earlier package with the same file name, whose output it would overwrite
+///
+/// With `mirror`, each package keeps its subfolder in the output folder, so none of them collide.
+fn output_collisions(files: &[PathBuf], mirror: bool) -> Vec<Option<&PathBuf>> {
+    let mut first_files: HashMap<&OsStr, &PathBuf> = HashMap::new();
+    files
+        .iter()
+        .map(|file| {
+            let file_name: &OsStr = file.file_name()?;
+            if mirror || !file_name.to_string_lossy().ends_with(".tar.gz") {
+                return None;
+            }
+            let first_file: &PathBuf = first_files.entry(file_name).or_insert(file);
+            (first_file != file).then_some(first_file)
+        })
+        .collect()
+}
+
+/// # Run the processing of a file, turning a panic into a failure
+fn catch_panic(file: &Path, process: impl FnOnce() -> Outcome) -> Outcome {
+    catch_unwind(AssertUnwindSafe(process)).unwrap_or_else(|payload: Box<dyn Any + Send>| {
+        let message: String = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast_ref::<&str>().map_or_else(
+                || String::from("unknown panic"),
+                |message| message.to_string(),
+            ),
+        };
+        let err: AnonymiserError = AnonymiserError::Panicked {
+            path: file.to_path_buf(),
+            message,
+        };
+        log::error!("Error: {}", err);
+        Outcome::Failed(err)
+    })
+}
+
+/// # Anonymise a package and log what happened
+fn process_file(
+    dir_input: &Path,
+    dir_output: &Path,
+    file: &Path,
+    policy: &Policy,
+    options: BatchOptions,
+) -> Outcome {
+    let file_name: String = relative_name(dir_input, file);
+    if !file_name.ends_with(".tar.gz") {
+        let reason: String = String::from("not a tar.gz file");
+        log::warn!("Skipped {file_name}: {reason}");
+        return Outcome::Skipped(reason);
+    }
+    let dir_output: PathBuf = match (options.mirror, Path::new(&file_name).parent()) {
+        (true, Some(relative_folder)) => dir_output.join(relative_folder),
+        _ => dir_output.to_path_buf(),
+    };
+    let result: Result<ProcessedPackage, AnonymiserError> = fs::create_dir_all(&dir_output)
+        .with_path(&dir_output)
+        .and_then(|_| match options.streaming {
+            true => process_package_streaming(&dir_output, file, policy),
+            false => process_package(&dir_output, file, policy),
+        });
+    match result {
+        Ok(_) => {
+            log::info!("Processed {file_name}");
+            Outcome::Succeeded
+        }
+        Err(err) => {
+            if !options.fail_fast {
+                log::error!("Error: {}", err);
+            }
+            Outcome::Failed(err)
+        }
+    }
+}
+
+/// # The path of a file relative to the input folder, or its file name if it isn't in the input folder
+pub(crate) fn relative_name(dir_input: &Path, file: &Path) -> String {
+    match file.strip_prefix(dir_input) {
+        Ok(relative_path) => relative_path.to_string_lossy().to_string(),
+        Err(_) => file
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use std::fs::write;
+    use testlib::{create_package, valid_json};
+
+    fn batch_files(input_dir: &TempDir) -> Vec<PathBuf> {
+        let good: PathBuf = create_package(input_dir, valid_json(), None);
+        let bad: PathBuf = input_dir.join("TDR-2024.tar.gz");
+        write(&bad, "not a tar.gz").unwrap();
+        let notes: PathBuf = input_dir.join("notes.txt");
+        write(&notes, "notes").unwrap();
+        vec![bad, good, notes]
+    }
+
+    #[test]
+    fn test_process_batch_continues_after_a_failure() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let files: Vec<PathBuf> = batch_files(&input_dir);
+        let summary = process_batch(
+            &input_dir,
+            &output_dir,
+            &files,
+            &Policy::default(),
+            BatchOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(summary.succeeded, vec!["TDR-2023.tar.gz"]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].file, "TDR-2024.tar.gz");
+        assert!(summary.failed[0]
+            .reason
+            .starts_with("Cannot read the archive"));
+        assert_eq!(
+            summary.skipped,
+            vec![BatchItem {
+                file: String::from("notes.txt"),
+                reason: String::from("not a tar.gz file"),
+                retryable: false,
+            }]
+        );
+        assert!(summary.has_failures());
+        assert!(output_dir.join("TST-2023.tar.gz").exists());
+    }
+
+    #[test]
+    fn test_process_batch_stops_at_the_first_failure_if_fail_fast() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let files: Vec<PathBuf> = batch_files(&input_dir);
+        let options = BatchOptions {
+            fail_fast: true,
+            ..BatchOptions::default()
+        };
+        let err = process_batch(&input_dir, &output_dir, &files, &Policy::default(), options)
+            .unwrap_err();
+        assert!(matches!(err, AnonymiserError::BadArchive { .. }));
+        assert!(!output_dir.join("TST-2023.tar.gz").exists());
+    }
+
+    #[test]
+    fn test_process_batch_with_jobs_keeps_the_order_of_the_files() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let mut files: Vec<PathBuf> = batch_files(&input_dir);
+        for bad_file_name in ["TDR-2025.tar.gz", "TDR-2026.tar.gz", "TDR-2027.tar.gz"] {
+            let bad: PathBuf = input_dir.join(bad_file_name);
+            write(&bad, "not a tar.gz").unwrap();
+            files.push(bad);
+        }
+        let options = BatchOptions {
+            jobs: NonZeroUsize::new(3).unwrap(),
+            ..BatchOptions::default()
+        };
+        let summary =
+            process_batch(&input_dir, &output_dir, &files, &Policy::default(), options).unwrap();
+        assert_eq!(summary.succeeded, vec!["TDR-2023.tar.gz"]);
+        let failed: Vec<&str> = summary
+            .failed
+            .iter()
+            .map(|failed| failed.file.as_str())
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                "TDR-2024.tar.gz",
+                "TDR-2025.tar.gz",
+                "TDR-2026.tar.gz",
+                "TDR-2027.tar.gz"
+            ]
+        );
+        assert_eq!(summary.skipped.len(), 1);
+    }
+
+    #[test]
+    fn test_process_batch_mirrors_the_input_folders_if_mirror() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let package_dir: PathBuf = input_dir.join("2023").join("january");
+        fs::create_dir_all(&package_dir).unwrap();
+        let package: PathBuf = package_dir.join("TDR-2023.tar.gz");
+        fs::rename(create_package(&input_dir, valid_json(), None), &package).unwrap();
+        let files: Vec<PathBuf> = vec![package];
+        let options = BatchOptions {
+            mirror: true,
+            ..BatchOptions::default()
+        };
+        let summary =
+            process_batch(&input_dir, &output_dir, &files, &Policy::default(), options).unwrap();
+        assert_eq!(summary.succeeded, vec!["2023/january/TDR-2023.tar.gz"]);
+        assert!(output_dir
+            .join("2023")
+            .join("january")
+            .join("TST-2023.tar.gz")
+            .exists());
+    }
+
+    #[test]
+    fn test_process_batch_fails_packages_with_the_same_file_name_unless_mirror() {
+        let input_dir = TempDir::new().unwrap();
+        let files: Vec<PathBuf> = ["2023", "2024"]
+            .iter()
+            .map(|folder| {
+                let package_dir: PathBuf = input_dir.join(folder);
+                fs::create_dir_all(&package_dir).unwrap();
+                let package: PathBuf = package_dir.join("TDR-2023.tar.gz");
+                fs::rename(create_package(&input_dir, valid_json(), None), &package).unwrap();
+                package
+            })
+            .collect();
+
+        let output_dir = TempDir::new().unwrap();
+        let summary = process_batch(
+            &input_dir,
+            &output_dir,
+            &files,
+            &Policy::default(),
+            BatchOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(summary.succeeded, vec!["2023/TDR-2023.tar.gz"]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].file, "2024/TDR-2023.tar.gz");
+        assert!(summary.failed[0]
+            .reason
+            .contains("has the same file name as"));
+
+        let mirrored_output_dir = TempDir::new().unwrap();
+        let options = BatchOptions {
+            mirror: true,
+            ..BatchOptions::default()
+        };
+        let summary = process_batch(
+            &input_dir,
+            &mirrored_output_dir,
+            &files,
+            &Policy::default(),
+            options,
+        )
+        .unwrap();
+        assert_eq!(summary.succeeded.len(), 2);
+        assert!(!summary.has_failures());
+    }
+
+    #[test]
+    fn test_catch_panic_records_a_panic_as_a_failure() {
+        let outcome: Outcome = catch_panic(Path::new("TDR-2023.tar.gz"), || {
+            panic!("the package is too strange")
+        });
+        let Outcome::Failed(err) = outcome else {
+            panic!("The panic is not recorded as a failure");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Processing TDR-2023.tar.gz panicked: the package is too strange"
+        );
+        let outcome: Outcome = catch_panic(Path::new("TDR-2023.tar.gz"), || Outcome::Succeeded);
+        assert!(matches!(outcome, Outcome::Succeeded));
+    }
+
+    #[test]
+    fn test_batch_summary_display() {
+        let summary = BatchSummary {
+            succeeded: vec![String::from("TDR-2023.tar.gz")],
+            failed: vec![BatchItem {
+                file: String::from("TDR-2024.tar.gz"),
+                reason: String::from("bad archive"),
+                retryable: false,
+            }],
+            skipped: vec![BatchItem {
+                file: String::from("notes.txt"),
+                reason: String::from("not a tar.gz file"),
+                retryable: false,
+            }],
+        };
+        assert_eq!(
+            summary.to_string(),
+            "Summary: 1 succeeded, 1 failed, 1 skipped\n  Failed: TDR-2024.tar.gz: bad archive\n  Skipped: notes.txt: not a tar.gz file\n"
+        );
+    }
+}
@@ -0,0 +1,546 @@
+//! ## Docx scrubbing
+//!
+//! This keeps the structure of the original docx and only replaces the text in it.
+//! Styles, numbering, tables, headers, footers and footnotes are copied unchanged.
+//! Author names are removed from the document properties and from tracked changes and comments,
+//! the targets of external links are scrambled, embedded images are replaced with placeholders,
+//! and embedded objects and custom xml are emptied.
+use crate::error::AnonymiserError;
+use crate::fake::TextScrambler;
+use crate::media::placeholder_image;
+use crate::xml::scramble_value;
+use quick_xml::events::{BytesCData, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// The elements which contain the text of a run, including the field codes such as `HYPERLINK "mailto:..."`
+const TEXT_ELEMENTS: [&[u8]; 4] = [b"w:t", b"w:delText", b"w:instrText", b"w:delInstrText"];
+
+/// The attributes which name the author of a tracked change or comment
+const AUTHOR_ATTRIBUTES: [&[u8]; 3] = [b"w:author", b"w:initials", b"w15:author"];
+
+/// The parts which hold the document properties
+const PROPERTY_PARTS: [&str; 2] = ["docProps/core.xml", "docProps/app.xml"];
+
+/// The parts which hold custom properties or data bound to the document, which are blanked completely
+const CUSTOM_DATA_PARTS: [&str; 2] = ["docProps/custom.xml", "customXml/"];
+
+/// The document properties which can name a person or an organisation
+const PROPERTY_ELEMENTS: [&[u8]; 8] = [
+    b"dc:creator",
+    b"cp:lastModifiedBy",
+    b"dc:title",
+    b"dc:subject",
+    b"dc:description",
+    b"cp:keywords",
+    b"Company",
+    b"Manager",
+];
+
+/// # How the docx in the package is anonymised
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum DocxReplacement {
+    /// Replace the docx with a new one which only contains the judgment name
+    #[default]
+    Regenerate,
+    /// Keep the structure of the original docx and scramble the text of each run
+    Scrub(TextScrambler),
+}
+
+/// # Scrub the text from a docx
+///
+/// This copies every part of the original docx to a new one. The text of each run in the document,
+/// headers, footers, footnotes, endnotes and comments is replaced using the scrambler, as are the
+/// authors of tracked changes and comments and the field codes. The author, company and title are blanked
+/// in the document properties, and the custom properties and custom xml are blanked completely.
+/// The targets of external relationships, such as `mailto:` links, are scrambled keeping the scheme and host.
+/// Each image under `word/media/` is replaced with a placeholder of the same format, or emptied if the format
+/// is not recognised, and each object under `word/embeddings/` is emptied.
+pub fn scrub_docx(
+    docx_file_name: &str,
+    original_docx: &[u8],
+    scrambler: &TextScrambler,
+) -> Result<Vec<u8>, AnonymiserError> {
+    scrub_docx_parts(original_docx, &mut scrambler.clone()).map_err(|source| {
+        AnonymiserError::DocxGeneration {
+            path: Path::new(docx_file_name).to_path_buf(),
+            source,
+        }
+    })
+}
+
+type DocxResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// # How a part of the docx is scrubbed
+enum PartScrub {
+    /// Copy the part unchanged
+    Copy,
+    /// Scramble the text runs and the authors
+    Text,
+    /// Scramble the targets of the external relationships
+    Relationships,
+    /// Blank the document properties which can name a person or an organisation
+    Properties,
+    /// Blank all the text
+    CustomData,
+    /// Replace the image with a placeholder
+    Media,
+    /// Replace the part with an empty one
+    Empty,
+}
+
+impl PartScrub {
+    /// # Decide how a part is scrubbed from its name
+    fn of(part_name: &str) -> PartScrub {
+        if part_name.ends_with('/') {
+            PartScrub::Copy
+        } else if part_name.ends_with(".rels") {
+            PartScrub::Relationships
+        } else if PROPERTY_PARTS.contains(&part_name) {
+            PartScrub::Properties
+        } else if CUSTOM_DATA_PARTS
+            .iter()
+            .any(|custom_data_part| part_name.starts_with(custom_data_part))
+        {
+            PartScrub::CustomData
+        } else if part_name.starts_with("word/media/") {
+            PartScrub::Media
+        } else if part_name.starts_with("word/embeddings/") {
+            PartScrub::Empty
+        } else if part_name.starts_with("word/") && part_name.ends_with(".xml") {
+            PartScrub::Text
+        } else {
+            PartScrub::Copy
+        }
+    }
+}
+
+/// # Helper function to copy the parts of the docx, scrubbing the parts which can hold personal data
+fn scrub_docx_parts(original_docx: &[u8], scrambler: &mut TextScrambler) -> DocxResult<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(original_docx))?;
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for index in 0..archive.len() {
+        let mut part = archive.by_index(index)?;
+        let part_name: String = part.name().to_string();
+        let part_scrub: PartScrub = PartScrub::of(&part_name);
+        if let PartScrub::Copy = part_scrub {
+            writer.raw_copy_file(part)?;
+            continue;
+        }
+        let mut contents: Vec<u8> = Vec::new();
+        part.read_to_end(&mut contents)?;
+        let scrubbed: Vec<u8> = match part_scrub {
+            PartScrub::Copy => contents,
+            PartScrub::Text => scrub_xml(&contents, scrambler)?,
+            PartScrub::Relationships => scrub_relationships(&contents, scrambler)?,
+            PartScrub::Properties => blank_text(&contents, Some(&PROPERTY_ELEMENTS))?,
+            PartScrub::CustomData => blank_text(&contents, None)?,
+            PartScrub::Media => placeholder_image(&contents).unwrap_or_default(),
+            PartScrub::Empty => Vec::new(),
+        };
+        let options = FileOptions::default().compression_method(part.compression());
+        writer.start_file(part_name, options)?;
+        writer.write_all(&scrubbed)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// # Replace the text inside the run text elements of an xml part
+fn scrub_xml(xml: &[u8], scrambler: &mut TextScrambler) -> DocxResult<Vec<u8>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut in_text_element: bool = false;
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buffer)? {
+            Event::Eof => break,
+            Event::Start(element) => {
+                in_text_element = TEXT_ELEMENTS.contains(&element.name().as_ref());
+                writer.write_event(Event::Start(scrub_attributes(element, scrambler)?))?;
+            }
+            Event::Empty(element) => {
+                writer.write_event(Event::Empty(scrub_attributes(element, scrambler)?))?;
+            }
+            Event::End(element) => {
+                in_text_element = false;
+                writer.write_event(Event::End(element))?;
+            }
+            Event::Text(text) if in_text_element => {
+                let scrambled: String = scrambler.scramble(&text.unescape()?);
+                writer.write_event(Event::Text(BytesText::new(&scrambled)))?;
+            }
+            Event::CData(data) if in_text_element => {
+                let text: String = String::from_utf8_lossy(&data.into_inner()).to_string();
+                let scrambled: String = scrambler.scramble(&text);
+                writer.write_event(Event::CData(BytesCData::new(scrambled)))?;
+            }
+            event => writer.write_event(event)?,
+        }
+        buffer.clear();
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// # Scramble the targets of the external relationships of a part, such as hyperlinks
+///
+/// The targets of internal relationships are the names of other parts, so they are kept.
+fn scrub_relationships(xml: &[u8], scrambler: &mut TextScrambler) -> DocxResult<Vec<u8>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buffer)? {
+            Event::Eof => break,
+            Event::Start(element) => {
+                writer.write_event(Event::Start(scrub_target(element, scrambler)?))?;
+            }
+            Event::Empty(element) => {
+                writer.write_event(Event::Empty(scrub_target(element, scrambler)?))?;
+            }
+            event => writer.write_event(event)?,
+        }
+        buffer.clear();
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+/// # Scramble the target of a relationship if it is external
+fn scrub_target<'a>(
+    element: BytesStart<'a>,
+    scrambler: &mut TextScrambler,
+) -> DocxResult<BytesStart<'a>> {
+    let is_external: bool = element.attributes().flatten().any(|attribute| {
+        attribute.key.as_ref() == b"TargetMode" && attribute.value.as_ref() == b"External"
+    });
+    if element.name().as_ref() != b"Relationship" || !is_external {
+        return Ok(element);
+    }
+    let mut scrubbed: BytesStart =
+        BytesStart::new(String::from_utf8(element.name().as_ref().to_vec())?);
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let key: String = String::from_utf8(attribute.key.as_ref().to_vec())?;
+        let mut value: String = attribute.unescape_value()?.into_owned();
+        if key == "Target" {
+            value = scramble_value(scrambler, &value);
+        }
+        scrubbed.push_attribute((key.as_str(), value.as_str()));
+    }
+    Ok(scrubbed)
+}
+
+/// # Replace the values of the author attributes of an element
+///
+/// The element is returned unchanged if it has no author attributes.
+fn scrub_attributes<'a>(
+    element: BytesStart<'a>,
+    scrambler: &mut TextScrambler,
+) -> DocxResult<BytesStart<'a>> {
+    let has_author: bool = element
+        .attributes()
+        .flatten()
+        .any(|attribute| AUTHOR_ATTRIBUTES.contains(&attribute.key.as_ref()));
+    if !has_author {
+        return Ok(element);
+    }
+    let mut scrubbed: BytesStart =
+        BytesStart::new(String::from_utf8(element.name().as_ref().to_vec())?);
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let key: String = String::from_utf8(attribute.key.as_ref().to_vec())?;
+        let mut value: String = attribute.unescape_value()?.into_owned();
+        if AUTHOR_ATTRIBUTES.contains(&attribute.key.as_ref()) {
+            value = scrambler.scramble(&value);
+        }
+        scrubbed.push_attribute((key.as_str(), value.as_str()));
+    }
+    Ok(scrubbed)
+}
+
+/// # Remove the text inside the elements of an xml part, or all the text if `elements` is `None`
+fn blank_text(xml: &[u8], elements: Option<&[&[u8]]>) -> DocxResult<Vec<u8>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+    let mut in_blanked_element: bool = false;
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buffer)? {
+            Event::Eof => break,
+            Event::Start(element) => {
+                in_blanked_element =
+                    elements.is_none_or(|elements| elements.contains(&element.name().as_ref()));
+                writer.write_event(Event::Start(element))?;
+            }
+            Event::End(element) => {
+                in_blanked_element = elements.is_none();
+                writer.write_event(Event::End(element))?;
+            }
+            Event::Text(_) | Event::CData(_) if in_blanked_element => {}
+            event => writer.write_event(event)?,
+        }
+        buffer.clear();
+    }
+    Ok(writer.into_inner().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use docx_rs::{Comment, Delete, Docx, Hyperlink, HyperlinkType, Insert, Paragraph, Pic, Run};
+    use image::ImageFormat;
+    use std::io::Seek;
+    use testlib::{test_docx, test_image};
+
+    /// # Creates a test docx with an author, tracked changes, a comment and an image
+    fn test_docx_with_author() -> Vec<u8> {
+        let mut docx: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        Docx::new()
+            .add_paragraph(
+                Paragraph::new()
+                    .add_comment_start(
+                        Comment::new(1).author("Jane Author").add_paragraph(
+                            Paragraph::new().add_run(Run::new().add_text("Check this")),
+                        ),
+                    )
+                    .add_insert(Insert::new(Run::new().add_text("Smith")).author("Jane Author"))
+                    .add_delete(
+                        Delete::new()
+                            .author("Jane Author")
+                            .add_run(Run::new().add_delete_text("Jones")),
+                    )
+                    .add_comment_end(1),
+            )
+            .add_paragraph(
+                Paragraph::new().add_run(Run::new().add_image(Pic::new(&test_image(
+                    10,
+                    10,
+                    ImageFormat::Png,
+                )))),
+            )
+            .build()
+            .pack(&mut docx)
+            .unwrap();
+        let docx = with_part(docx.into_inner(), "word/comments.xml", |xml| {
+            xml.replace(r#"w:initials="""#, r#"w:initials="JA""#)
+        });
+        let docx = with_part(docx, "docProps/core.xml", |xml| {
+            xml.replace("unknown", "Jane Author")
+        });
+        with_part(docx, "docProps/app.xml", |_| {
+            String::from(
+                r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Properties xmlns="http://schemas.openxmlformats.org/officeDocument/2006/extended-properties"><Company>Author &amp; Co</Company></Properties>"#,
+            )
+        })
+    }
+
+    /// # Creates a test docx with a mailto link, a field code, an embedded object and custom data
+    fn test_docx_with_links() -> Vec<u8> {
+        let mut docx: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        Docx::new()
+            .custom_property("Client", "Jane Author")
+            .add_paragraph(
+                Paragraph::new().add_hyperlink(
+                    Hyperlink::new("mailto:jane.author@example.org", HyperlinkType::External)
+                        .add_run(Run::new().add_text("Email the clerk")),
+                ),
+            )
+            .build()
+            .pack(&mut docx)
+            .unwrap();
+        let docx = with_part(docx.into_inner(), "word/document.xml", |xml| {
+            xml.replacen(
+                "<w:body>",
+                r#"<w:body><w:p><w:r><w:instrText xml:space="preserve"> HYPERLINK "mailto:jane.author@example.org" </w:instrText></w:r><w:r><w:t><![CDATA[Jane Author]]></w:t></w:r></w:p>"#,
+                1,
+            )
+        });
+        let docx = with_added_part(docx, "word/embeddings/oleObject1.bin", b"Jane Author");
+        with_added_part(
+            docx,
+            "customXml/item1.xml",
+            b"<client><name>Jane Author</name></client>",
+        )
+    }
+
+    /// # Adds a part to a docx
+    fn with_added_part(docx: Vec<u8>, part_name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut archive = ZipArchive::new(Cursor::new(docx)).unwrap();
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for index in 0..archive.len() {
+            writer
+                .raw_copy_file(archive.by_index(index).unwrap())
+                .unwrap();
+        }
+        writer
+            .start_file(part_name, FileOptions::default())
+            .unwrap();
+        writer.write_all(contents).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// # Rewrites one xml part of a docx
+    fn with_part(docx: Vec<u8>, part_name: &str, rewrite: impl Fn(String) -> String) -> Vec<u8> {
+        let mut archive = ZipArchive::new(Cursor::new(docx)).unwrap();
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for index in 0..archive.len() {
+            let part = archive.by_index(index).unwrap();
+            if part.name() == part_name {
+                let name: String = part.name().to_string();
+                let contents: String = rewrite(std::io::read_to_string(part).unwrap());
+                writer.start_file(name, FileOptions::default()).unwrap();
+                writer.write_all(contents.as_bytes()).unwrap();
+            } else {
+                writer.raw_copy_file(part).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn read_part<R: Read + Seek>(archive: &mut ZipArchive<R>, part_name: &str) -> String {
+        let mut contents = String::new();
+        archive
+            .by_name(part_name)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    fn part_names<R: Read + Seek>(archive: &ZipArchive<R>) -> Vec<String> {
+        let mut part_names: Vec<String> = archive.file_names().map(String::from).collect();
+        part_names.sort();
+        part_names
+    }
+
+    #[test]
+    fn test_scrub_docx_keeps_the_structure() {
+        let original = test_docx();
+        let scrubbed = scrub_docx("test.docx", &original, &TextScrambler::new(1)).unwrap();
+        let mut original_archive = ZipArchive::new(Cursor::new(original)).unwrap();
+        let mut scrubbed_archive = ZipArchive::new(Cursor::new(scrubbed)).unwrap();
+
+        assert_eq!(part_names(&scrubbed_archive), part_names(&original_archive));
+        assert_eq!(
+            read_part(&mut scrubbed_archive, "word/styles.xml"),
+            read_part(&mut original_archive, "word/styles.xml")
+        );
+        let document = read_part(&mut scrubbed_archive, "word/document.xml");
+        assert!(document.contains("<w:tbl>"));
+        assert!(document.contains(r#"<w:pStyle w:val="Heading1" />"#));
+        assert!(!document.contains("Smith"));
+        assert!(document.contains(" &amp; "));
+        assert!(!read_part(&mut scrubbed_archive, "word/header1.xml").contains("Neutral"));
+        assert!(!read_part(&mut scrubbed_archive, "word/footer1.xml").contains("Page"));
+    }
+
+    #[test]
+    fn test_scrub_docx_removes_the_authors_and_images() {
+        let original = test_docx_with_author();
+        let mut original_archive = ZipArchive::new(Cursor::new(original.clone())).unwrap();
+        assert!(read_part(&mut original_archive, "docProps/core.xml").contains("Jane Author"));
+        assert!(read_part(&mut original_archive, "word/document.xml").contains("Jane Author"));
+        assert!(
+            read_part(&mut original_archive, "word/comments.xml").contains(r#"w:initials="JA""#)
+        );
+
+        let scrubbed = scrub_docx("test.docx", &original, &TextScrambler::new(1)).unwrap();
+        let mut scrubbed_archive = ZipArchive::new(Cursor::new(scrubbed)).unwrap();
+        assert_eq!(part_names(&scrubbed_archive), part_names(&original_archive));
+        let core = read_part(&mut scrubbed_archive, "docProps/core.xml");
+        assert!(!core.contains("Jane"));
+        assert!(core.contains("<dc:creator></dc:creator>"));
+        assert!(core.contains("<cp:lastModifiedBy></cp:lastModifiedBy>"));
+        let app = read_part(&mut scrubbed_archive, "docProps/app.xml");
+        assert!(app.contains("<Company></Company>"));
+        let document = read_part(&mut scrubbed_archive, "word/document.xml");
+        assert!(!document.contains("Jane"));
+        assert!(!document.contains("Smith"));
+        assert!(!document.contains("Jones"));
+        assert!(document.contains("<w:ins "));
+        assert!(document.contains("<w:del "));
+        let comments = read_part(&mut scrubbed_archive, "word/comments.xml");
+        assert!(!comments.contains("Jane"));
+        assert!(!comments.contains(r#"w:initials="JA""#));
+
+        let media_name: String = part_names(&scrubbed_archive)
+            .into_iter()
+            .find(|name| name.starts_with("word/media/") && !name.ends_with('/'))
+            .unwrap();
+        let mut original_image: Vec<u8> = Vec::new();
+        original_archive
+            .by_name(&media_name)
+            .unwrap()
+            .read_to_end(&mut original_image)
+            .unwrap();
+        let mut scrubbed_image: Vec<u8> = Vec::new();
+        scrubbed_archive
+            .by_name(&media_name)
+            .unwrap()
+            .read_to_end(&mut scrubbed_image)
+            .unwrap();
+        assert_ne!(scrubbed_image, original_image);
+        assert_eq!(scrubbed_image, placeholder_image(&original_image).unwrap());
+    }
+
+    #[test]
+    fn test_scrub_docx_removes_links_embedded_objects_and_custom_data() {
+        let original = test_docx_with_links();
+        let mut original_archive = ZipArchive::new(Cursor::new(original.clone())).unwrap();
+        let original_text: String = part_names(&original_archive)
+            .iter()
+            .filter(|name| !name.ends_with('/'))
+            .map(|name| read_part(&mut original_archive, name))
+            .collect();
+        assert!(original_text.contains("jane.author@example.org"));
+        assert!(
+            read_part(&mut original_archive, "word/_rels/document.xml.rels")
+                .contains("mailto:jane.author@example.org")
+        );
+        assert!(read_part(&mut original_archive, "docProps/custom.xml").contains("Jane Author"));
+
+        let scrubbed = scrub_docx("test.docx", &original, &TextScrambler::new(1)).unwrap();
+        let mut scrubbed_archive = ZipArchive::new(Cursor::new(scrubbed)).unwrap();
+        assert_eq!(part_names(&scrubbed_archive), part_names(&original_archive));
+        for part_name in part_names(&scrubbed_archive) {
+            if part_name.ends_with('/') {
+                continue;
+            }
+            let part: String = read_part(&mut scrubbed_archive, &part_name);
+            assert!(!part.contains("jane.author"), "{part_name} has the email");
+            assert!(!part.contains("Jane Author"), "{part_name} has the name");
+        }
+        let relationships = read_part(&mut scrubbed_archive, "word/_rels/document.xml.rels");
+        assert!(relationships.contains(r#"TargetMode="External""#));
+        assert!(relationships.contains(r#"Target="styles.xml""#));
+        assert!(read_part(&mut scrubbed_archive, "word/embeddings/oleObject1.bin").is_empty());
+        assert_eq!(
+            read_part(&mut scrubbed_archive, "customXml/item1.xml"),
+            "<client><name></name></client>"
+        );
+    }
+
+    #[test]
+    fn test_scrub_docx_is_reproducible_for_a_seed() {
+        let original = test_docx();
+        let scrubbed = scrub_docx("test.docx", &original, &TextScrambler::new(1)).unwrap();
+        assert_eq!(
+            scrubbed,
+            scrub_docx("test.docx", &original, &TextScrambler::new(1)).unwrap()
+        );
+        assert_ne!(
+            scrubbed,
+            scrub_docx("test.docx", &original, &TextScrambler::new(2)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_scrub_docx_error_if_not_a_docx() {
+        let err = scrub_docx("test.docx", b"", &TextScrambler::new(1)).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Cannot generate the docx test.docx: "));
+    }
+}
@@ -0,0 +1,190 @@
+//! ## Errors
+//!
+//! Every function in the library returns an `AnonymiserError` so callers can tell what went wrong
+//! without matching on the message.
+use crate::pii::PiiFinding;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// # The errors returned by the anonymiser library
+#[derive(Error, Debug)]
+pub enum AnonymiserError {
+    /// The package file name can't be turned into a batch reference
+    #[error("Invalid package name '{name}': {reason}")]
+    InvalidPackageName { name: String, reason: String },
+
+    /// A batch reference is not a source system, a year and an optional identifier
+    #[error("Invalid batch reference '{reference}': {reason}")]
+    InvalidBatchReference { reference: String, reason: String },
+
+    /// A field the anonymiser needs is missing from the metadata json
+    #[error("'{field}' is missing from the metadata json at {pointer}")]
+    MissingMetadataField { field: String, pointer: String },
+
+    /// A field in the metadata json doesn't have the type or value the anonymiser needs
+    #[error("'{pointer}' in the metadata json is not valid: {reason}")]
+    InvalidMetadataField { pointer: String, reason: String },
+
+    /// A file the anonymiser needs is missing from the package
+    #[error("'{}' is missing from the package", path.display())]
+    MissingEntry { path: PathBuf },
+
+    /// The tar.gz file can't be read
+    #[error("Cannot read the archive {}: {source}", path.display())]
+    BadArchive { path: PathBuf, source: io::Error },
+
+    /// An entry in the tar.gz has an absolute path or a `..` component, so it could be written outside the output folder
+    #[error("'{}' in the archive {} is not a relative path inside the archive", entry.display(), path.display())]
+    UnsafeEntryPath { path: PathBuf, entry: PathBuf },
+
+    /// An entry in the tar.gz is a symlink or a hardlink
+    #[error("'{}' in the archive {} is a link", entry.display(), path.display())]
+    LinkEntry { path: PathBuf, entry: PathBuf },
+
+    /// An entry in the tar.gz is a device or a fifo
+    #[error("'{}' in the archive {} is a device or fifo, not a regular file or folder", entry.display(), path.display())]
+    SpecialEntry { path: PathBuf, entry: PathBuf },
+
+    /// Two entries in the tar.gz have the same path, so it isn't clear which one is the package
+    #[error("'{}' appears more than once in the archive {}", entry.display(), path.display())]
+    DuplicateEntry { path: PathBuf, entry: PathBuf },
+
+    /// The tar.gz has more entries than the extraction limits allow
+    #[error("The archive {} has more than {limit} entries", path.display())]
+    TooManyEntries { path: PathBuf, limit: u64 },
+
+    /// An entry in the tar.gz is larger than the extraction limits allow
+    #[error("'{}' in the archive {} is larger than the limit of {limit} bytes", entry.display(), path.display())]
+    EntryTooLarge {
+        path: PathBuf,
+        entry: PathBuf,
+        limit: u64,
+    },
+
+    /// The entries in the tar.gz are larger in total than the extraction limits allow
+    #[error("The entries in the archive {} are larger than the limit of {limit} bytes in total", path.display())]
+    ArchiveTooLarge { path: PathBuf, limit: u64 },
+
+    /// A json file can't be parsed
+    #[error("Cannot parse the json in {}: {source}", path.display())]
+    JsonParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    /// An xml file can't be parsed
+    #[error("Cannot parse the xml in {}: {source}", path.display())]
+    XmlParse {
+        path: PathBuf,
+        source: quick_xml::Error,
+    },
+
+    /// The replacement docx can't be written
+    #[error("Cannot generate the docx {}: {source}", path.display())]
+    DocxGeneration {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// The policy fails packages with personal data in the metadata json
+    #[error(
+        "Possible personal data found in the metadata json: {}",
+        findings.iter().map(ToString::to_string).collect::<Vec<String>>().join(", ")
+    )]
+    PiiDetected { findings: Vec<PiiFinding> },
+
+    /// The policy fails packages with files which are not expected or allowed
+    #[error("Unexpected files in the package: {}", paths.join(", "))]
+    UnexpectedFiles { paths: Vec<String> },
+
+    /// The rules, key or other settings are not valid
+    #[error("{0}")]
+    InvalidPolicy(String),
+
+    /// Processing a package panicked, so the batch records it as failed instead of stopping
+    #[error("Processing {} panicked: {message}", path.display())]
+    Panicked { path: PathBuf, message: String },
+
+    /// Two packages in a batch have the same file name, so the second would overwrite the output of the first
+    #[error("{} has the same file name as {}, so it would overwrite its anonymised package. Use --mirror to keep the subfolders", path.display(), other.display())]
+    OutputCollision { path: PathBuf, other: PathBuf },
+
+    /// Reading or writing a file failed
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    /// The input folder, or one of its subfolders, can't be read
+    #[error("Cannot read the input folder {}: {source}", path.display())]
+    InputDir { path: PathBuf, source: io::Error },
+}
+
+impl AnonymiserError {
+    /// # Whether trying the same package again might succeed
+    ///
+    /// Problems with the package or the policy are permanent. Most file system errors are not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AnonymiserError::Io { source, .. } | AnonymiserError::InputDir { source, .. } => {
+                !matches!(
+                    source.kind(),
+                    ErrorKind::NotFound
+                        | ErrorKind::InvalidInput
+                        | ErrorKind::InvalidData
+                        | ErrorKind::Unsupported
+                )
+            }
+            _ => false,
+        }
+    }
+
+    /// # Create a missing metadata field error from its json pointer
+    pub(crate) fn missing_metadata_field(pointer: &str) -> AnonymiserError {
+        AnonymiserError::MissingMetadataField {
+            field: pointer.rsplit('/').next().unwrap_or_default().to_string(),
+            pointer: pointer.to_string(),
+        }
+    }
+}
+
+/// # Adds the path to an io error
+pub(crate) trait WithPath<T> {
+    fn with_path(self, path: &Path) -> Result<T, AnonymiserError>;
+}
+
+impl<T> WithPath<T> for Result<T, io::Error> {
+    fn with_path(self, path: &Path) -> Result<T, AnonymiserError> {
+        self.map_err(|source| AnonymiserError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_metadata_field_message() {
+        let err = AnonymiserError::missing_metadata_field("/parameters/TRE/payload/filename");
+        assert_eq!(
+            err.to_string(),
+            "'filename' is missing from the metadata json at /parameters/TRE/payload/filename"
+        );
+    }
+
+    #[test]
+    fn test_io_errors_are_retryable_unless_permanent() {
+        let error = |kind: ErrorKind| -> AnonymiserError {
+            Err::<(), io::Error>(io::Error::new(kind, "test"))
+                .with_path(Path::new("/test"))
+                .unwrap_err()
+        };
+        assert!(error(ErrorKind::Interrupted).is_retryable());
+        assert!(error(ErrorKind::PermissionDenied).is_retryable());
+        assert!(!error(ErrorKind::NotFound).is_retryable());
+        assert!(!AnonymiserError::InvalidPolicy(String::from("test")).is_retryable());
+    }
+}
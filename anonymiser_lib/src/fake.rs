@@ -0,0 +1,251 @@
+//! ## Fake values
+//!
+//! This generates realistic but fake replacements which have the same type as the original value.
+//! Each value is derived from the seed and the original, so the same seed always gives the same output.
+//! It also scrambles free text, such as the text of a document, keeping its shape.
+use sha2::{Digest, Sha256};
+
+const FIRST_NAMES: [&str; 16] = [
+    "Alex", "Sam", "Jordan", "Charlie", "Morgan", "Jamie", "Taylor", "Casey", "Robin", "Ashley",
+    "Frankie", "Riley", "Harper", "Rowan", "Elliot", "Jesse",
+];
+
+const LAST_NAMES: [&str; 16] = [
+    "Smith", "Jones", "Taylor", "Brown", "Williams", "Wilson", "Johnson", "Davies", "Patel",
+    "Wright", "Evans", "Thomas", "Roberts", "Walker", "Green", "Hughes",
+];
+
+/// The reserved domain used for fake email addresses
+pub const FAKE_EMAIL_DOMAIN: &str = "example.com";
+
+/// # The kinds of fake value which can be generated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FakeValueKind {
+    Email,
+    Name,
+    Uuid,
+    /// A file path, which is never chosen from a metadata value or field name
+    Path,
+}
+
+impl FakeValueKind {
+    /// # Work out which kind of value to generate from the original value
+    pub fn of(value: &str) -> FakeValueKind {
+        if value.contains('@') {
+            FakeValueKind::Email
+        } else if is_uuid(value) {
+            FakeValueKind::Uuid
+        } else {
+            FakeValueKind::Name
+        }
+    }
+
+    /// # Work out which kind of value to generate from the name of the field
+    pub fn of_field(field_name: &str) -> FakeValueKind {
+        let field_name: String = field_name.to_lowercase();
+        if field_name.contains("email") {
+            FakeValueKind::Email
+        } else if field_name.contains("uuid") || field_name.ends_with("-id") || field_name == "id" {
+            FakeValueKind::Uuid
+        } else {
+            FakeValueKind::Name
+        }
+    }
+}
+
+/// # A seedable generator of fake values
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FakeValueGenerator {
+    seed: u64,
+}
+
+impl FakeValueGenerator {
+    pub fn new(seed: u64) -> FakeValueGenerator {
+        FakeValueGenerator { seed }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// # Generate a fake value of the same kind as the original
+    pub fn fake_for(&self, original: &str) -> String {
+        self.generate(FakeValueKind::of(original), original)
+    }
+
+    /// # Generate a fake value of a particular kind
+    ///
+    /// The `input` is only used to pick the value so it can't be recovered from the output.
+    pub fn generate(&self, kind: FakeValueKind, input: &str) -> String {
+        let bytes: Vec<u8> = self.bytes_for(input);
+        let first_name: &str = FIRST_NAMES[bytes[0] as usize % FIRST_NAMES.len()];
+        let last_name: &str = LAST_NAMES[bytes[1] as usize % LAST_NAMES.len()];
+        match kind {
+            FakeValueKind::Name => format!("{first_name} {last_name}"),
+            FakeValueKind::Email => format!(
+                "{}.{}{}@{FAKE_EMAIL_DOMAIN}",
+                first_name.to_lowercase(),
+                last_name.to_lowercase(),
+                bytes[2] % 100
+            ),
+            FakeValueKind::Path => format!(
+                "/home/{}.{}/document{}",
+                first_name.to_lowercase(),
+                last_name.to_lowercase(),
+                bytes[2] % 100
+            ),
+            FakeValueKind::Uuid => {
+                let mut uuid_bytes: Vec<u8> = bytes[..16].to_vec();
+                // Mark it as a version 4, variant 1 UUID
+                uuid_bytes[6] = (uuid_bytes[6] & 0x0f) | 0x40;
+                uuid_bytes[8] = (uuid_bytes[8] & 0x3f) | 0x80;
+                let hex: String = hex::encode(uuid_bytes);
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                )
+            }
+        }
+    }
+
+    fn bytes_for(&self, input: &str) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_be_bytes());
+        hasher.update(input.as_bytes());
+        hasher.finalize().to_vec()
+    }
+}
+
+/// # A seedable generator of scrambled text
+///
+/// Scrambled text has the same shape as the original. Letters are replaced with letters of the same case
+/// and digits with digits. Spaces and punctuation are kept.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TextScrambler {
+    seed: u64,
+    counter: u64,
+    bytes: Vec<u8>,
+}
+
+impl TextScrambler {
+    pub fn new(seed: u64) -> TextScrambler {
+        TextScrambler {
+            seed,
+            ..TextScrambler::default()
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// # Scramble the text, keeping its shape
+    pub fn scramble(&mut self, text: &str) -> String {
+        text.chars()
+            .map(|c| {
+                if c.is_uppercase() {
+                    (b'A' + self.next_byte() % 26) as char
+                } else if c.is_alphabetic() {
+                    (b'a' + self.next_byte() % 26) as char
+                } else if c.is_numeric() {
+                    (b'0' + self.next_byte() % 10) as char
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.bytes.is_empty() {
+            let mut hasher = Sha256::new();
+            hasher.update(self.seed.to_be_bytes());
+            hasher.update(self.counter.to_be_bytes());
+            self.bytes = hasher.finalize().to_vec();
+            self.counter += 1;
+        }
+        self.bytes.pop().unwrap_or_default()
+    }
+}
+
+/// # Helper function to check a value looks like a UUID
+fn is_uuid(value: &str) -> bool {
+    value.len() == 36
+        && value.char_indices().all(|(index, c)| match index {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_values_have_the_same_kind_as_the_original() {
+        let generator = FakeValueGenerator::new(1);
+        let email = generator.fake_for("someone@nationalarchives.gov.uk");
+        let uuid = generator.fake_for("4ab8f3d2-8a43-4b1c-9a2f-0123456789ab");
+        let name = generator.fake_for("Test Person");
+
+        assert!(email.ends_with("@example.com"));
+        assert_eq!(email.matches('@').count(), 1);
+        assert!(is_uuid(&uuid));
+        assert_eq!(&uuid[14..15], "4");
+        assert_eq!(name.split(' ').count(), 2);
+        assert_eq!(FakeValueKind::of(&name), FakeValueKind::Name);
+    }
+
+    #[test]
+    fn test_fake_values_are_reproducible_for_a_seed() {
+        let generator = FakeValueGenerator::new(1);
+        assert_eq!(
+            generator.fake_for("Test Person"),
+            FakeValueGenerator::new(1).fake_for("Test Person")
+        );
+        assert_eq!(generator.fake_for("Test Person"), "Alex Roberts");
+        assert_eq!(
+            generator.generate(FakeValueKind::Path, "/home/test/judgment.docx"),
+            generator.generate(FakeValueKind::Path, "/home/test/judgment.docx")
+        );
+        assert!(generator
+            .generate(FakeValueKind::Path, "/home/test/judgment.docx")
+            .starts_with("/home/"));
+        assert_eq!(
+            generator.fake_for("test@nationalarchives.gov.uk"),
+            "elliot.johnson11@example.com"
+        );
+    }
+
+    #[test]
+    fn test_scrambled_text_has_the_same_shape() {
+        let scrambled = TextScrambler::new(1).scramble("Mr A. Smith, [2023] EWCA 1");
+        assert_eq!(scrambled.len(), 26);
+        for (original, scrambled) in "Mr A. Smith, [2023] EWCA 1".chars().zip(scrambled.chars()) {
+            assert_eq!(original.is_uppercase(), scrambled.is_uppercase());
+            assert_eq!(original.is_lowercase(), scrambled.is_lowercase());
+            assert_eq!(original.is_ascii_digit(), scrambled.is_ascii_digit());
+            if !original.is_alphanumeric() {
+                assert_eq!(original, scrambled);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fake_value_kind_of_field() {
+        assert_eq!(
+            FakeValueKind::of_field("Contact-Email"),
+            FakeValueKind::Email
+        );
+        assert_eq!(FakeValueKind::of_field("Contact-Name"), FakeValueKind::Name);
+        assert_eq!(FakeValueKind::of_field("UUID"), FakeValueKind::Uuid);
+        assert_eq!(
+            FakeValueKind::of_field("Consignment-Id"),
+            FakeValueKind::Uuid
+        );
+    }
+}
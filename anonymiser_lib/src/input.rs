@@ -0,0 +1,250 @@
+//! ## Finding the input packages
+//!
+//! The files in the input folder are filtered with `--include` and `--exclude` glob patterns, relative to the input folder.
+//! By default only `*.tar.gz` files are included. `*` also matches `/`, so `*.tar.gz` matches files in subfolders too.
+//! With `--recursive`, the subfolders of the input folder are searched as well.
+//!
+//! Hidden files and folders, which start with `.`, are ignored, and so are any folders the anonymiser writes to,
+//! such as the output folder. Any other file which isn't included is reported as skipped.
+use crate::batch::BatchItem;
+use crate::error::AnonymiserError;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::fs::{self, DirEntry};
+use std::path::{Path, PathBuf};
+
+/// The pattern of the files included by default
+pub const DEFAULT_INCLUDE: &str = "*.tar.gz";
+
+/// # Which files in the input folder are processed
+#[derive(Clone, Debug)]
+pub struct InputFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+    /// Search the subfolders of the input folder too
+    pub recursive: bool,
+}
+
+impl Default for InputFilter {
+    fn default() -> Self {
+        InputFilter::new(&[String::from(DEFAULT_INCLUDE)], &[], false)
+            .expect("The default include pattern is valid")
+    }
+}
+
+/// # The files found in the input folder
+#[derive(Debug, Default, PartialEq)]
+pub struct InputFiles {
+    /// The files to process, sorted by path
+    pub files: Vec<PathBuf>,
+    /// The files which aren't processed, and why, by their path relative to the input folder
+    pub skipped: Vec<BatchItem>,
+}
+
+impl InputFilter {
+    /// # Build a filter from include and exclude glob patterns
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        recursive: bool,
+    ) -> Result<InputFilter, AnonymiserError> {
+        Ok(InputFilter {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+            recursive,
+        })
+    }
+
+    /// # Why a file isn't processed, or `None` if it is
+    fn skip_reason(&self, relative_path: &Path) -> Option<&'static str> {
+        if !self.include.is_match(relative_path) {
+            Some("not matched by the include patterns")
+        } else if self.exclude.is_match(relative_path) {
+            Some("matched by the exclude patterns")
+        } else {
+            None
+        }
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, AnonymiserError> {
+    let mut glob_set: GlobSetBuilder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| {
+            AnonymiserError::InvalidPolicy(format!("Invalid input file pattern: {e}"))
+        })?;
+        glob_set.add(glob);
+    }
+    glob_set
+        .build()
+        .map_err(|e| AnonymiserError::InvalidPolicy(e.to_string()))
+}
+
+/// # Find the files to process in the input folder
+///
+/// Folders in `ignored` aren't searched, so the output of a run isn't picked up as input by the next one.
+pub fn find_input_files(
+    dir_input: &Path,
+    filter: &InputFilter,
+    ignored: &[PathBuf],
+) -> Result<InputFiles, AnonymiserError> {
+    let mut input_files: InputFiles = InputFiles::default();
+    let mut folders: Vec<PathBuf> = vec![dir_input.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let input_dir_error = |source| AnonymiserError::InputDir {
+            path: folder.clone(),
+            source,
+        };
+        for entry in fs::read_dir(&folder).map_err(input_dir_error)? {
+            let entry: DirEntry = entry.map_err(input_dir_error)?;
+            let path: PathBuf = entry.path();
+            if is_hidden(&entry) {
+                continue;
+            }
+            if path.is_dir() {
+                if filter.recursive && !is_ignored(&path, ignored) {
+                    folders.push(path);
+                }
+                continue;
+            }
+            let relative_path: &Path = path.strip_prefix(dir_input).unwrap_or(&path);
+            match filter.skip_reason(relative_path) {
+                None => input_files.files.push(path),
+                Some(reason) => input_files.skipped.push(BatchItem {
+                    file: relative_path.to_string_lossy().to_string(),
+                    reason: String::from(reason),
+                    retryable: false,
+                }),
+            }
+        }
+    }
+    input_files.files.sort();
+    input_files
+        .skipped
+        .sort_by(|first, second| first.file.cmp(&second.file));
+    Ok(input_files)
+}
+
+/// # Helper function to check if a file or folder starts with `.`
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|file_name| file_name.starts_with('.'))
+        .unwrap_or(true)
+}
+
+fn is_ignored(folder: &Path, ignored: &[PathBuf]) -> bool {
+    let canonical_folder: Option<PathBuf> = folder.canonicalize().ok();
+    ignored.iter().any(|ignored_folder| {
+        folder == ignored_folder
+            || canonical_folder.as_ref() == ignored_folder.canonicalize().ok().as_ref()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use std::fs::{create_dir_all, write};
+
+    fn input_dir() -> TempDir {
+        let input_dir = TempDir::new().unwrap();
+        for file in [
+            "TDR-2023.tar.gz",
+            "notes.txt",
+            ".TDR-2024.tar.gz",
+            "2024/TDR-2024.tar.gz",
+            "2024/old/TDR-2025.tar.gz",
+            "output/TST-2023.tar.gz",
+            ".hidden/TDR-2026.tar.gz",
+        ] {
+            let path: PathBuf = input_dir.join(file);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, "").unwrap();
+        }
+        input_dir
+    }
+
+    fn relative_files(input_dir: &TempDir, input_files: &InputFiles) -> Vec<String> {
+        input_files
+            .files
+            .iter()
+            .map(|file| {
+                file.strip_prefix(input_dir.path())
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_input_files_at_the_top_level() {
+        let input_dir = input_dir();
+        let input_files = find_input_files(&input_dir, &InputFilter::default(), &[]).unwrap();
+        assert_eq!(
+            relative_files(&input_dir, &input_files),
+            vec!["TDR-2023.tar.gz"]
+        );
+        assert_eq!(
+            input_files.skipped,
+            vec![BatchItem {
+                file: String::from("notes.txt"),
+                reason: String::from("not matched by the include patterns"),
+                retryable: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_find_input_files_recursively_with_filters() {
+        let input_dir = input_dir();
+        let filter = InputFilter::new(
+            &[String::from(DEFAULT_INCLUDE)],
+            &[String::from("*/old/*")],
+            true,
+        )
+        .unwrap();
+        let input_files =
+            find_input_files(&input_dir, &filter, &[input_dir.join("output")]).unwrap();
+        assert_eq!(
+            relative_files(&input_dir, &input_files),
+            vec!["2024/TDR-2024.tar.gz", "TDR-2023.tar.gz"]
+        );
+        let skipped: Vec<(&str, &str)> = input_files
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.file.as_str(), skipped.reason.as_str()))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (
+                    "2024/old/TDR-2025.tar.gz",
+                    "matched by the exclude patterns"
+                ),
+                ("notes.txt", "not matched by the include patterns")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_input_files_error_if_the_input_folder_is_missing() {
+        let input_dir = TempDir::new().unwrap();
+        let missing: PathBuf = input_dir.join("missing");
+        let err = find_input_files(&missing, &InputFilter::default(), &[]).unwrap_err();
+        assert!(matches!(err, AnonymiserError::InputDir { .. }));
+        assert!(err.to_string().starts_with(&format!(
+            "Cannot read the input folder {}: ",
+            missing.display()
+        )));
+    }
+
+    #[test]
+    fn test_input_filter_error_if_a_pattern_is_invalid() {
+        let err = InputFilter::new(&[String::from("[")], &[], false).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid input file pattern: error parsing glob '['"));
+    }
+}
@@ -3,236 +3,620 @@
 //! This library contains common code shared between the anonymiser script and the lambda.
 use clap::Parser;
 use docx_rs::*;
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde_json::{json, Value};
-use sha256::try_digest;
 
-use std::fs::{remove_file, DirEntry};
-use std::io::ErrorKind;
-use std::{fs, fs::File, io, io::Error, io::Read, path::Path, path::PathBuf};
-use tar::{Archive, Builder};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::{fs, fs::File, io::Cursor, io::Read, path::Path, path::PathBuf};
+use tempfile::TempDir;
+
+mod allow_list;
+mod archive;
+mod audit;
+mod batch;
+mod docx;
+mod error;
+mod fake;
+mod input;
+mod media;
+mod parser_log;
+mod payload;
+mod pii;
+mod plan;
+mod publish;
+mod reference;
+mod replacement;
+mod reproducible;
+mod rules;
+mod stream;
+mod transform;
+mod verify;
+mod watch;
+mod xml;
+pub use allow_list::*;
+pub use archive::{
+    ExtractionLimits, DEFAULT_MAX_ENTRIES, DEFAULT_MAX_ENTRY_SIZE, DEFAULT_MAX_TOTAL_SIZE,
+};
+pub use audit::*;
+pub use batch::*;
+pub use docx::*;
+pub use error::*;
+pub use fake::*;
+pub use input::*;
+pub use media::MAX_PLACEHOLDER_SIDE;
+pub use parser_log::*;
+pub use payload::*;
+pub use pii::*;
+pub use plan::*;
+pub use reference::*;
+pub use replacement::*;
+pub use reproducible::ENTRY_MTIME;
+pub use rules::*;
+pub use stream::*;
+pub use verify::*;
+pub use watch::*;
+pub use xml::*;
+
+use archive::{unpack_archive, PackageEntries};
+use publish::{scratch_dir, PendingFile};
+use transform::PackageTransform;
+
+/// The checksum field which is always updated with the checksum of the new docx
+pub(crate) const CHECKSUM_POINTER: &str = "/parameters/TDR/Document-Checksum-sha256";
 
 /// # A struct representing the input arguments
 #[derive(Parser)]
-#[clap(name = "anonymiser")]
+#[clap(name = "anonymiser", subcommand_negates_reqs = true)]
 pub struct Opt {
     /// Input folder
-    #[clap(long, short, value_parser)]
-    pub input: String,
+    #[clap(long, short, value_parser, required = true)]
+    pub input: Option<String>,
 
     /// Output folder
+    #[clap(long, short, value_parser, required = true)]
+    pub output: Option<String>,
+
+    /// Redaction rules file. The built-in rules are used if this is not set
     #[clap(long, short, value_parser)]
-    pub output: String,
-}
+    pub rules: Option<String>,
 
-/// # Package processor
-/// This takes an output directory path and a path to a tar.gz file as input and anonymises them with the following steps:
-///
-/// * It replaces the values of Contact-Email and Contact-Name with XXXXXXX
-/// * It generates a new docx file which only contains the name of the judgment.
-/// * It updates the checksum field with the calculated checksum of the new docx file.
-/// * It renames the folder and metadata file from TDR-xxx to TST-xxx.
-/// * It creates a new tar.gz folder in the output directory.
-/// * It deletes the uncompressed folder in the output directory.
-pub fn process_package(dir_output: &PathBuf, file: &PathBuf) -> Result<PathBuf, Error> {
-    let tar_gz_file_name: String = file
-        .file_name()
-        .and_then(|name| name.to_os_string().into_string().ok())
-        .ok_or("Error getting the file name from the file")
-        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
-
-    let output_tar_gz_path: PathBuf =
-        Path::new(&dir_output).join(Path::new(&tar_gz_file_name.replace("TDR", "TST")));
-    let uncompressed_folder_input_path: &PathBuf = &file.with_extension("").with_extension("");
-    let input_batch_reference: String = uncompressed_folder_input_path
-        .file_name()
-        .and_then(|name| name.to_str().map(|name| name.replace("TRE-", "")))
-        .ok_or(Error::new(
-            ErrorKind::InvalidInput,
-            "Cannot get a batch reference from the file name",
-        ))?;
-    let output_batch_reference: &String = &input_batch_reference.replace("TDR", "TST");
-
-    let extracted_output_original_name: PathBuf =
-        dir_output.join(PathBuf::from(&input_batch_reference));
-    let extracted_output_path: PathBuf = dir_output.join(PathBuf::from(output_batch_reference));
-
-    let output_path_with_file = |file_name: &str| -> PathBuf {
-        let output_path = extracted_output_path.clone();
-        output_path.join(PathBuf::from(file_name))
-    };
+    /// What masked values are replaced with
+    #[clap(long, short, value_enum, default_value_t = ReplacementMode::Mask)]
+    pub mode: ReplacementMode,
 
-    fs::create_dir_all(extracted_output_path.clone())?;
+    /// File containing the pseudonymisation key, which is also used by hash rules and keyed hash references.
+    /// The ANONYMISER_KEY environment variable is used if this is not set
+    #[clap(long, short, value_parser)]
+    pub key_file: Option<String>,
 
-    decompress_file(file, dir_output)?;
+    /// How the batch reference of each anonymised package is chosen
+    #[clap(long, value_enum, default_value_t = ReferenceMode::PrefixSwap)]
+    pub reference_mode: ReferenceMode,
 
-    let metadata_input_file_path: PathBuf =
-        output_path_with_file(format!("TRE-{input_batch_reference}-metadata.json").as_str());
-    let metadata_output_file_path: PathBuf =
-        output_path_with_file(format!("TRE-{output_batch_reference}-metadata.json").as_str());
+    /// CSV file of original and anonymised batch references, used by the csv reference mode
+    #[clap(long, value_parser, required_if_eq("reference_mode", "csv"))]
+    pub reference_csv: Option<String>,
 
-    if extracted_output_path.exists() {
-        fs::remove_dir_all(&extracted_output_path)?;
-    }
-    fs::rename(extracted_output_original_name, &extracted_output_path)?;
-    fs::rename(metadata_input_file_path, &metadata_output_file_path)?;
+    /// The seed for generating fake values and scrambled docx text
+    #[clap(long, short, value_parser, default_value_t = 0)]
+    pub seed: u64,
 
-    let mut metadata_json_value: Value = parse_metadata_json(&metadata_output_file_path)?;
+    /// How the docx in each package is anonymised
+    #[clap(long, short, value_enum, default_value_t = DocxMode::Regenerate)]
+    pub docx: DocxMode,
 
-    let docx_checksum =
-        create_docx_with_checksum(&extracted_output_path, &mut metadata_json_value)?;
+    /// What happens to the LegalDocML xml in each package
+    #[clap(long, short, value_enum, default_value_t = XmlMode::Delete)]
+    pub xml: XmlMode,
 
-    update_json_file(
-        &metadata_output_file_path,
-        docx_checksum,
-        &mut metadata_json_value,
-    )?;
+    /// What happens to the parser log in each package
+    #[clap(long, short, value_enum, default_value_t = ParserLogMode::Drop)]
+    pub parser_log: ParserLogMode,
 
-    if_present_delete(output_path_with_file(
-        format!("{input_batch_reference}.xml").as_str(),
-    ))?;
-    if_present_delete(output_path_with_file("parser.log"))?;
+    /// What happens when an email address, phone number or other personal data is found in the metadata json
+    #[clap(long, value_enum, default_value_t = PiiAction::Warn)]
+    pub pii: PiiAction,
 
-    tar_folder(
-        &output_tar_gz_path,
-        &extracted_output_path,
-        output_batch_reference,
-    )?;
+    /// What happens to files in the package which are not named in the metadata json or allowed
+    #[clap(long, value_enum, default_value_t = UnexpectedFileAction::Fail)]
+    pub unexpected_files: UnexpectedFileAction,
+
+    /// A glob pattern of files which are allowed in each package, relative to the package folder. Can be repeated
+    #[clap(long, value_parser)]
+    pub allow_file: Vec<String>,
+
+    /// The largest total size of the files in each package, in bytes
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_TOTAL_SIZE)]
+    pub max_total_size: u64,
+
+    /// The largest number of files and folders in each package
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_ENTRIES)]
+    pub max_entries: u64,
+
+    /// The largest size of each file in each package, in bytes
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_ENTRY_SIZE)]
+    pub max_entry_size: u64,
+
+    /// Print the changes that would be made to each package without writing anything
+    #[clap(long, value_parser)]
+    pub dry_run: bool,
+
+    /// Anonymise each package without extracting it to the output folder
+    #[clap(long, value_parser)]
+    pub streaming: bool,
+
+    /// Stop at the first package which fails, instead of processing the rest and printing a summary
+    #[clap(long, value_parser)]
+    pub fail_fast: bool,
+
+    /// The number of packages processed at once
+    #[clap(long, short, value_parser, default_value_t = NonZeroUsize::MIN)]
+    pub jobs: NonZeroUsize,
+
+    /// Search the subfolders of the input folder for packages too
+    #[clap(long, value_parser)]
+    pub recursive: bool,
+
+    /// Only process input files which match these glob patterns, relative to the input folder. `*` also matches `/`
+    #[clap(long, value_parser, default_values_t = [String::from(DEFAULT_INCLUDE)])]
+    pub include: Vec<String>,
+
+    /// Skip input files which match these glob patterns, relative to the input folder
+    #[clap(long, value_parser)]
+    pub exclude: Vec<String>,
+
+    /// Write each package to the same subfolder of the output folder as it is in the input folder
+    #[clap(long, value_parser, requires = "recursive")]
+    pub mirror: bool,
 
-    fs::remove_dir_all(&extracted_output_path)?;
-    Ok(output_tar_gz_path)
+    /// Keep running and anonymise each tar.gz file as it lands in the input folder, until interrupted
+    #[clap(long, value_parser, conflicts_with_all = ["dry_run", "fail_fast"])]
+    pub watch: bool,
+
+    /// How often the input folder is checked for new files in watch mode, in seconds
+    #[clap(long, value_parser, default_value_t = 2)]
+    pub poll_interval: u64,
+
+    /// How long a file must be unchanged before it is processed in watch mode, in seconds
+    #[clap(long, value_parser, default_value_t = 5)]
+    pub settle_time: u64,
+
+    /// Move each file to the processed, failed or skipped folder in the input folder once it has been processed in watch mode
+    #[clap(long, value_parser, requires = "watch")]
+    pub move_processed: bool,
+
+    /// The format of the dry run, summary and verify output
+    #[clap(long, short, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    pub format: OutputFormat,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 }
 
-/// # Creates a docx and returns a checksum
-///
-/// This creates a new docx file with the name parsed from the metadata filename.
+/// # The subcommands of the anonymiser
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Check an anonymised package for sensitive values from the original package
+    Verify {
+        /// The original tar.gz file
+        #[clap(value_parser)]
+        original: String,
+
+        /// The anonymised tar.gz file
+        #[clap(value_parser)]
+        anonymised: String,
+    },
+}
+
+/// # The docx modes which can be selected from the command line
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DocxMode {
+    /// Replace the docx with a new one which only contains the judgment name
+    Regenerate,
+    /// Keep the structure of the original docx and replace its text with scrambled text of the same shape
+    Scrub,
+}
+
+/// # The xml modes which can be selected from the command line
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum XmlMode {
+    /// Don't copy the xml to the anonymised package
+    Delete,
+    /// Keep the structure of the xml and scramble the text, names and identifiers in it
+    Anonymise,
+}
+
+/// # The formats reports can be printed in
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// Json
+    Json,
+}
+
+/// # The replacement modes which can be selected from the command line
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReplacementMode {
+    /// Replace values with XXXXXXXXX
+    Mask,
+    /// Replace values with a keyed hash of the value
+    Pseudonymise,
+    /// Replace values with fake values of the same kind, such as a valid email address
+    Fake,
+}
+
+/// # The reference modes which can be selected from the command line
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ReferenceMode {
+    /// Replace the source system with TST, so TDR-2023-ABC becomes TST-2023-ABC
+    PrefixSwap,
+    /// Replace the identifier with a random one, so the original reference can't be worked out
+    Random,
+    /// Replace the identifier with a keyed hash of the reference, using the same key as the pseudonymise mode
+    KeyedHash,
+    /// Look up the anonymised reference in the file passed in --reference-csv
+    Csv,
+}
+
+impl Opt {
+    /// # Build the filter for the input files from the input arguments
+    pub fn input_filter(&self) -> Result<InputFilter, AnonymiserError> {
+        InputFilter::new(&self.include, &self.exclude, self.recursive)
+    }
+
+    /// # Build the anonymisation policy from the input arguments
+    pub fn policy(&self) -> Result<Policy, AnonymiserError> {
+        let rules: RedactionRules = match &self.rules {
+            Some(rules_path) => RedactionRules::load(Path::new(rules_path))?,
+            None => RedactionRules::default(),
+        };
+        let key = || match &self.key_file {
+            Some(key_file_path) => PseudonymisationKey::from_file(Path::new(key_file_path)),
+            None => PseudonymisationKey::from_env(),
+        };
+        let replacement: Replacement = match self.mode {
+            ReplacementMode::Mask => Replacement::Mask,
+            ReplacementMode::Pseudonymise => Replacement::Pseudonymise(key()?),
+            ReplacementMode::Fake => Replacement::Fake(FakeValueGenerator::new(self.seed)),
+        };
+        let hash_key: Option<PseudonymisationKey> = match rules.uses_hash() {
+            true => Some(key()?),
+            false => None,
+        };
+        let docx: DocxReplacement = match self.docx {
+            DocxMode::Regenerate => DocxReplacement::Regenerate,
+            DocxMode::Scrub => DocxReplacement::Scrub(TextScrambler::new(self.seed)),
+        };
+        let xml: XmlReplacement = match self.xml {
+            XmlMode::Delete => XmlReplacement::Delete,
+            XmlMode::Anonymise => XmlReplacement::Anonymise(TextScrambler::new(self.seed)),
+        };
+        let reference_mapping: ReferenceMapping = match self.reference_mode {
+            ReferenceMode::PrefixSwap => ReferenceMapping::PrefixSwap,
+            ReferenceMode::Random => ReferenceMapping::Random,
+            ReferenceMode::KeyedHash => ReferenceMapping::KeyedHash(key()?),
+            ReferenceMode::Csv => match &self.reference_csv {
+                Some(csv_path) => ReferenceMapping::load_csv(Path::new(csv_path))?,
+                None => {
+                    return Err(AnonymiserError::InvalidPolicy(String::from(
+                        "A reference CSV file is needed for the csv reference mode",
+                    )))
+                }
+            },
+        };
+        Ok(Policy {
+            rules,
+            replacement,
+            hash_key,
+            docx,
+            xml,
+            parser_log: self.parser_log,
+            pii: self.pii,
+            reference_mapping,
+            allow_list: AllowList::new(&self.allow_file, self.unexpected_files)?,
+            limits: ExtractionLimits {
+                max_total_size: self.max_total_size,
+                max_entries: self.max_entries,
+                max_entry_size: self.max_entry_size,
+            },
+        })
+    }
+}
+
+/// # The anonymisation policy applied to each package
+#[derive(Default)]
+pub struct Policy {
+    /// The redaction rules applied to the metadata json
+    pub rules: RedactionRules,
+    /// What values matched by `mask` rules are replaced with
+    pub replacement: Replacement,
+    /// The key used by `hash` rules. Values are masked instead if there is no key
+    pub hash_key: Option<PseudonymisationKey>,
+    /// How the docx is anonymised
+    pub docx: DocxReplacement,
+    /// What happens to the LegalDocML xml
+    pub xml: XmlReplacement,
+    /// What happens to the parser log
+    pub parser_log: ParserLogMode,
+    /// What happens to personal data found by the detector in the metadata json
+    pub pii: PiiAction,
+    /// How the batch reference of the anonymised package is chosen
+    pub reference_mapping: ReferenceMapping,
+    /// The files allowed in the package and what happens to any others
+    pub allow_list: AllowList,
+    /// The limits on the number and size of the entries in the package
+    pub limits: ExtractionLimits,
+}
+
+/// # Package processor
+/// This takes an output directory path and a path to a tar.gz file as input and anonymises them with the following steps:
 ///
-/// It then writes the judgment name to the docx file and saves it to the output directory.
-/// If there is no judgment name, it uses the filename
+/// * It finds the docx, xml, parser log and images from the `TRE.payload` block of the metadata json,
+///   and the attachments from `PARSER.attachments`.
+/// * It checks every entry of the tar.gz before extracting it, and fails if any has an unsafe path, is a link or device,
+///   or is over the policy's size and number limits.
+/// * It checks every other file in the package against the policy's allow list. By default, the package fails
+///   if there are any files which aren't allowed. They can be dropped or kept instead.
+/// * It replaces the batch reference in every metadata json value which contains it with the anonymised reference.
+///   If the name of a file in the payload contains it, the file is renamed to match.
+/// * It applies the policy's redaction rules to the metadata json. By default, these replace the values of Contact-Email and Contact-Name with XXXXXXX
+/// * It searches the rest of the metadata json for personal data, and warns, redacts it or fails depending on the policy.
+/// * It generates a new docx file which only contains the name of the judgment.
+///   If the policy scrubs the docx, it keeps the original docx and scrambles its text instead.
+/// * It updates the checksum field with the calculated checksum of the new docx file.
+/// * It replaces each image with a placeholder and regenerates each docx attachment in the same way as the docx,
+///   updating the checksums of the attachments. Anything else is removed and reported in the audit record.
+/// * It renames the folder, metadata file and tar.gz file from TDR-xxx to the reference chosen by the policy's reference mapping,
+///   which is TST-xxx by default.
+/// * It deletes the LegalDocML xml, or anonymises it if the policy keeps it.
+/// * It deletes the parser log, or scrubs its lines if the policy keeps it.
+/// * It creates a new tar.gz folder in the output directory. The entries are sorted and have no owner or local timestamps,
+///   so the same input and policy always give the same bytes.
+/// * It writes an audit record of the changes next to the new tar.gz file and returns it.
 ///
-/// Finally, it generates a sha256 checksum for the new file and returns it.
-fn create_docx_with_checksum(
-    extracted_output_path: &Path,
-    metadata_json_value: &mut Value,
-) -> Result<String, Error> {
-    let docx_file_name: &str = metadata_json_value["parameters"]["TRE"]["payload"]["filename"]
-        .as_str()
-        .ok_or("'filename' is missing from the metadata json")
-        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+/// The package is extracted and anonymised in a private scratch directory, which is always removed afterwards.
+/// The tar.gz and audit record are written to temporary files and renamed into the output directory once they are complete,
+/// so nothing is left in the output directory if any step fails, and packages can be processed at the same time
+/// with the same output directory.
+pub fn process_package(
+    dir_output: &PathBuf,
+    file: &Path,
+    policy: &Policy,
+) -> Result<ProcessedPackage, AnonymiserError> {
+    let names: PackageNames = package_names(file, &policy.reference_mapping)?;
+    let output_tar_gz_path: PathBuf = dir_output.join(&names.output_tar_gz_file_name);
+    let metadata_path: PathBuf = names
+        .input_batch_reference
+        .folder()
+        .join(names.input_batch_reference.metadata_file_name());
+
+    fs::create_dir_all(dir_output).with_path(dir_output)?;
+    let scratch: TempDir = scratch_dir()?;
+    let dir_scratch: PathBuf = scratch.path().to_path_buf();
+    let entries: PackageEntries = decompress_file(file, &dir_scratch, policy.limits)?;
+    let metadata_json_value: Value = parse_metadata_json(&dir_scratch.join(&metadata_path))?;
+
+    let transform: PackageTransform =
+        PackageTransform::new(names, policy, &entries, metadata_json_value, |paths| {
+            let mut contents: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+            for path in paths {
+                let extracted_path: PathBuf = dir_scratch.join(path);
+                if extracted_path.is_file() {
+                    let extracted: Vec<u8> =
+                        fs::read(&extracted_path).with_path(&extracted_path)?;
+                    contents.insert(path.clone(), extracted);
+                }
+            }
+            Ok(contents)
+        })?;
+    transform.check()?;
 
-    let judgment_name: &str = metadata_json_value["parameters"]["PARSER"]["name"]
-        .as_str()
-        .unwrap_or(docx_file_name);
-    let docx_path: PathBuf = extracted_output_path.join(PathBuf::from(docx_file_name));
+    let output_tar_gz: PendingFile = PendingFile::new(&output_tar_gz_path)?;
+    transform.write(
+        output_tar_gz.as_file(),
+        &output_tar_gz_path,
+        |input_path, write_entry| {
+            let extracted_path: PathBuf = dir_scratch.join(input_path);
+            let mut extracted: File = File::open(&extracted_path).with_path(&extracted_path)?;
+            let size: u64 = extracted.metadata().with_path(&extracted_path)?.len();
+            write_entry(size, &mut extracted)
+        },
+    )?;
+    output_tar_gz.publish()?;
 
-    let file: File = File::create(&docx_path)?;
-    Docx::new()
-        .add_paragraph(Paragraph::new().add_run(Run::new().add_text(judgment_name)))
-        .build()
-        .pack(file)?;
+    scratch.close().with_path(&dir_scratch)?;
+    transform.audit(file, &output_tar_gz_path)
+}
 
-    let docx_checksum: String = try_digest(&docx_path).unwrap();
-    Ok(docx_checksum)
+/// # The names derived from the input tar.gz file name
+pub(crate) struct PackageNames {
+    pub(crate) output_tar_gz_file_name: String,
+    pub(crate) input_batch_reference: BatchReference,
+    pub(crate) output_batch_reference: BatchReference,
 }
 
-/// # Helper function to delete a file if present
-fn if_present_delete(path: PathBuf) -> io::Result<()> {
-    if path.exists() {
-        remove_file(path)?
-    }
-    Ok(())
+/// # Get the batch references and output file name from the input tar.gz file name
+///
+/// The file name must be a batch reference followed by `.tar.gz`, such as `TRE-TDR-2023-ABC.tar.gz`.
+/// The output batch reference is chosen by the reference mapping.
+pub(crate) fn package_names(
+    file: &Path,
+    reference_mapping: &ReferenceMapping,
+) -> Result<PackageNames, AnonymiserError> {
+    let invalid_package_name = |reason: String| AnonymiserError::InvalidPackageName {
+        name: file.display().to_string(),
+        reason,
+    };
+    let tar_gz_file_name: &str =
+        file.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(invalid_package_name(String::from(
+                "Error getting the file name from the file",
+            )))?;
+    let package_file_name: PackageFileName =
+        PackageFileName::parse(tar_gz_file_name).map_err(invalid_package_name)?;
+    let output_batch_reference: BatchReference =
+        reference_mapping.map(&package_file_name.batch_reference)?;
+    Ok(PackageNames {
+        output_tar_gz_file_name: package_file_name.with_reference(&output_batch_reference),
+        output_batch_reference,
+        input_batch_reference: package_file_name.batch_reference,
+    })
 }
 
-/// # Helper function to check if a file does not start with `.`
-fn is_not_hidden(entry: &DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|file_name| !file_name.starts_with('.'))
-        .unwrap_or(false)
+/// # The files in the package which are not copied to the anonymised package
+pub(crate) fn files_to_delete<'a>(model: &'a PackageModel, policy: &Policy) -> Vec<&'a str> {
+    let mut files_to_delete: Vec<&str> = Vec::new();
+    if policy.xml == XmlReplacement::Delete {
+        files_to_delete.push(&model.xml);
+    }
+    if policy.parser_log == ParserLogMode::Drop {
+        files_to_delete.push(&model.log);
+    }
+    files_to_delete
 }
 
-/// # Helper function to check if the entry is a file
-fn is_file(entry: &DirEntry) -> bool {
-    !entry.path().is_dir()
+/// # Get the docx file name from the metadata json
+pub(crate) fn docx_file_name(metadata_json_value: &Value) -> Result<&str, AnonymiserError> {
+    let pointer: &str = "/parameters/TRE/payload/filename";
+    metadata_json_value
+        .pointer(pointer)
+        .and_then(|file_name| file_name.as_str())
+        .ok_or(AnonymiserError::missing_metadata_field(pointer))
 }
 
-/// # List files in input directory
+/// # Generates the replacement docx in memory
 ///
-/// This takes a directory path and returns a list of paths of all files on that level.
-/// It will not recursively search subdirectories.
-pub fn files_in_input_dir(directory_path: &PathBuf) -> Result<Vec<PathBuf>, Error> {
-    let path_list: Vec<PathBuf> = fs::read_dir(directory_path)
-        .unwrap()
-        .filter_map(|e| {
-            let entry: DirEntry = e.ok()?;
-            if is_file(&entry) && is_not_hidden(&entry) {
-                Some(entry.path())
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<PathBuf>>();
-    Ok(path_list)
+/// This writes the judgment name to a new docx. If there is no judgment name, it uses the filename.
+/// If the docx is scrubbed, the text of the original docx is scrambled instead.
+///
+/// Returns the docx file name from the metadata and the contents of the new docx.
+pub(crate) fn generate_docx(
+    metadata_json_value: &Value,
+    docx_replacement: &DocxReplacement,
+    original_docx: Option<&[u8]>,
+) -> Result<(String, Vec<u8>), AnonymiserError> {
+    let docx_file_name: &str = docx_file_name(metadata_json_value)?;
+    let judgment_name: &str = metadata_json_value["parameters"]["PARSER"]["name"]
+        .as_str()
+        .unwrap_or(docx_file_name);
+    let docx: Vec<u8> = replacement_docx(
+        docx_file_name,
+        judgment_name,
+        docx_replacement,
+        original_docx,
+    )?;
+    Ok((docx_file_name.to_string(), docx))
 }
 
-/// # Tars and Gzips the specified folder
+/// # Generates a replacement for a docx in the package
 ///
-/// This creates a tar file at `tar_path`, compresses everything in `path_to_compress` and names it with `folder_name`
-fn tar_folder(
-    tar_path: &PathBuf,
-    path_to_compress: &PathBuf,
-    folder_name: &String,
-) -> Result<(), Error> {
-    let tar_gz: File = File::create(tar_path)?;
-    let enc: GzEncoder<File> = GzEncoder::new(tar_gz, Compression::default());
-    let mut tar: Builder<GzEncoder<File>> = Builder::new(enc);
-    tar.append_dir_all(folder_name, path_to_compress)?;
-    Ok(())
+/// This writes the title to a new docx, or scrambles the text of the original docx if the docx is scrubbed.
+pub(crate) fn replacement_docx(
+    docx_file_name: &str,
+    title: &str,
+    docx_replacement: &DocxReplacement,
+    original_docx: Option<&[u8]>,
+) -> Result<Vec<u8>, AnonymiserError> {
+    if let DocxReplacement::Scrub(scrambler) = docx_replacement {
+        let original_docx: &[u8] = original_docx.ok_or(AnonymiserError::MissingEntry {
+            path: PathBuf::from(docx_file_name),
+        })?;
+        return scrub_docx(docx_file_name, original_docx, scrambler);
+    }
+
+    let mut docx: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    Docx::new()
+        .add_paragraph(Paragraph::new().add_run(Run::new().add_text(title)))
+        .build()
+        .pack(&mut docx)
+        .map_err(|e| AnonymiserError::DocxGeneration {
+            path: PathBuf::from(docx_file_name),
+            source: Box::new(e),
+        })?;
+    Ok(docx.into_inner())
 }
 
-/// # Anonymise the contact fields and update the checksum
-fn update_json_file(
-    metadata_file_name: &PathBuf,
-    checksum: String,
+/// # Apply the redaction rules and personal data action, then set the checksums of the new docx and attachments
+///
+/// The personal data detector runs after the rules, so it only finds values the rules didn't change.
+/// `attachment_checksums` are the json pointers of the attachment checksum fields and their new values.
+///
+/// Returns the pointers of the values which were changed and any personal data found.
+pub(crate) fn anonymise_metadata_json(
     json_value: &mut Value,
-) -> Result<(), Error> {
-    let tdr: &mut Value = &mut json_value["parameters"]["TDR"];
-    tdr["Contact-Email"] = json!("XXXXXXXXX");
-    tdr["Contact-Name"] = json!("XXXXXXXXX");
-    tdr["Document-Checksum-sha256"] = json!(checksum);
-    fs::write(metadata_file_name, json_value.to_string())
+    checksum: String,
+    attachment_checksums: &[(String, String)],
+    policy: &Policy,
+) -> (Vec<String>, Vec<PiiFinding>) {
+    let mut changed_pointers: Vec<String> =
+        policy
+            .rules
+            .apply(json_value, &policy.replacement, policy.hash_key.as_ref());
+    let pii_findings: Vec<PiiFinding> = apply_pii_action(
+        json_value,
+        &changed_pointers,
+        policy.pii,
+        &policy.replacement,
+    );
+    if policy.pii == PiiAction::Redact {
+        changed_pointers.extend(pii_findings.iter().map(|finding| finding.pointer.clone()));
+    }
+    json_value["parameters"]["TDR"]["Document-Checksum-sha256"] = json!(checksum);
+    changed_pointers.push(CHECKSUM_POINTER.to_string());
+    for (pointer, attachment_checksum) in attachment_checksums {
+        if let Some(value) = json_value.pointer_mut(pointer) {
+            *value = json!(attachment_checksum);
+            changed_pointers.push(pointer.clone());
+        }
+    }
+    changed_pointers.sort();
+    changed_pointers.dedup();
+    (changed_pointers, pii_findings)
 }
 
 /// # Untar and unzip the input tar.gz file
-fn decompress_file(path_to_tar: &PathBuf, output_path: &PathBuf) -> Result<(), Error> {
-    let tar_gz: File = File::open(path_to_tar)?;
-    let tar: GzDecoder<File> = GzDecoder::new(tar_gz);
-    let mut archive: Archive<GzDecoder<File>> = Archive::new(tar);
-    archive.unpack(output_path)?;
-    Ok(())
+///
+/// Each entry is checked against the limits as it is extracted. The output path is the private scratch folder,
+/// which is removed if an entry fails the checks, so an unsafe archive is never left partly extracted.
+/// Returns the paths of the entries.
+fn decompress_file(
+    path_to_tar: &Path,
+    output_path: &Path,
+    limits: ExtractionLimits,
+) -> Result<PackageEntries, AnonymiserError> {
+    unpack_archive(path_to_tar, output_path, limits)
 }
 
 /// # Read the metadata.json file and parse it into a serde `Value`
-fn parse_metadata_json(metadata_file_path: &PathBuf) -> Result<Value, Error> {
-    let mut metadata_file: File = File::open(metadata_file_path)?;
+fn parse_metadata_json(metadata_file_path: &PathBuf) -> Result<Value, AnonymiserError> {
+    let mut metadata_file: File = File::open(metadata_file_path).with_path(metadata_file_path)?;
     let mut metadata_json_as_string: String = String::new();
-    metadata_file.read_to_string(&mut metadata_json_as_string)?;
-    Ok(serde_json::from_str(&metadata_json_as_string)?)
+    metadata_file
+        .read_to_string(&mut metadata_json_as_string)
+        .with_path(metadata_file_path)?;
+    serde_json::from_str(&metadata_json_as_string).map_err(|source| AnonymiserError::JsonParse {
+        path: metadata_file_path.clone(),
+        source,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::create_docx_with_checksum;
     use assert_fs::TempDir;
-    use std::fs::{read_dir, read_to_string};
-    use testlib::create_package;
+    use flate2::read::GzDecoder;
+    use sha256::digest;
+    use std::fs::read_dir;
+    use tar::Archive;
+    use testlib::{
+        create_package, create_package_with_files, json_missing_filename, test_docx, valid_json,
+    };
 
     #[test]
-    fn test_create_docx_with_checksum() {
-        let output_path = TempDir::new().unwrap();
-        let mut json_value = json!({
+    fn test_generate_docx() {
+        let json_value = json!({
             "parameters": {
                 "PARSER": {
                     "name" : "test-name"
@@ -244,53 +628,209 @@ mod tests {
                 }
             }
         });
-        let docx_checksum =
-            create_docx_with_checksum(&output_path.to_owned(), &mut json_value).unwrap();
-        let output_files = read_dir(&output_path.to_owned()).unwrap();
-        let filename = &output_files.last().unwrap().unwrap().file_name();
+        let (file_name, docx) =
+            generate_docx(&json_value, &DocxReplacement::Regenerate, None).unwrap();
 
+        assert_eq!(file_name, "test-file-name.docx");
         assert_eq!(
-            filename.to_str().unwrap().to_string(),
-            "test-file-name.docx"
-        );
-        assert_eq!(
-            docx_checksum,
+            digest(&docx),
             "a951e0d7f11d9d2fa8c9508ee4b25944bb5810364089fc33221b1ec038eefd37"
         )
     }
 
     #[test]
-    fn test_create_docx_with_checksum_missing_metadata_filename() {
-        let output_path = TempDir::new().unwrap();
-        let mut json_value = json!({
+    fn test_generate_docx_missing_metadata_filename() {
+        let json_value = json!({
             "parameters": {
                 "PARSER": {
                     "name" : "test-name"
                 }
             }
         });
-        let err = create_docx_with_checksum(&output_path.to_owned(), &mut json_value).unwrap_err();
+        let err = generate_docx(&json_value, &DocxReplacement::Regenerate, None).unwrap_err();
         assert_eq!(
             err.to_string(),
-            "'filename' is missing from the metadata json"
-        )
+            "'filename' is missing from the metadata json at /parameters/TRE/payload/filename"
+        );
+        assert!(matches!(err, AnonymiserError::MissingMetadataField { .. }))
+    }
+
+    #[test]
+    fn test_generate_docx_scrubs_the_original_docx() {
+        let json_value =
+            json!({"parameters": {"TRE": {"payload": {"filename": "test-file-name.docx"}}}});
+        let docx_replacement = DocxReplacement::Scrub(TextScrambler::new(1));
+
+        let (_, scrubbed_docx) =
+            generate_docx(&json_value, &docx_replacement, Some(&test_docx())).unwrap();
+
+        assert_ne!(scrubbed_docx, test_docx());
+    }
+
+    #[test]
+    fn test_generate_docx_error_if_scrubbing_a_missing_docx() {
+        let json_value =
+            json!({"parameters": {"TRE": {"payload": {"filename": "test-file-name.docx"}}}});
+        let docx_replacement = DocxReplacement::Scrub(TextScrambler::new(1));
+        let err = generate_docx(&json_value, &docx_replacement, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'test-file-name.docx' is missing from the package"
+        );
     }
 
     #[test]
     fn test_parse_metadata_json_parses_data_into_value() {
         let output_dir = TempDir::new().unwrap();
         let metadata_path = &output_dir.join(PathBuf::from("metadata.json"));
-        fs::write(&metadata_path, r#"{"a": "b"}"#.as_bytes()).unwrap();
-        let json = parse_metadata_json(&metadata_path).unwrap();
+        fs::write(metadata_path, r#"{"a": "b"}"#.as_bytes()).unwrap();
+        let json = parse_metadata_json(metadata_path).unwrap();
         assert_eq!(&json["a"], "b")
     }
 
+    #[test]
+    fn test_parse_metadata_json_invalid_json() {
+        let output_dir = TempDir::new().unwrap();
+        let metadata_path = &output_dir.join(PathBuf::from("metadata.json"));
+        fs::write(metadata_path, "{".as_bytes()).unwrap();
+        let err = parse_metadata_json(metadata_path).unwrap_err();
+        assert!(matches!(err, AnonymiserError::JsonParse { .. }));
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_decompress_file_invalid_archive() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let tar_path = input_dir.join("test.tar.gz");
+        fs::write(&tar_path, "test").unwrap();
+        let err = decompress_file(&tar_path, &output_dir, ExtractionLimits::default()).unwrap_err();
+        assert!(matches!(err, AnonymiserError::BadArchive { .. }));
+    }
+
+    #[test]
+    fn test_process_package_invalid_package_name() {
+        let output_dir = TempDir::new().unwrap();
+        let err = process_package(
+            &output_dir.to_owned(),
+            &PathBuf::from("/"),
+            &Policy::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, AnonymiserError::InvalidPackageName { .. }));
+    }
+
+    #[test]
+    fn test_process_package_leaves_nothing_in_the_output_if_it_fails() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let tar_path = create_package(&input_dir, json_missing_filename(), None);
+        let err =
+            process_package(&output_dir.to_owned(), &tar_path, &Policy::default()).unwrap_err();
+        assert!(matches!(err, AnonymiserError::MissingMetadataField { .. }));
+        assert_eq!(read_dir(output_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_process_package_only_writes_the_tar_gz_and_audit_record() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let tar_path = create_package(&input_dir, valid_json(), None);
+        process_package(&output_dir.to_owned(), &tar_path, &Policy::default()).unwrap();
+        let mut output_file_names: Vec<String> = read_dir(output_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        output_file_names.sort();
+        assert_eq!(
+            output_file_names,
+            vec!["TST-2023.audit.json", "TST-2023.tar.gz"]
+        );
+    }
+
+    #[test]
+    fn test_process_package_can_run_concurrently_with_the_same_output_dir() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let tar_path = create_package(&input_dir, valid_json(), None);
+        let output_paths: Vec<PathBuf> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        process_package(&output_dir.to_path_buf(), &tar_path, &Policy::default())
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap().unwrap().output_path)
+                .collect()
+        });
+        assert!(output_paths
+            .iter()
+            .all(|output_path| output_path == &output_dir.join("TST-2023.tar.gz")));
+        assert_eq!(read_dir(output_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_process_package_output_is_reproducible() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package_with_files(
+            &input_dir,
+            valid_json(),
+            None,
+            &[
+                ("TDR-2023.xml", b"<xml/>"),
+                ("images/b.png", b"b"),
+                ("images/a.png", b"a"),
+            ],
+        );
+        let policy = Policy {
+            allow_list: AllowList::new(&[String::from("images/*.png")], UnexpectedFileAction::Keep)
+                .unwrap(),
+            ..Policy::default()
+        };
+        let first_output_dir = TempDir::new().unwrap();
+        let second_output_dir = TempDir::new().unwrap();
+        let first = process_package(&first_output_dir.to_owned(), &tar_path, &policy).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let second = process_package(&second_output_dir.to_owned(), &tar_path, &policy).unwrap();
+        assert_eq!(
+            fs::read(&first.output_path).unwrap(),
+            fs::read(&second.output_path).unwrap()
+        );
+
+        let tar_gz: File = File::open(&first.output_path).unwrap();
+        let mut archive: Archive<GzDecoder<File>> = Archive::new(GzDecoder::new(tar_gz));
+        let mut entry_paths: Vec<String> = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let header = entry.header();
+            assert_eq!(header.mtime().unwrap(), ENTRY_MTIME);
+            assert_eq!((header.uid().unwrap(), header.gid().unwrap()), (0, 0));
+            assert_eq!(header.username().unwrap(), Some(""));
+            assert_eq!(header.groupname().unwrap(), Some(""));
+            entry_paths.push(entry.path().unwrap().to_string_lossy().to_string());
+        }
+        assert_eq!(
+            entry_paths,
+            vec![
+                "TST-2023/",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/images/",
+                "TST-2023/images/a.png",
+                "TST-2023/images/b.png",
+                "TST-2023/test.docx"
+            ]
+        );
+    }
+
     #[test]
     fn test_decompress_file() {
         let input_dir = TempDir::new().unwrap();
         let output_dir = TempDir::new().unwrap();
         let tar_path = create_package(&input_dir, "{}", None);
-        decompress_file(&tar_path, &output_dir.to_owned()).unwrap();
+        decompress_file(&tar_path, &output_dir, ExtractionLimits::default()).unwrap();
         assert!(output_dir
             .join(PathBuf::from("TDR-2023/test.docx"))
             .exists());
@@ -300,9 +840,23 @@ mod tests {
     }
 
     #[test]
-    fn test_update_json_file() {
+    fn test_decompress_file_error_if_the_archive_is_over_the_limits() {
+        let input_dir = TempDir::new().unwrap();
         let output_dir = TempDir::new().unwrap();
-        let metadata_path = &output_dir.join(PathBuf::from("metadata.json"));
+        let tar_path = create_package(&input_dir, "{}", None);
+        let limits = ExtractionLimits {
+            max_entries: 2,
+            ..ExtractionLimits::default()
+        };
+        let err = decompress_file(&tar_path, &output_dir, limits).unwrap_err();
+        assert!(matches!(
+            err,
+            AnonymiserError::TooManyEntries { limit: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn test_anonymise_metadata_json() {
         let mut json_value = json!({
             "parameters": {
                 "TDR": {
@@ -314,20 +868,152 @@ mod tests {
                 }
             }
         });
-        update_json_file(&metadata_path, "abcde".to_owned(), &mut json_value).unwrap();
-        let metadata_json_string = read_to_string(&metadata_path).unwrap();
+        anonymise_metadata_json(&mut json_value, "abcde".to_owned(), &[], &Policy::default());
+        let metadata_json_string = json_value.to_string();
         let expected_json = r#"{"parameters":{"TDR":{"Contact-Email":"XXXXXXXXX","Contact-Email2":"test-email-2","Contact-Name":"XXXXXXXXX","Document-Checksum-sha256":"abcde","TDR-Contact-Name":"tdr-contact-name"}}}"#;
         assert_eq!(metadata_json_string, expected_json);
     }
 
     #[test]
-    fn test_tar_folder_creates_a_new_tar() {
-        let tar_dir = TempDir::new().unwrap();
-        let output_dir = TempDir::new().unwrap();
-        let folder_name: String = String::from("test_name");
-        let tar_file_path = tar_dir.join("test.tar.gz");
-        tar_folder(&tar_file_path, &output_dir.to_owned(), &folder_name).unwrap();
+    fn test_anonymise_metadata_json_with_custom_rules() {
+        let mut json_value = json!({
+            "parameters": {
+                "TDR": {
+                    "Contact-Email" : "test-email",
+                    "Contact-Name" : "test-name",
+                    "Document-Checksum-sha256": "test-checksum"
+                }
+            }
+        });
+        let rules = RedactionRules(vec![
+            RedactionRule {
+                pointer: String::from("/parameters/TDR/Contact-Email"),
+                action: RedactionAction::Drop,
+            },
+            RedactionRule {
+                pointer: String::from("/parameters/TDR/Contact-Name"),
+                action: RedactionAction::Replace(json!("Test Name")),
+            },
+        ]);
+        anonymise_metadata_json(
+            &mut json_value,
+            "abcde".to_owned(),
+            &[],
+            &Policy {
+                rules,
+                ..Policy::default()
+            },
+        );
+        let metadata_json_string = json_value.to_string();
+        let expected_json = r#"{"parameters":{"TDR":{"Contact-Name":"Test Name","Document-Checksum-sha256":"abcde"}}}"#;
+        assert_eq!(metadata_json_string, expected_json);
+    }
 
-        assert!(tar_file_path.exists());
+    #[test]
+    fn test_anonymise_metadata_json_pseudonymises_values() {
+        let mut json_value = json!({
+            "parameters": {
+                "TDR": {
+                    "Contact-Email" : "test-email",
+                    "Contact-Name" : "test-name"
+                }
+            }
+        });
+        let key = PseudonymisationKey::new(b"test-key").unwrap();
+        anonymise_metadata_json(
+            &mut json_value,
+            "abcde".to_owned(),
+            &[],
+            &Policy {
+                replacement: Replacement::Pseudonymise(key.clone()),
+                ..Policy::default()
+            },
+        );
+        let tdr = &json_value["parameters"]["TDR"];
+        assert_eq!(tdr["Contact-Email"], key.pseudonym("test-email"));
+        assert_eq!(tdr["Contact-Name"], key.pseudonym("test-name"));
+    }
+
+    #[test]
+    fn test_anonymise_metadata_json_writes_fake_values() {
+        let mut json_value = json!({
+            "parameters": {
+                "TDR": {
+                    "Contact-Email" : "test@nationalarchives.gov.uk",
+                    "Contact-Name" : "test-name"
+                }
+            }
+        });
+        anonymise_metadata_json(
+            &mut json_value,
+            "abcde".to_owned(),
+            &[],
+            &Policy {
+                replacement: Replacement::Fake(FakeValueGenerator::new(1)),
+                pii: PiiAction::Fail,
+                ..Policy::default()
+            },
+        );
+        let tdr = &json_value["parameters"]["TDR"];
+        assert_eq!(tdr["Contact-Email"], "elliot.johnson11@example.com");
+        assert_eq!(tdr["Contact-Name"], "Ashley Wilson");
+    }
+
+    #[test]
+    fn test_anonymise_metadata_json_redacts_personal_data_in_other_fields() {
+        let mut json_value = json!({
+            "parameters": {
+                "TDR": {
+                    "Contact-Email" : "test@nationalarchives.gov.uk",
+                    "Judgment-Update-Details": "Sent by test@example.com from SW1A 1AA"
+                }
+            }
+        });
+        let (changed_pointers, pii_findings) = anonymise_metadata_json(
+            &mut json_value,
+            "abcde".to_owned(),
+            &[],
+            &Policy {
+                pii: PiiAction::Redact,
+                ..Policy::default()
+            },
+        );
+        let tdr = &json_value["parameters"]["TDR"];
+        assert_eq!(tdr["Contact-Email"], "XXXXXXXXX");
+        assert_eq!(
+            tdr["Judgment-Update-Details"],
+            "Sent by XXXXXXXXX from XXXXXXXXX"
+        );
+        assert_eq!(
+            changed_pointers,
+            vec![
+                "/parameters/TDR/Contact-Email",
+                "/parameters/TDR/Contact-Name",
+                "/parameters/TDR/Document-Checksum-sha256",
+                "/parameters/TDR/Judgment-Update-Details"
+            ]
+        );
+        assert_eq!(pii_findings.len(), 2);
+    }
+
+    #[test]
+    fn test_process_package_error_if_the_policy_fails_on_personal_data() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let json = valid_json().replace(
+            r#""name": "test""#,
+            r#""name": "test", "notes": "call 07700 900123""#,
+        );
+        let tar_path = create_package(&input_dir, &json, None);
+        let policy = Policy {
+            pii: PiiAction::Fail,
+            ..Policy::default()
+        };
+        let err = process_package(&output_dir.to_path_buf(), &tar_path, &policy).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Possible personal data found in the metadata json: UK phone number at /parameters/PARSER/notes"
+        );
+        assert_eq!(read_dir(output_dir.path()).unwrap().count(), 0);
     }
 }
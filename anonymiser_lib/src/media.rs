@@ -0,0 +1,295 @@
+//! ## Images and attachments
+//!
+//! Each image listed in `TRE.payload.images` is replaced with a plain grey placeholder of the same format.
+//! The placeholder has the same dimensions, scaled down if either side is longer than `MAX_PLACEHOLDER_SIDE`.
+//!
+//! Each docx attachment listed in `PARSER.attachments` is regenerated or scrubbed in the same way as the main docx,
+//! and any sha256 checksum fields next to its name in the metadata json are updated.
+//!
+//! Anything which can't be anonymised, such as an image in an unknown format or an attachment which isn't a docx,
+//! is removed from the package and reported in the audit record. So is any file which is listed but missing.
+use crate::docx::DocxReplacement;
+use crate::error::AnonymiserError;
+use crate::payload::{PackageModel, ATTACHMENTS_POINTER};
+use crate::reference::BatchReference;
+use crate::replacement_docx;
+use image::io::Reader;
+use image::{ImageFormat, ImageOutputFormat, Rgb, RgbImage};
+use serde_json::Value;
+use sha256::digest;
+use std::io::Cursor;
+use std::path::Path;
+
+/// The longest side of a placeholder image, in pixels
+pub const MAX_PLACEHOLDER_SIDE: u32 = 2000;
+
+/// The colour of a placeholder image
+const PLACEHOLDER_COLOUR: Rgb<u8> = Rgb([200, 200, 200]);
+
+/// # The changes to the images and attachments of a package
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct MediaChanges {
+    /// The new contents of each replaced file, by its output file name
+    pub(crate) replaced: Vec<(String, Vec<u8>)>,
+    /// The input file names of the files which are removed from the package
+    pub(crate) removed: Vec<String>,
+    /// The json pointers of the attachment checksums and their new values
+    pub(crate) checksums: Vec<(String, String)>,
+    /// Why each file was removed, or that it is missing
+    pub(crate) unexpected: Vec<String>,
+}
+
+/// # Anonymise the images and attachments of a package
+///
+/// `read` returns the contents of a file from its input file name, or `None` if it isn't in the package.
+/// Nothing is written here, so the processors can write the new contents wherever the files end up.
+pub(crate) fn anonymise_media(
+    input_batch_reference: &BatchReference,
+    input_model: &PackageModel,
+    output_model: &PackageModel,
+    metadata_json_value: &Value,
+    docx_replacement: &DocxReplacement,
+    mut read: impl FnMut(&str) -> Result<Option<Vec<u8>>, AnonymiserError>,
+) -> Result<MediaChanges, AnonymiserError> {
+    let mut changes: MediaChanges = MediaChanges::default();
+    let images = input_model.images.iter().zip(&output_model.images);
+    for (input_file_name, output_file_name) in images {
+        let path: String = input_batch_reference.package_path(input_file_name);
+        match read(input_file_name)? {
+            None => changes.missing(path),
+            Some(image) => match placeholder_image(&image) {
+                Ok(placeholder) => changes
+                    .replaced
+                    .push((output_file_name.clone(), placeholder)),
+                Err(reason) => changes.remove(input_file_name, path, &reason),
+            },
+        }
+    }
+    let attachments = input_model
+        .attachments
+        .iter()
+        .zip(&output_model.attachments);
+    for (index, (input_file_name, output_file_name)) in attachments.enumerate() {
+        let path: String = input_batch_reference.package_path(input_file_name);
+        let attachment: Vec<u8> = match read(input_file_name)? {
+            None => {
+                changes.missing(path);
+                continue;
+            }
+            Some(attachment) => attachment,
+        };
+        if !is_docx(input_file_name) {
+            changes.remove(
+                input_file_name,
+                path,
+                "only docx attachments can be anonymised",
+            );
+            continue;
+        }
+        match replacement_docx(
+            output_file_name,
+            output_file_name,
+            docx_replacement,
+            Some(&attachment),
+        ) {
+            Ok(docx) => {
+                let checksum: String = digest(&docx);
+                changes.checksums.extend(
+                    checksum_pointers(metadata_json_value, index)
+                        .into_iter()
+                        .map(|pointer| (pointer, checksum.clone())),
+                );
+                changes.replaced.push((output_file_name.clone(), docx));
+            }
+            Err(error) => changes.remove(input_file_name, path, &error.to_string()),
+        }
+    }
+    changes.unexpected.iter().for_each(|unexpected| {
+        log::warn!("{unexpected}");
+    });
+    Ok(changes)
+}
+
+impl MediaChanges {
+    fn missing(&mut self, path: String) {
+        self.unexpected.push(format!(
+            "{path}: listed in the metadata json but is missing from the package"
+        ));
+    }
+
+    fn remove(&mut self, file_name: &str, path: String, reason: &str) {
+        self.removed.push(file_name.to_string());
+        self.unexpected
+            .push(format!("{path}: {reason}, so it was removed"));
+    }
+}
+
+/// # Generate a placeholder for an image
+///
+/// The placeholder has the same format as the image, and the same dimensions up to `MAX_PLACEHOLDER_SIDE`.
+/// Returns why if the image can't be read or its format can't be written.
+pub(crate) fn placeholder_image(image: &[u8]) -> Result<Vec<u8>, String> {
+    let reader = Reader::new(Cursor::new(image))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let format: ImageFormat = reader
+        .format()
+        .ok_or(String::from("the image format is not recognised"))?;
+    let (width, height) = reader.into_dimensions().map_err(|e| e.to_string())?;
+    let (width, height) = placeholder_dimensions(width, height);
+    let placeholder: RgbImage = RgbImage::from_pixel(width, height, PLACEHOLDER_COLOUR);
+    let mut placeholder_bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    placeholder
+        .write_to(&mut placeholder_bytes, ImageOutputFormat::from(format))
+        .map_err(|e| format!("a {format:?} placeholder can't be written: {e}"))?;
+    Ok(placeholder_bytes.into_inner())
+}
+
+/// # Scale the dimensions down to fit in `MAX_PLACEHOLDER_SIDE`, keeping the aspect ratio
+fn placeholder_dimensions(width: u32, height: u32) -> (u32, u32) {
+    let longest_side: u32 = width.max(height);
+    if longest_side <= MAX_PLACEHOLDER_SIDE {
+        return (width.max(1), height.max(1));
+    }
+    let scale = |side: u32| -> u32 {
+        ((side as u64 * MAX_PLACEHOLDER_SIDE as u64) / longest_side as u64).max(1) as u32
+    };
+    (scale(width), scale(height))
+}
+
+fn is_docx(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.eq_ignore_ascii_case("docx"))
+        .unwrap_or(false)
+}
+
+/// # The json pointers of the checksum fields of an attachment
+///
+/// These are the fields of the attachment object whose names contain `sha256`.
+fn checksum_pointers(metadata_json_value: &Value, index: usize) -> Vec<String> {
+    let attachment_pointer: String = format!("{ATTACHMENTS_POINTER}/{index}");
+    match metadata_json_value.pointer(&attachment_pointer) {
+        Some(Value::Object(attachment)) => attachment
+            .keys()
+            .filter(|key| key.to_lowercase().contains("sha256"))
+            .map(|key| format!("{attachment_pointer}/{key}"))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use testlib::{test_docx, test_image};
+
+    fn dimensions_and_format(image: &[u8]) -> ((u32, u32), ImageFormat) {
+        let reader = Reader::new(Cursor::new(image))
+            .with_guessed_format()
+            .unwrap();
+        let format: ImageFormat = reader.format().unwrap();
+        (reader.into_dimensions().unwrap(), format)
+    }
+
+    #[test]
+    fn test_placeholder_image_keeps_the_format_and_dimensions() {
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Bmp,
+            ImageFormat::Tiff,
+        ] {
+            let image: Vec<u8> = test_image(30, 20, format);
+            let placeholder: Vec<u8> = placeholder_image(&image).unwrap();
+            assert_ne!(placeholder, image);
+            assert_eq!(dimensions_and_format(&placeholder), ((30, 20), format));
+        }
+    }
+
+    #[test]
+    fn test_placeholder_image_scales_down_large_images() {
+        assert_eq!(placeholder_dimensions(4000, 1000), (2000, 500));
+        assert_eq!(placeholder_dimensions(10, 100000), (1, 2000));
+        assert_eq!(placeholder_dimensions(2000, 30), (2000, 30));
+    }
+
+    #[test]
+    fn test_placeholder_image_error_if_the_format_is_not_recognised() {
+        assert_eq!(
+            placeholder_image(b"png").unwrap_err(),
+            "the image format is not recognised"
+        );
+    }
+
+    #[test]
+    fn test_anonymise_media() {
+        let metadata = json!({"parameters": {
+            "TRE": {"payload": {"filename": "test.docx", "images": ["TDR-2023-1.png", "2.png", "3.png"]}},
+            "PARSER": {"attachments": [
+                {"name": "TDR-2023-annex.docx", "Checksum-SHA256": "abc", "Checksum-md5": "def"},
+                "annex.pdf",
+                "missing.docx"
+            ]}
+        }});
+        let input_batch_reference = BatchReference::parse("TDR-2023").unwrap();
+        let output_batch_reference = BatchReference::parse("TST-2023").unwrap();
+        let input_model = PackageModel::from_metadata(&metadata, &input_batch_reference).unwrap();
+        let mut output_metadata = metadata.clone();
+        crate::reference::rewrite_references(
+            &mut output_metadata,
+            &input_batch_reference,
+            &output_batch_reference,
+        );
+        let output_model =
+            PackageModel::from_metadata(&output_metadata, &output_batch_reference).unwrap();
+        let changes = anonymise_media(
+            &input_batch_reference,
+            &input_model,
+            &output_model,
+            &output_metadata,
+            &DocxReplacement::Regenerate,
+            |file_name| {
+                Ok(match file_name {
+                    "TDR-2023-1.png" => Some(test_image(3, 2, ImageFormat::Png)),
+                    "2.png" => Some(b"png".to_vec()),
+                    "TDR-2023-annex.docx" => Some(test_docx()),
+                    "annex.pdf" => Some(b"pdf".to_vec()),
+                    _ => None,
+                })
+            },
+        )
+        .unwrap();
+
+        let replaced_names: Vec<&str> = changes
+            .replaced
+            .iter()
+            .map(|(file_name, _)| file_name.as_str())
+            .collect();
+        assert_eq!(
+            replaced_names,
+            vec!["TST-2023-1.png", "TST-2023-annex.docx"]
+        );
+        let annex_checksum: String = digest(&changes.replaced[1].1);
+        assert_eq!(
+            changes.checksums,
+            vec![(
+                String::from("/parameters/PARSER/attachments/0/Checksum-SHA256"),
+                annex_checksum
+            )]
+        );
+        assert_eq!(changes.removed, vec!["2.png", "annex.pdf"]);
+        assert_eq!(
+            changes.unexpected,
+            vec![
+                "TDR-2023/2.png: the image format is not recognised, so it was removed",
+                "TDR-2023/3.png: listed in the metadata json but is missing from the package",
+                "TDR-2023/annex.pdf: only docx attachments can be anonymised, so it was removed",
+                "TDR-2023/missing.docx: listed in the metadata json but is missing from the package"
+            ]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! ## Parser log scrubbing
+//!
+//! The ingest pipeline reports parser warnings from `parser.log`, so the log can be kept in the anonymised package.
+//! Each line is scrubbed the same way as the rest of the package.
+use crate::fake::FakeValueKind;
+use crate::reference::{replace_reference, replace_whole_words, BatchReference};
+use crate::replacement::Replacement;
+use crate::rules::{value_as_string, MASK};
+use crate::CHECKSUM_POINTER;
+use serde::Serialize;
+use serde_json::Value;
+
+/// The name of the parser log in the package folder
+pub(crate) const PARSER_LOG_FILE_NAME: &str = "parser.log";
+
+/// # What happens to the parser log in the package
+#[derive(clap::ValueEnum, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParserLogMode {
+    /// Don't copy the parser log to the anonymised package
+    #[default]
+    Drop,
+    /// Keep the parser log with the batch references, emails, names and file paths scrubbed
+    Scrub,
+    /// Keep the parser log unchanged
+    Keep,
+}
+
+/// # The changes made to each line of the parser log
+pub(crate) struct LogRedactions<'a> {
+    input_batch_reference: &'a BatchReference,
+    output_batch_reference: &'a BatchReference,
+    /// The original and new values of the metadata fields which were changed by the redaction rules
+    redacted_values: Vec<(String, String)>,
+    replacement: &'a Replacement,
+}
+
+impl<'a> LogRedactions<'a> {
+    /// # Find the values to scrub from the changes made to the metadata json
+    pub(crate) fn new(
+        input_batch_reference: &'a BatchReference,
+        output_batch_reference: &'a BatchReference,
+        original_metadata_json_value: &Value,
+        metadata_json_value: &Value,
+        changed_pointers: &[String],
+        replacement: &'a Replacement,
+    ) -> LogRedactions<'a> {
+        let mut redacted_values: Vec<(String, String)> = changed_pointers
+            .iter()
+            .filter(|pointer| pointer.as_str() != CHECKSUM_POINTER)
+            .filter_map(|pointer| {
+                let original: String = value_as_string(
+                    original_metadata_json_value
+                        .pointer(pointer)
+                        .filter(|value| !value.is_null())?,
+                );
+                let new_value: String = metadata_json_value
+                    .pointer(pointer)
+                    .map_or(MASK.to_string(), value_as_string);
+                (!original.is_empty()).then_some((original, new_value))
+            })
+            .collect();
+        // Replace the longest values first so a value inside another one doesn't leave part of it behind
+        redacted_values.sort_by_key(|(original, _)| std::cmp::Reverse(original.len()));
+        LogRedactions {
+            input_batch_reference,
+            output_batch_reference,
+            redacted_values,
+            replacement,
+        }
+    }
+
+    /// # Scrub each line of the parser log
+    pub(crate) fn scrub(&self, log: &str) -> String {
+        log.split_inclusive('\n')
+            .map(|line| self.scrub_line(line))
+            .collect()
+    }
+
+    /// # Scrub a line of the parser log
+    ///
+    /// * The values changed in the metadata are replaced with their new values, unless they are part of a longer word.
+    /// * The batch reference is changed from TDR-xxx to the anonymised reference, unless it is part of a longer word.
+    /// * Any other emails and file paths are replaced in the same way as masked metadata values,
+    ///   with fake emails for emails and fake paths for paths.
+    fn scrub_line(&self, line: &str) -> String {
+        let mut line: String = line.to_string();
+        for (original, new_value) in &self.redacted_values {
+            if let Some(replaced) = replace_whole_words(&line, original, new_value) {
+                line = replaced;
+            }
+        }
+        if let Some(replaced) = replace_reference(
+            &line,
+            self.input_batch_reference,
+            self.output_batch_reference,
+        ) {
+            line = replaced;
+        }
+        line.split(' ')
+            .map(|word| {
+                let token: &str =
+                    word.trim_matches(|c: char| c.is_whitespace() || "\"'`()[]<>,;:".contains(c));
+                let token: &str = token.trim_end_matches('.');
+                let kind: Option<FakeValueKind> = if is_email(token) {
+                    Some(FakeValueKind::Email)
+                } else if is_file_path(token) {
+                    Some(FakeValueKind::Path)
+                } else {
+                    None
+                };
+                match kind {
+                    Some(kind) if !self.is_redacted_value(token) => {
+                        word.replacen(token, &self.replacement.replace_text(kind, token), 1)
+                    }
+                    _ => word.to_string(),
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// # Helper function to check if a value is one of the new metadata values, so it isn't replaced twice
+    fn is_redacted_value(&self, token: &str) -> bool {
+        self.redacted_values
+            .iter()
+            .any(|(_, new_value)| new_value == token)
+    }
+}
+
+/// # Helper function to check if a word looks like an email address
+fn is_email(token: &str) -> bool {
+    match token.split_once('@') {
+        Some((user, domain)) => {
+            !user.is_empty() && domain.contains('.') && !domain.starts_with('.')
+        }
+        None => false,
+    }
+}
+
+/// # Helper function to check if a word looks like an absolute unix or windows file path
+fn is_file_path(token: &str) -> bool {
+    let is_unix_path: bool = token.starts_with('/') && token[1..].contains('/');
+    let is_windows_path: bool = token.len() > 3
+        && token.as_bytes()[0].is_ascii_alphabetic()
+        && token.get(1..3) == Some(":\\");
+    is_unix_path || is_windows_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeValueGenerator;
+    use crate::replacement::PseudonymisationKey;
+    use crate::rules::RedactionRules;
+    use serde_json::json;
+
+    fn scrub(log: &str, replacement: &Replacement) -> String {
+        let original = json!({"parameters": {"TDR": {
+            "Contact-Email": "test@nationalarchives.gov.uk",
+            "Contact-Name": "Test Person"
+        }}});
+        let mut metadata = original.clone();
+        let changed_pointers = RedactionRules::default().apply(&mut metadata, replacement, None);
+        LogRedactions::new(
+            &BatchReference::parse("TDR-2023").unwrap(),
+            &BatchReference::parse("TST-2023").unwrap(),
+            &original,
+            &metadata,
+            &changed_pointers,
+            replacement,
+        )
+        .scrub(log)
+    }
+
+    #[test]
+    fn test_scrub_replaces_metadata_values_references_emails_and_paths() {
+        let log = "WARNING Test Person (test@nationalarchives.gov.uk) uploaded TDR-2023\n\
+                   ERROR cannot read '/home/test/TDR-2023/test.docx': contact other@example.org.\n";
+        assert_eq!(
+            scrub(log, &Replacement::Mask),
+            "WARNING XXXXXXXXX (XXXXXXXXX) uploaded TST-2023\n\
+             ERROR cannot read 'XXXXXXXXX': contact XXXXXXXXX.\n"
+        );
+    }
+
+    #[test]
+    fn test_scrub_uses_the_replacement_mode() {
+        let key = PseudonymisationKey::new(b"test-key").unwrap();
+        let replacement = Replacement::Pseudonymise(key.clone());
+        let scrubbed = scrub("Test Person, C:\\Users\\test\\judgment.docx", &replacement);
+        assert_eq!(
+            scrubbed,
+            format!(
+                "{}, {}",
+                key.pseudonym("Test Person"),
+                key.pseudonym("C:\\Users\\test\\judgment.docx")
+            )
+        );
+    }
+
+    #[test]
+    fn test_scrub_uses_the_same_fake_values_as_the_metadata() {
+        let generator = FakeValueGenerator::new(1);
+        let scrubbed = scrub(
+            "from test@nationalarchives.gov.uk",
+            &Replacement::Fake(generator),
+        );
+        assert_eq!(
+            scrubbed,
+            format!(
+                "from {}",
+                generator.fake_for("test@nationalarchives.gov.uk")
+            )
+        );
+    }
+
+    #[test]
+    fn test_scrub_only_replaces_whole_values_and_references() {
+        let log = "Test Personnel uploaded TDR-20234 and TDR-2023-1 after TDR-2023\n";
+        assert_eq!(
+            scrub(log, &Replacement::Mask),
+            "Test Personnel uploaded TDR-20234 and TST-2023-1 after TST-2023\n"
+        );
+    }
+
+    #[test]
+    fn test_scrub_replaces_paths_with_fake_paths() {
+        let generator = FakeValueGenerator::new(1);
+        let scrubbed = scrub(
+            "cannot read /home/test/judgment.docx",
+            &Replacement::Fake(generator),
+        );
+        assert_eq!(
+            scrubbed,
+            format!(
+                "cannot read {}",
+                generator.generate(FakeValueKind::Path, "/home/test/judgment.docx")
+            )
+        );
+        assert!(!scrubbed.contains('@'));
+    }
+
+    #[test]
+    fn test_scrub_keeps_other_lines_unchanged() {
+        let log = "INFO parsed 3 paragraphs in 0.2s\nDEBUG a/b 2023-01-01 12:00:00\n";
+        assert_eq!(scrub(log, &Replacement::Mask), log);
+    }
+}
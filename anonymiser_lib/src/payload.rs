@@ -0,0 +1,279 @@
+//! ## Package contents
+//!
+//! The `TRE.payload` block of the metadata json names the files in the package, such as
+//! ```json
+//! {"filename": "judgment.docx", "xml": "TDR-2023-ABC.xml", "log": "parser.log", "images": ["image1.png"]}
+//! ```
+//! The anonymiser finds each file from here instead of guessing its name, so a change to the layout of the package
+//! changes which files are anonymised rather than leaving them behind.
+//! Attachments are listed by the parser in `PARSER.attachments`.
+//! If the payload names the metadata json in `metadata`, it must be the one found from the batch reference.
+use crate::error::AnonymiserError;
+use crate::parser_log::PARSER_LOG_FILE_NAME;
+use crate::reference::BatchReference;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Component, Path};
+
+/// The json pointer of the payload block in the metadata json
+const PAYLOAD_POINTER: &str = "/parameters/TRE/payload";
+
+/// The json pointer of the list of attachments in the metadata json
+pub(crate) const ATTACHMENTS_POINTER: &str = "/parameters/PARSER/attachments";
+
+/// # The files in a package, relative to the package folder
+///
+/// The metadata json itself isn't included. It has to be read before the payload, so it is found from the batch reference.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PackageModel {
+    /// The docx, from `payload.filename`
+    pub document: String,
+    /// The LegalDocML xml, from `payload.xml`. This is `{reference}.xml` if it isn't set
+    pub xml: String,
+    /// The parser log, from `payload.log`. This is `parser.log` if it isn't set
+    pub log: String,
+    /// The images extracted from the docx, from `payload.images`
+    pub images: Vec<String>,
+    /// The attachments, from `PARSER.attachments`. Each one is a file name or an object with the file name in `name`
+    pub attachments: Vec<String>,
+}
+
+impl PackageModel {
+    /// # Build the model from the payload block of the metadata json
+    ///
+    /// The document is required. Each file name must be a relative path inside the package folder.
+    /// The metadata json named in the payload, if there is one, must be the one found from the batch reference.
+    pub fn from_metadata(
+        metadata_json_value: &Value,
+        batch_reference: &BatchReference,
+    ) -> Result<PackageModel, AnonymiserError> {
+        let document_pointer: String = format!("{PAYLOAD_POINTER}/filename");
+        let document: String = match metadata_json_value.pointer(&document_pointer) {
+            Some(Value::String(document)) => document.clone(),
+            _ => return Err(AnonymiserError::missing_metadata_field(&document_pointer)),
+        };
+        let optional_file_name =
+            |field: &str, default: String| -> Result<String, AnonymiserError> {
+                let pointer: String = format!("{PAYLOAD_POINTER}/{field}");
+                match metadata_json_value.pointer(&pointer) {
+                    None | Some(Value::Null) => Ok(default),
+                    Some(Value::String(file_name)) => Ok(file_name.clone()),
+                    Some(_) => Err(invalid_field(&pointer, "Expected a file name")),
+                }
+            };
+        let metadata_file_name: String = batch_reference.metadata_file_name();
+        let metadata: String = optional_file_name("metadata", metadata_file_name.clone())?;
+        if metadata != metadata_file_name {
+            return Err(invalid_field(
+                &format!("{PAYLOAD_POINTER}/metadata"),
+                &format!("Expected '{metadata_file_name}', the metadata json of the package"),
+            ));
+        }
+        let xml: String = optional_file_name("xml", batch_reference.xml_file_name())?;
+        let log: String = optional_file_name("log", PARSER_LOG_FILE_NAME.to_string())?;
+        let images: Vec<String> = file_name_list(
+            metadata_json_value,
+            &format!("{PAYLOAD_POINTER}/images"),
+            |image| image.as_str(),
+        )?;
+        let attachments: Vec<String> = file_name_list(
+            metadata_json_value,
+            ATTACHMENTS_POINTER,
+            |attachment| match attachment {
+                Value::Object(attachment) => attachment.get("name").and_then(Value::as_str),
+                _ => attachment.as_str(),
+            },
+        )?;
+        let model = PackageModel {
+            document,
+            xml,
+            log,
+            images,
+            attachments,
+        };
+        match model
+            .file_names()
+            .into_iter()
+            .find(|file_name| !is_package_relative(file_name))
+        {
+            Some(file_name) => Err(invalid_field(
+                PAYLOAD_POINTER,
+                &format!("'{file_name}' is not a relative path inside the package folder"),
+            )),
+            None => Ok(model),
+        }
+    }
+
+    /// # The names of every file in the model
+    pub fn file_names(&self) -> Vec<&str> {
+        let mut file_names: Vec<&str> = vec![&self.document, &self.xml, &self.log];
+        file_names.extend(self.images.iter().map(String::as_str));
+        file_names.extend(self.attachments.iter().map(String::as_str));
+        file_names
+    }
+
+    /// # The files whose names are different in the output model
+    ///
+    /// Returns pairs of the input and output file names. The files are matched by their role in the package.
+    pub(crate) fn renamed_files<'a>(&'a self, output: &'a PackageModel) -> Vec<(&'a str, &'a str)> {
+        self.file_names()
+            .into_iter()
+            .zip(output.file_names())
+            .filter(|(input_file_name, output_file_name)| input_file_name != output_file_name)
+            .collect()
+    }
+}
+
+/// # Read a list of file names from the metadata json
+///
+/// The list is empty if the field isn't set.
+fn file_name_list(
+    metadata_json_value: &Value,
+    pointer: &str,
+    file_name: impl Fn(&Value) -> Option<&str>,
+) -> Result<Vec<String>, AnonymiserError> {
+    match metadata_json_value.pointer(pointer) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Array(items)) => items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                file_name(item).map(String::from).ok_or(invalid_field(
+                    &format!("{pointer}/{index}"),
+                    "Expected a file name",
+                ))
+            })
+            .collect(),
+        Some(_) => Err(invalid_field(pointer, "Expected a list of file names")),
+    }
+}
+
+fn invalid_field(pointer: &str, reason: &str) -> AnonymiserError {
+    AnonymiserError::InvalidMetadataField {
+        pointer: pointer.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// # Whether a file name only has normal path components, so it can't point outside the package folder
+fn is_package_relative(file_name: &str) -> bool {
+    !file_name.is_empty()
+        && Path::new(file_name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reference() -> BatchReference {
+        BatchReference::parse("TDR-2023-ABC").unwrap()
+    }
+
+    #[test]
+    fn test_model_from_the_payload() {
+        let metadata = json!({"parameters": {
+            "TRE": {"payload": {
+                "filename": "judgment.docx",
+                "xml": "judgment.xml",
+                "log": "logs/parser.log",
+                "images": ["images/image1.png", "image2.jpg"]
+            }},
+            "PARSER": {"attachments": ["annex1.docx", {"name": "annex2.docx", "sha256": "abc"}]}
+        }});
+        assert_eq!(
+            PackageModel::from_metadata(&metadata, &reference()).unwrap(),
+            PackageModel {
+                document: String::from("judgment.docx"),
+                xml: String::from("judgment.xml"),
+                log: String::from("logs/parser.log"),
+                images: vec![
+                    String::from("images/image1.png"),
+                    String::from("image2.jpg")
+                ],
+                attachments: vec![String::from("annex1.docx"), String::from("annex2.docx")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_model_accepts_the_metadata_of_the_package() {
+        let metadata = json!({"parameters": {"TRE": {"payload": {
+            "filename": "judgment.docx",
+            "metadata": "TRE-TDR-2023-ABC-metadata.json"
+        }}}});
+        assert!(PackageModel::from_metadata(&metadata, &reference()).is_ok());
+    }
+
+    #[test]
+    fn test_model_defaults() {
+        let metadata = json!({"parameters": {"TRE": {"payload": {"filename": "judgment.docx"}}}});
+        let model = PackageModel::from_metadata(&metadata, &reference()).unwrap();
+        assert_eq!(model.xml, "TDR-2023-ABC.xml");
+        assert_eq!(model.log, "parser.log");
+        assert!(model.images.is_empty());
+        assert!(model.attachments.is_empty());
+    }
+
+    #[test]
+    fn test_model_errors() {
+        let error = |payload: Value| {
+            let metadata = json!({"parameters": {"TRE": {"payload": payload}}});
+            PackageModel::from_metadata(&metadata, &reference())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(json!({})),
+            "'filename' is missing from the metadata json at /parameters/TRE/payload/filename"
+        );
+        assert_eq!(
+            error(json!({"filename": "a.docx", "xml": 1})),
+            "'/parameters/TRE/payload/xml' in the metadata json is not valid: Expected a file name"
+        );
+        assert_eq!(
+            error(json!({"filename": "a.docx", "images": ["a.png", 2]})),
+            "'/parameters/TRE/payload/images/1' in the metadata json is not valid: Expected a file name"
+        );
+        assert_eq!(
+            error(json!({"filename": "a.docx", "images": "a.png"})),
+            "'/parameters/TRE/payload/images' in the metadata json is not valid: Expected a list of file names"
+        );
+        assert_eq!(
+            error(json!({"filename": "../a.docx"})),
+            "'/parameters/TRE/payload' in the metadata json is not valid: '../a.docx' is not a relative path inside the package folder"
+        );
+        assert!(error(json!({"filename": "/tmp/a.docx"})).contains("'/tmp/a.docx'"));
+        assert_eq!(
+            error(json!({"filename": "a.docx", "metadata": "TRE-TDR-2023-XYZ-metadata.json"})),
+            "'/parameters/TRE/payload/metadata' in the metadata json is not valid: Expected 'TRE-TDR-2023-ABC-metadata.json', the metadata json of the package"
+        );
+    }
+
+    #[test]
+    fn test_renamed_files() {
+        let input = PackageModel {
+            document: String::from("TDR-2023.docx"),
+            xml: String::from("TDR-2023.xml"),
+            log: String::from("parser.log"),
+            images: vec![String::from("image1.png")],
+            attachments: vec![String::from("TDR-2023-annex.docx")],
+        };
+        let output = PackageModel {
+            document: String::from("TST-2023.docx"),
+            xml: String::from("TST-2023.xml"),
+            attachments: vec![String::from("TST-2023-annex.docx")],
+            ..input.clone()
+        };
+        assert_eq!(
+            input.renamed_files(&output),
+            vec![
+                ("TDR-2023.docx", "TST-2023.docx"),
+                ("TDR-2023.xml", "TST-2023.xml"),
+                ("TDR-2023-annex.docx", "TST-2023-annex.docx")
+            ]
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! ## Personal data detection
+//!
+//! The redaction rules only change the fields they name, but free text fields such as `Judgment-Update-Details`
+//! can contain anything. This searches every value in the metadata json for text which looks like personal data.
+use crate::error::AnonymiserError;
+use crate::replacement::Replacement;
+use crate::rules::{json_values, value_as_string};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+/// # What happens when personal data is found in the metadata json
+#[derive(clap::ValueEnum, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiAction {
+    /// Log a warning and list the findings in the audit record
+    #[default]
+    Warn,
+    /// Replace the matching text in the same way as masked values
+    Redact,
+    /// Don't anonymise the package
+    Fail,
+}
+
+/// # The kinds of personal data which can be found
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiKind {
+    Email,
+    PhoneNumber,
+    NationalInsuranceNumber,
+    Postcode,
+}
+
+impl PiiKind {
+    const ALL: [PiiKind; 4] = [
+        PiiKind::Email,
+        PiiKind::NationalInsuranceNumber,
+        PiiKind::PhoneNumber,
+        PiiKind::Postcode,
+    ];
+
+    /// # The pattern which matches this kind of value
+    ///
+    /// The National Insurance number and postcode patterns are case sensitive, so ordinary words don't match them.
+    fn pattern(&self) -> &'static Regex {
+        static PATTERNS: OnceLock<[Regex; 4]> = OnceLock::new();
+        let patterns: &[Regex; 4] = PATTERNS.get_or_init(|| {
+            [
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+                r"(?:\+44\s?|\b0)\d(?:[\s-]?\d){8,9}\b",
+                r"\b[A-CEGHJ-PR-TW-Z][A-CEGHJ-NPR-TW-Z]\s?\d{2}\s?\d{2}\s?\d{2}\s?[A-D]\b",
+                r"\b(?:[A-Z]{1,2}\d[A-Z\d]?|GIR)\s?\d[A-Z]{2}\b",
+            ]
+            .map(|pattern| Regex::new(pattern).expect("The personal data patterns are valid"))
+        });
+        match self {
+            PiiKind::Email => &patterns[0],
+            PiiKind::PhoneNumber => &patterns[1],
+            PiiKind::NationalInsuranceNumber => &patterns[2],
+            PiiKind::Postcode => &patterns[3],
+        }
+    }
+}
+
+impl Display for PiiKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PiiKind::Email => "email address",
+            PiiKind::PhoneNumber => "UK phone number",
+            PiiKind::NationalInsuranceNumber => "National Insurance number",
+            PiiKind::Postcode => "postcode",
+        })
+    }
+}
+
+/// # Text which looks like personal data in the metadata json
+///
+/// The matching text is never included.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PiiFinding {
+    pub pointer: String,
+    pub kind: PiiKind,
+}
+
+impl Display for PiiFinding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}", self.kind, self.pointer)
+    }
+}
+
+/// # Find the values in the metadata json which look like personal data
+///
+/// Every string and number is searched for email addresses, UK phone numbers, National Insurance numbers and postcodes.
+/// Each kind is only listed once for a value.
+pub fn detect_pii(json_value: &Value) -> Vec<PiiFinding> {
+    json_values(json_value)
+        .iter()
+        .flat_map(|(pointer, value)| {
+            PiiKind::ALL
+                .iter()
+                .filter(|kind| kind.pattern().is_match(value))
+                .map(|kind| PiiFinding {
+                    pointer: pointer.clone(),
+                    kind: *kind,
+                })
+        })
+        .collect()
+}
+
+/// # Find the personal data in the values which the redaction rules didn't change
+///
+/// The values at `changed_pointers` have already been replaced by the rules, so they are skipped.
+/// Otherwise a fake email written by a rule would be found again.
+pub(crate) fn find_unredacted_pii(
+    json_value: &Value,
+    changed_pointers: &[String],
+) -> Vec<PiiFinding> {
+    detect_pii(json_value)
+        .into_iter()
+        .filter(|finding| !changed_pointers.contains(&finding.pointer))
+        .collect()
+}
+
+/// # Apply the policy's action to the personal data the redaction rules didn't change
+///
+/// Returns the findings. If the action is `Redact`, every match is replaced using the replacement,
+/// so `Mask` turns `call 07700 900123` into `call XXXXXXXXX`. Otherwise the values are left alone,
+/// and `check_pii_findings` warns or fails once the package is ready to be written.
+pub(crate) fn apply_pii_action(
+    json_value: &mut Value,
+    changed_pointers: &[String],
+    action: PiiAction,
+    replacement: &Replacement,
+) -> Vec<PiiFinding> {
+    let findings: Vec<PiiFinding> = find_unredacted_pii(json_value, changed_pointers);
+    if action == PiiAction::Redact {
+        for finding in &findings {
+            if let Some(value) = json_value.pointer_mut(&finding.pointer) {
+                let redacted: String = finding
+                    .kind
+                    .pattern()
+                    .replace_all(&value_as_string(value), |captures: &regex::Captures| {
+                        let original: Value = json!(&captures[0]);
+                        value_as_string(&replacement.replace(&finding.pointer, Some(&original)))
+                    })
+                    .to_string();
+                *value = json!(redacted);
+            }
+        }
+    }
+    findings
+}
+
+/// # Log the personal data which wasn't redacted, then fail if the policy fails packages with any
+///
+/// This is kept apart from `apply_pii_action`, so a dry run can list the findings without failing.
+pub(crate) fn check_pii_findings(
+    findings: &[PiiFinding],
+    action: PiiAction,
+) -> Result<(), AnonymiserError> {
+    match action {
+        PiiAction::Warn => findings
+            .iter()
+            .for_each(|finding| log::warn!("Possible {finding} in the metadata json")),
+        PiiAction::Fail if !findings.is_empty() => {
+            return Err(AnonymiserError::PiiDetected {
+                findings: findings.to_vec(),
+            })
+        }
+        PiiAction::Redact | PiiAction::Fail => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeValueGenerator;
+    use crate::rules::MASK;
+
+    fn kinds(value: &str) -> Vec<PiiKind> {
+        detect_pii(&json!({ "a": value }))
+            .into_iter()
+            .map(|finding| finding.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_detect_pii_finds_each_kind() {
+        assert_eq!(
+            kinds("email test.person@example.co.uk"),
+            vec![PiiKind::Email]
+        );
+        assert_eq!(kinds("call 07700 900123"), vec![PiiKind::PhoneNumber]);
+        assert_eq!(kinds("call +44 20 7946 0958"), vec![PiiKind::PhoneNumber]);
+        assert_eq!(kinds("call 020-7946-0958."), vec![PiiKind::PhoneNumber]);
+        assert_eq!(
+            kinds("NI number AB 12 34 56 C"),
+            vec![PiiKind::NationalInsuranceNumber]
+        );
+        assert_eq!(kinds("lives at SW1A 1AA"), vec![PiiKind::Postcode]);
+        assert_eq!(kinds("TW9 4DU, London"), vec![PiiKind::Postcode]);
+    }
+
+    #[test]
+    fn test_detect_pii_ignores_other_values() {
+        for value in [
+            "Smith v Jones",
+            "TDR-2023-ABC",
+            "2023-01-01T12:00:00Z",
+            "3c7b9ef49d36659762c34c63bae05b4cf07d6406c2736720385ed0c6f015840a",
+            "0b8d3a5e-2f1c-4d7a-9e6b-1a2b3c4d5e6f",
+            "[2023] EWCA Civ 123",
+            "judgment.docx",
+        ] {
+            assert_eq!(kinds(value), vec![], "{value}");
+        }
+    }
+
+    #[test]
+    fn test_detect_pii_walks_the_whole_json() {
+        let metadata = json!({"parameters": {"TDR": {
+            "Judgment-Update-Details": "Sent to test@example.com from SW1A 1AA",
+            "Notes": ["nothing", {"phone": "07700900123"}],
+            "Count": 3
+        }}});
+        assert_eq!(
+            detect_pii(&metadata),
+            vec![
+                PiiFinding {
+                    pointer: String::from("/parameters/TDR/Judgment-Update-Details"),
+                    kind: PiiKind::Email
+                },
+                PiiFinding {
+                    pointer: String::from("/parameters/TDR/Judgment-Update-Details"),
+                    kind: PiiKind::Postcode
+                },
+                PiiFinding {
+                    pointer: String::from("/parameters/TDR/Notes/1/phone"),
+                    kind: PiiKind::PhoneNumber
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_pii_action_redacts_the_matching_text() {
+        let mut metadata = json!({"details": "Sent to test@example.com, call 07700 900123"});
+        let findings = apply_pii_action(&mut metadata, &[], PiiAction::Redact, &Replacement::Mask);
+        assert_eq!(findings.len(), 2);
+        assert_eq!(
+            metadata,
+            json!({"details": format!("Sent to {MASK}, call {MASK}")})
+        );
+    }
+
+    #[test]
+    fn test_apply_pii_action_uses_the_replacement() {
+        let generator = FakeValueGenerator::new(1);
+        let mut metadata = json!({"details": "Sent to test@example.com"});
+        apply_pii_action(
+            &mut metadata,
+            &[],
+            PiiAction::Redact,
+            &Replacement::Fake(generator),
+        );
+        assert_eq!(
+            metadata,
+            json!({"details": format!("Sent to {}", generator.fake_for("test@example.com"))})
+        );
+    }
+
+    #[test]
+    fn test_apply_pii_action_warn_keeps_the_values() {
+        let mut metadata = json!({"details": "test@example.com"});
+        let findings = apply_pii_action(&mut metadata, &[], PiiAction::Warn, &Replacement::Mask);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(metadata, json!({"details": "test@example.com"}));
+    }
+
+    #[test]
+    fn test_apply_pii_action_skips_values_changed_by_the_rules() {
+        let mut metadata = json!({"email": "fake@example.com", "details": "test@example.com"});
+        let findings = apply_pii_action(
+            &mut metadata,
+            &[String::from("/email")],
+            PiiAction::Fail,
+            &Replacement::Mask,
+        );
+        let err = check_pii_findings(&findings, PiiAction::Fail).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Possible personal data found in the metadata json: email address at /details"
+        );
+    }
+
+    #[test]
+    fn test_check_pii_findings_fail() {
+        let mut metadata = json!({"details": "test@example.com"});
+        let findings = apply_pii_action(&mut metadata, &[], PiiAction::Fail, &Replacement::Mask);
+        assert_eq!(metadata, json!({"details": "test@example.com"}));
+        let err = check_pii_findings(&findings, PiiAction::Fail).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Possible personal data found in the metadata json: email address at /details"
+        );
+        assert!(check_pii_findings(&findings, PiiAction::Warn).is_ok());
+        assert!(check_pii_findings(&[], PiiAction::Fail).is_ok());
+    }
+}
@@ -0,0 +1,396 @@
+//! ## Dry run plans
+//!
+//! A plan describes what `process_package` would do to a package without writing anything.
+use crate::allow_list::{FileDecision, FileReport};
+use crate::archive::{read_entries, read_package_index, PackageIndex};
+use crate::audit::PackageChanges;
+use crate::error::AnonymiserError;
+use crate::pii::PiiFinding;
+use crate::transform::{path_string, PackageTransform};
+use crate::{package_names, PackageNames, Policy};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// # A file which would be moved to a new path
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+/// # The changes that processing a package would make
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PackagePlan {
+    pub input_file: String,
+    pub output_file: String,
+    pub input_batch_reference: String,
+    pub output_batch_reference: String,
+    /// The entries which would be moved to a new path
+    pub renamed: Vec<Rename>,
+    /// The entries which would not be copied to the output package
+    pub deleted: Vec<String>,
+    /// The entries which would be replaced with new content
+    pub regenerated: Vec<String>,
+    /// The json pointers of the metadata fields which would change
+    pub metadata_changes: Vec<String>,
+    /// The json pointers of the metadata values where the batch reference would be replaced
+    pub reference_rewrites: Vec<String>,
+    /// The metadata values which look like personal data, without the values
+    pub pii_findings: Vec<PiiFinding>,
+    /// Images and attachments which couldn't be anonymised or are missing from the package
+    pub unexpected: Vec<String>,
+    /// What would happen to each file in the input package
+    pub files: Vec<FileReport>,
+}
+
+impl Display for PackagePlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Package {}", self.input_file)?;
+        writeln!(f, "  Output file: {}", self.output_file)?;
+        writeln!(
+            f,
+            "  Batch reference: {} -> {}",
+            self.input_batch_reference, self.output_batch_reference
+        )?;
+        for rename in &self.renamed {
+            writeln!(f, "  Rename: {} -> {}", rename.from, rename.to)?;
+        }
+        for deleted in &self.deleted {
+            writeln!(f, "  Delete: {deleted}")?;
+        }
+        for regenerated in &self.regenerated {
+            writeln!(f, "  Regenerate: {regenerated}")?;
+        }
+        for pointer in &self.metadata_changes {
+            writeln!(f, "  Change metadata: {pointer}")?;
+        }
+        for pointer in &self.reference_rewrites {
+            writeln!(f, "  Rewrite reference: {pointer}")?;
+        }
+        for finding in &self.pii_findings {
+            writeln!(f, "  Possible personal data: {finding}")?;
+        }
+        for unexpected in &self.unexpected {
+            writeln!(f, "  Unexpected: {unexpected}")?;
+        }
+        for file in &self.files {
+            if file.decision != FileDecision::Expected {
+                writeln!(f, "  File: {file}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// # Plan the changes to a package
+///
+/// This reads the tar.gz file and returns the changes `process_package` would make with the same policy,
+/// using the same transform as the processors.
+/// The images and attachments are read too, to find out which of them can be anonymised.
+/// Files and personal data which would fail the package are listed rather than returned as an error.
+/// Nothing is written to disk.
+pub fn plan_package(file: &Path, policy: &Policy) -> Result<PackagePlan, AnonymiserError> {
+    let names: PackageNames = package_names(file, &policy.reference_mapping)?;
+    let metadata_path: PathBuf = names
+        .input_batch_reference
+        .folder()
+        .join(names.input_batch_reference.metadata_file_name());
+    let PackageIndex {
+        entries,
+        metadata_json_value,
+    } = read_package_index(file, &metadata_path, policy.limits)?;
+    let transform: PackageTransform =
+        PackageTransform::new(names, policy, &entries, metadata_json_value, |paths| {
+            read_entries(file, paths)
+        })?;
+
+    let mut renamed: Vec<Rename> = Vec::new();
+    let mut deleted: Vec<String> = Vec::new();
+    for (entry_path, output_path) in &transform.entries {
+        match output_path {
+            None => deleted.push(path_string(entry_path)),
+            Some(output_path) if output_path != entry_path => renamed.push(Rename {
+                from: path_string(entry_path),
+                to: path_string(output_path),
+            }),
+            Some(_) => (),
+        }
+    }
+    let PackageChanges {
+        metadata_changes,
+        regenerated,
+        reference_rewrites,
+        pii_findings,
+        unexpected,
+        files,
+        ..
+    } = transform.changes;
+
+    Ok(PackagePlan {
+        input_file: path_string(Path::new(file.file_name().unwrap_or_default())),
+        output_file: transform.names.output_tar_gz_file_name,
+        input_batch_reference: transform.names.input_batch_reference.to_string(),
+        output_batch_reference: transform.names.output_batch_reference.to_string(),
+        renamed,
+        deleted,
+        regenerated,
+        metadata_changes,
+        reference_rewrites,
+        pii_findings,
+        unexpected,
+        files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParserLogMode, PiiAction, PiiKind, TextScrambler, XmlReplacement};
+    use assert_fs::TempDir;
+    use image::ImageFormat;
+    use serde_json::json;
+    use testlib::{create_package, create_package_with_files, test_docx, test_image, valid_json};
+
+    #[test]
+    fn test_plan_package_lists_the_changes() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package(&input_dir, valid_json(), None);
+        let mut plan = plan_package(&tar_path, &Policy::default()).unwrap();
+        plan.renamed.sort_by(|a, b| a.from.cmp(&b.from));
+
+        assert_eq!(plan.input_file, "TDR-2023.tar.gz");
+        assert_eq!(plan.output_file, "TST-2023.tar.gz");
+        assert_eq!(plan.input_batch_reference, "TDR-2023");
+        assert_eq!(plan.output_batch_reference, "TST-2023");
+        assert_eq!(
+            plan.renamed,
+            vec![
+                Rename {
+                    from: String::from("TDR-2023/"),
+                    to: String::from("TST-2023/")
+                },
+                Rename {
+                    from: String::from("TDR-2023/TRE-TDR-2023-metadata.json"),
+                    to: String::from("TST-2023/TRE-TST-2023-metadata.json")
+                },
+                Rename {
+                    from: String::from("TDR-2023/test.docx"),
+                    to: String::from("TST-2023/test.docx")
+                }
+            ]
+        );
+        assert!(plan.deleted.is_empty());
+        assert_eq!(plan.regenerated, vec!["TST-2023/test.docx"]);
+        assert_eq!(
+            plan.metadata_changes,
+            vec![
+                "/parameters/TDR/Contact-Email",
+                "/parameters/TDR/Contact-Name",
+                "/parameters/TDR/Document-Checksum-sha256"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_package_renames_the_xml_if_it_is_anonymised() {
+        let input_dir = TempDir::new().unwrap();
+        let files: [(&str, &[u8]); 2] = [("TDR-2023.xml", b"<a/>"), ("parser.log", b"log")];
+        let tar_path = create_package_with_files(&input_dir, valid_json(), None, &files);
+        let policy = Policy {
+            xml: XmlReplacement::Anonymise(TextScrambler::new(1)),
+            ..Policy::default()
+        };
+        let plan = plan_package(&tar_path, &policy).unwrap();
+
+        assert!(plan.renamed.contains(&Rename {
+            from: String::from("TDR-2023/TDR-2023.xml"),
+            to: String::from("TST-2023/TST-2023.xml")
+        }));
+        assert_eq!(plan.deleted, vec!["TDR-2023/parser.log"]);
+        assert_eq!(
+            plan.regenerated,
+            vec!["TST-2023/test.docx", "TST-2023/TST-2023.xml"]
+        );
+    }
+
+    #[test]
+    fn test_plan_package_lists_the_reference_rewrites() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json().replace(
+            r#""filename": "test.docx""#,
+            r#""filename": "TDR-2023.docx", "reference": "TDR-2023""#,
+        );
+        let files: [(&str, &[u8]); 1] = [("TDR-2023.docx", b"")];
+        let tar_path = create_package_with_files(&input_dir, &json, None, &files);
+        let plan = plan_package(&tar_path, &Policy::default()).unwrap();
+
+        assert_eq!(
+            plan.reference_rewrites,
+            vec![
+                "/parameters/TRE/payload/filename",
+                "/parameters/TRE/payload/reference"
+            ]
+        );
+        assert!(plan.renamed.contains(&Rename {
+            from: String::from("TDR-2023/TDR-2023.docx"),
+            to: String::from("TST-2023/TST-2023.docx")
+        }));
+        assert_eq!(plan.regenerated, vec!["TST-2023/TST-2023.docx"]);
+    }
+
+    #[test]
+    fn test_plan_package_keeps_the_parser_log_if_it_is_scrubbed() {
+        let input_dir = TempDir::new().unwrap();
+        let files: [(&str, &[u8]); 1] = [("parser.log", b"log")];
+        let tar_path = create_package_with_files(&input_dir, valid_json(), None, &files);
+        let policy = Policy {
+            parser_log: ParserLogMode::Scrub,
+            ..Policy::default()
+        };
+        let plan = plan_package(&tar_path, &policy).unwrap();
+
+        assert!(plan.deleted.is_empty());
+        assert_eq!(
+            plan.regenerated,
+            vec!["TST-2023/test.docx", "TST-2023/parser.log"]
+        );
+    }
+
+    #[test]
+    fn test_plan_package_lists_the_images_and_attachments() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json()
+            .replace(
+                r#""filename": "test.docx""#,
+                r#""filename": "test.docx", "images": ["image1.png"]"#,
+            )
+            .replace(
+                r#""name": "test""#,
+                r#""name": "test", "attachments": [{"name": "annex.docx", "sha256": "abc"}, "annex.pdf"]"#,
+            );
+        let image: Vec<u8> = test_image(2, 2, ImageFormat::Png);
+        let annex: Vec<u8> = test_docx();
+        let files: [(&str, &[u8]); 3] = [
+            ("image1.png", &image),
+            ("annex.docx", &annex),
+            ("annex.pdf", b"pdf"),
+        ];
+        let tar_path = create_package_with_files(&input_dir, &json, None, &files);
+        let plan = plan_package(&tar_path, &Policy::default()).unwrap();
+
+        assert_eq!(
+            plan.regenerated,
+            vec![
+                "TST-2023/test.docx",
+                "TST-2023/image1.png",
+                "TST-2023/annex.docx"
+            ]
+        );
+        assert_eq!(plan.deleted, vec!["TDR-2023/annex.pdf"]);
+        assert!(plan
+            .metadata_changes
+            .contains(&String::from("/parameters/PARSER/attachments/0/sha256")));
+        assert_eq!(
+            plan.unexpected,
+            vec!["TDR-2023/annex.pdf: only docx attachments can be anonymised, so it was removed"]
+        );
+    }
+
+    #[test]
+    fn test_plan_package_does_not_write_anything() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package(&input_dir, valid_json(), None);
+        let files_before = std::fs::read_dir(&input_dir).unwrap().count();
+        plan_package(&tar_path, &Policy::default()).unwrap();
+        assert_eq!(std::fs::read_dir(&input_dir).unwrap().count(), files_before);
+    }
+
+    #[test]
+    fn test_plan_package_missing_metadata_filename() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package(&input_dir, "{}", None);
+        let err = plan_package(&tar_path, &Policy::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'filename' is missing from the metadata json at /parameters/TRE/payload/filename"
+        );
+    }
+
+    #[test]
+    fn test_plan_package_lists_personal_data_and_redacted_fields() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json().replace(
+            r#""name": "test""#,
+            r#""name": "test", "notes": "call 07700 900123""#,
+        );
+        let tar_path = create_package(&input_dir, &json, None);
+        let policy = Policy {
+            pii: PiiAction::Redact,
+            ..Policy::default()
+        };
+        let plan = plan_package(&tar_path, &policy).unwrap();
+        assert_eq!(
+            plan.pii_findings,
+            vec![PiiFinding {
+                pointer: String::from("/parameters/PARSER/notes"),
+                kind: PiiKind::PhoneNumber
+            }]
+        );
+        assert!(plan
+            .metadata_changes
+            .contains(&String::from("/parameters/PARSER/notes")));
+    }
+
+    #[test]
+    fn test_plan_display() {
+        let plan = PackagePlan {
+            input_file: String::from("TDR-2023.tar.gz"),
+            output_file: String::from("TST-2023.tar.gz"),
+            input_batch_reference: String::from("TDR-2023"),
+            output_batch_reference: String::from("TST-2023"),
+            renamed: vec![Rename {
+                from: String::from("TDR-2023"),
+                to: String::from("TST-2023"),
+            }],
+            deleted: vec![String::from("TDR-2023/parser.log")],
+            regenerated: vec![String::from("TST-2023/test.docx")],
+            metadata_changes: vec![String::from("/parameters/TDR/Contact-Email")],
+            reference_rewrites: vec![String::from("/parameters/TRE/reference")],
+            pii_findings: vec![PiiFinding {
+                pointer: String::from("/parameters/TDR/Judgment-Update-Details"),
+                kind: PiiKind::Email,
+            }],
+            unexpected: vec![String::from(
+                "TDR-2023/annex.pdf: only docx attachments can be anonymised, so it was removed",
+            )],
+            files: vec![
+                FileReport {
+                    path: String::from("TDR-2023/test.docx"),
+                    decision: FileDecision::Expected,
+                },
+                FileReport {
+                    path: String::from("TDR-2023/notes.txt"),
+                    decision: FileDecision::Dropped,
+                },
+            ],
+        };
+        let expected = "Package TDR-2023.tar.gz
+  Output file: TST-2023.tar.gz
+  Batch reference: TDR-2023 -> TST-2023
+  Rename: TDR-2023 -> TST-2023
+  Delete: TDR-2023/parser.log
+  Regenerate: TST-2023/test.docx
+  Change metadata: /parameters/TDR/Contact-Email
+  Rewrite reference: /parameters/TRE/reference
+  Possible personal data: email address at /parameters/TDR/Judgment-Update-Details
+  Unexpected: TDR-2023/annex.pdf: only docx attachments can be anonymised, so it was removed
+  File: TDR-2023/notes.txt: unexpected, dropped
+";
+        assert_eq!(plan.to_string(), expected);
+        assert_eq!(
+            serde_json::to_value(&plan).unwrap()["deleted"],
+            json!(["TDR-2023/parser.log"])
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! ## Scratch directories and publishing outputs
+//!
+//! The output directory is often a shared drive, so nothing unanonymised is ever written to it.
+//! Packages are extracted and anonymised in a private scratch directory in the system temporary directory,
+//! which can be changed with the `TMPDIR` environment variable. It is removed when processing finishes, whether it succeeds or fails.
+//!
+//! The tar.gz and audit record are written to hidden temporary files next to their final paths,
+//! and only renamed into place once they are complete. A failed package leaves nothing behind.
+use crate::error::{AnonymiserError, WithPath};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tempfile::{Builder, NamedTempFile, TempDir};
+
+/// # Create a private scratch directory, which is removed when it is dropped
+pub(crate) fn scratch_dir() -> Result<TempDir, AnonymiserError> {
+    Builder::new()
+        .prefix("anonymiser-")
+        .tempdir()
+        .with_path(&std::env::temp_dir())
+}
+
+/// # A file which is written next to its final path and renamed into place once it is complete
+///
+/// The temporary file is removed if it is dropped without being published.
+pub(crate) struct PendingFile {
+    file: NamedTempFile,
+    path: PathBuf,
+}
+
+impl PendingFile {
+    pub(crate) fn new(path: &Path) -> Result<PendingFile, AnonymiserError> {
+        let folder: &Path = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let file_name: String = path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file: NamedTempFile = Builder::new()
+            .prefix(&format!(".{file_name}."))
+            .suffix(".part")
+            .tempfile_in(folder)
+            .with_path(path)?;
+        Ok(PendingFile {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn as_file(&self) -> &File {
+        self.file.as_file()
+    }
+
+    /// # Flush the file to disk and rename it to its final path, replacing any existing file
+    pub(crate) fn publish(self) -> Result<(), AnonymiserError> {
+        self.file.as_file().sync_all().with_path(&self.path)?;
+        self.file
+            .persist(&self.path)
+            .map_err(|e| e.error)
+            .with_path(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use std::fs::{read_dir, read_to_string};
+    use std::io::Write;
+
+    #[test]
+    fn test_pending_file_is_only_visible_once_published() {
+        let output_dir = TempDir::new().unwrap();
+        let path: PathBuf = output_dir.join("TST-2023.tar.gz");
+        let pending_file = PendingFile::new(&path).unwrap();
+        pending_file.as_file().write_all(b"package").unwrap();
+        assert!(!path.exists());
+
+        pending_file.publish().unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "package");
+        assert_eq!(read_dir(output_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_pending_file_is_removed_if_not_published() {
+        let output_dir = TempDir::new().unwrap();
+        let pending_file = PendingFile::new(&output_dir.join("TST-2023.tar.gz")).unwrap();
+        pending_file.as_file().write_all(b"partial").unwrap();
+        drop(pending_file);
+        assert_eq!(read_dir(output_dir.path()).unwrap().count(), 0);
+    }
+}
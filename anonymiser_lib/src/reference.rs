@@ -0,0 +1,601 @@
+//! ## Batch references
+//!
+//! A batch reference like `TDR-2023-ABC` names the package folder, the metadata file and the tar.gz file.
+//! By default, the anonymised package uses the same reference with `TST` as the source system.
+//! The reference mapping can replace the rest of the reference too, so it can't be read from the anonymised package.
+use crate::error::{AnonymiserError, WithPath};
+use crate::replacement::PseudonymisationKey;
+use crate::rules::json_values;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The prefix TRE adds to the names of the packages and metadata files it produces
+const TRE_PREFIX: &str = "TRE-";
+
+/// The source system of anonymised packages
+pub const ANONYMISED_SOURCE_SYSTEM: &str = "TST";
+
+/// The characters of generated identifiers
+const IDENTIFIER_CHARACTERS: &[u8; 36] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// The length of a generated identifier if the original reference doesn't have one
+const DEFAULT_IDENTIFIER_LENGTH: usize = 4;
+
+/// # A parsed batch reference
+///
+/// This is a source system, a year and an optional identifier, such as `TDR-2023-ABC`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BatchReference {
+    source_system: String,
+    year: u16,
+    identifier: Option<String>,
+    reference: String,
+}
+
+impl BatchReference {
+    /// # Parse a batch reference, with or without the `TRE-` prefix
+    pub fn parse(reference: &str) -> Result<BatchReference, AnonymiserError> {
+        parse_reference(reference).map_err(|reason| AnonymiserError::InvalidBatchReference {
+            reference: reference.to_string(),
+            reason,
+        })
+    }
+
+    /// # The batch reference in a package file name like `TRE-TDR-2023-ABC.tar.gz`
+    pub fn from_package_file_name(file_name: &str) -> Result<BatchReference, AnonymiserError> {
+        PackageFileName::parse(file_name)
+            .map(|package_file_name| package_file_name.batch_reference)
+            .map_err(|reason| AnonymiserError::InvalidPackageName {
+                name: file_name.to_string(),
+                reason,
+            })
+    }
+
+    pub fn source_system(&self) -> &str {
+        &self.source_system
+    }
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    pub fn identifier(&self) -> Option<&str> {
+        self.identifier.as_deref()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.reference
+    }
+
+    /// # The reference of the anonymised package, such as `TST-2023-ABC` for `TDR-2023-ABC`
+    pub fn anonymised(&self) -> BatchReference {
+        BatchReference::new(
+            ANONYMISED_SOURCE_SYSTEM.to_string(),
+            self.year,
+            self.identifier.clone(),
+        )
+    }
+
+    /// # The folder in the package which contains the files
+    pub fn folder(&self) -> &Path {
+        Path::new(&self.reference)
+    }
+
+    /// # The name of the metadata file, such as `TRE-TDR-2023-ABC-metadata.json`
+    pub fn metadata_file_name(&self) -> String {
+        format!("{TRE_PREFIX}{}-metadata.json", self.reference)
+    }
+
+    /// # The name of the LegalDocML xml file, such as `TDR-2023-ABC.xml`
+    pub fn xml_file_name(&self) -> String {
+        format!("{}.xml", self.reference)
+    }
+
+    /// # The path of a file in the package folder, as it is shown in plans and audit records
+    pub fn package_path(&self, file_name: &str) -> String {
+        format!("{}/{file_name}", self.reference)
+    }
+
+    fn new(source_system: String, year: u16, identifier: Option<String>) -> BatchReference {
+        let reference: String = match &identifier {
+            Some(identifier) => format!("{source_system}-{year:04}-{identifier}"),
+            None => format!("{source_system}-{year:04}"),
+        };
+        BatchReference {
+            source_system,
+            year,
+            identifier,
+            reference,
+        }
+    }
+}
+
+impl Display for BatchReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reference)
+    }
+}
+
+impl FromStr for BatchReference {
+    type Err = AnonymiserError;
+
+    fn from_str(reference: &str) -> Result<Self, Self::Err> {
+        BatchReference::parse(reference)
+    }
+}
+
+/// # How the batch reference of the anonymised package is chosen
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ReferenceMapping {
+    /// Replace the source system with `TST`, so `TDR-2023-ABC` becomes `TST-2023-ABC`
+    #[default]
+    PrefixSwap,
+    /// Replace the identifier with a random one of the same length, such as `TST-2023-Q7XK`.
+    /// A new one is chosen each time a package is processed.
+    Random,
+    /// Replace the identifier with one made from a keyed hash of the original reference.
+    /// The same reference and key always give the same anonymised reference.
+    KeyedHash(PseudonymisationKey),
+    /// Look up the anonymised reference in a mapping from original references
+    Explicit(HashMap<BatchReference, BatchReference>),
+}
+
+impl ReferenceMapping {
+    /// # Load an explicit mapping from a CSV file
+    ///
+    /// Each line is an original reference and the anonymised reference, such as `TDR-2023-ABC,TST-2023-XYZ`.
+    /// A header line of `original,anonymised` and blank lines are ignored.
+    /// Each original and each anonymised reference can only be used once.
+    pub fn load_csv(csv_file_path: &Path) -> Result<ReferenceMapping, AnonymiserError> {
+        let csv: String = fs::read_to_string(csv_file_path).with_path(csv_file_path)?;
+        let invalid_line = |line_number: usize, reason: String| {
+            AnonymiserError::InvalidPolicy(format!(
+                "Line {line_number} of the reference mapping {}: {reason}",
+                csv_file_path.display()
+            ))
+        };
+        let mut mapping: HashMap<BatchReference, BatchReference> = HashMap::new();
+        for (index, line) in csv.lines().enumerate() {
+            let line_number: usize = index + 1;
+            let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+            match cells[..] {
+                [""] => continue,
+                [original, anonymised]
+                    if original.eq_ignore_ascii_case("original")
+                        && anonymised.eq_ignore_ascii_case("anonymised") =>
+                {
+                    continue
+                }
+                [original, anonymised] => {
+                    let original = BatchReference::parse(original)
+                        .map_err(|err| invalid_line(line_number, err.to_string()))?;
+                    let anonymised = BatchReference::parse(anonymised)
+                        .map_err(|err| invalid_line(line_number, err.to_string()))?;
+                    if mapping.values().any(|reference| reference == &anonymised) {
+                        return Err(invalid_line(
+                            line_number,
+                            format!("'{anonymised}' is used for more than one reference"),
+                        ));
+                    }
+                    if mapping.insert(original.clone(), anonymised).is_some() {
+                        return Err(invalid_line(
+                            line_number,
+                            format!("'{original}' is mapped more than once"),
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(invalid_line(
+                        line_number,
+                        String::from("Expected an original and an anonymised reference"),
+                    ))
+                }
+            }
+        }
+        Ok(ReferenceMapping::Explicit(mapping))
+    }
+
+    /// # The name of the mapping used in the audit record
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReferenceMapping::PrefixSwap => "prefix_swap",
+            ReferenceMapping::Random => "random",
+            ReferenceMapping::KeyedHash(_) => "keyed_hash",
+            ReferenceMapping::Explicit(_) => "explicit",
+        }
+    }
+
+    /// # The reference of the anonymised package
+    ///
+    /// The random and keyed hash references keep the year and add an identifier if the original doesn't have one.
+    pub fn map(&self, reference: &BatchReference) -> Result<BatchReference, AnonymiserError> {
+        let identifier_length: usize = reference
+            .identifier()
+            .map_or(DEFAULT_IDENTIFIER_LENGTH, str::len);
+        let with_identifier = |bytes: &[u8]| {
+            let identifier: String = bytes
+                .iter()
+                .take(identifier_length)
+                .map(|byte| {
+                    IDENTIFIER_CHARACTERS[*byte as usize % IDENTIFIER_CHARACTERS.len()] as char
+                })
+                .collect();
+            BatchReference::new(
+                ANONYMISED_SOURCE_SYSTEM.to_string(),
+                reference.year,
+                Some(identifier),
+            )
+        };
+        match self {
+            ReferenceMapping::PrefixSwap => Ok(reference.anonymised()),
+            ReferenceMapping::Random => {
+                let mut bytes: Vec<u8> = vec![0; identifier_length];
+                getrandom::getrandom(&mut bytes).map_err(|err| {
+                    AnonymiserError::InvalidPolicy(format!(
+                        "Cannot generate a random reference: {err}"
+                    ))
+                })?;
+                Ok(with_identifier(&bytes))
+            }
+            ReferenceMapping::KeyedHash(key) => {
+                let mut bytes: Vec<u8> = Vec::new();
+                while bytes.len() < identifier_length {
+                    let input: String = format!("{}:{}", reference, bytes.len());
+                    bytes.extend(key.mac(&input));
+                }
+                Ok(with_identifier(&bytes))
+            }
+            ReferenceMapping::Explicit(mapping) => {
+                mapping.get(reference).cloned().ok_or_else(|| {
+                    AnonymiserError::InvalidBatchReference {
+                        reference: reference.to_string(),
+                        reason: String::from("It is not in the reference mapping"),
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// # The batch reference of a package and whether its file name has the `TRE-` prefix
+pub(crate) struct PackageFileName {
+    pub(crate) batch_reference: BatchReference,
+    pub(crate) has_tre_prefix: bool,
+}
+
+impl PackageFileName {
+    /// # Parse a file name like `TRE-TDR-2023-ABC.tar.gz`
+    ///
+    /// Returns the reason if the name is not a valid package name.
+    pub(crate) fn parse(file_name: &str) -> Result<PackageFileName, String> {
+        let reference: &str = file_name
+            .strip_suffix(".tar.gz")
+            .ok_or("The file name must end with .tar.gz")?;
+        Ok(PackageFileName {
+            batch_reference: parse_reference(reference)?,
+            has_tre_prefix: reference.starts_with(TRE_PREFIX),
+        })
+    }
+
+    /// # The file name of the package with another reference, keeping the `TRE-` prefix if there is one
+    pub(crate) fn with_reference(&self, batch_reference: &BatchReference) -> String {
+        let prefix: &str = if self.has_tre_prefix { TRE_PREFIX } else { "" };
+        format!("{prefix}{batch_reference}.tar.gz")
+    }
+}
+
+/// # Replace the input batch reference with the output one in every string in the metadata json
+///
+/// A reference is only replaced where it isn't part of a longer word, so `TDR-2023-ABC` is replaced in
+/// `TRE-TDR-2023-ABC-metadata.json` but `TDR-2023-AB` isn't.
+///
+/// Returns the pointers of the values which were changed.
+pub(crate) fn rewrite_references(
+    json_value: &mut Value,
+    input_batch_reference: &BatchReference,
+    output_batch_reference: &BatchReference,
+) -> Vec<String> {
+    let mut rewritten_pointers: Vec<String> = Vec::new();
+    for (pointer, _) in json_values(json_value) {
+        let Some(value) = json_value.pointer_mut(&pointer) else {
+            continue;
+        };
+        let Some(rewritten) = value.as_str().and_then(|text| {
+            replace_reference(text, input_batch_reference, output_batch_reference)
+        }) else {
+            continue;
+        };
+        *value = json!(rewritten);
+        rewritten_pointers.push(pointer);
+    }
+    rewritten_pointers
+}
+
+/// # Replace each whole occurrence of a batch reference in some text
+///
+/// Returns `None` if the text doesn't contain the reference.
+pub(crate) fn replace_reference(
+    text: &str,
+    from: &BatchReference,
+    to: &BatchReference,
+) -> Option<String> {
+    replace_whole_words(text, from.as_str(), to.as_str())
+}
+
+/// # Replace each occurrence of a value in some text which isn't part of a longer word
+///
+/// Returns `None` if the text doesn't contain the value.
+pub(crate) fn replace_whole_words(text: &str, from: &str, to: &str) -> Option<String> {
+    let is_word_character = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    let mut replaced: String = String::new();
+    let mut end_of_last_match: usize = 0;
+    for (start, value) in text.match_indices(from) {
+        let end: usize = start + value.len();
+        if is_word_character(text[..start].chars().next_back())
+            || is_word_character(text[end..].chars().next())
+        {
+            continue;
+        }
+        replaced.push_str(&text[end_of_last_match..start]);
+        replaced.push_str(to);
+        end_of_last_match = end;
+    }
+    if end_of_last_match == 0 {
+        return None;
+    }
+    replaced.push_str(&text[end_of_last_match..]);
+    Some(replaced)
+}
+
+/// # Parse a batch reference, returning the reason if it is not valid
+fn parse_reference(reference: &str) -> Result<BatchReference, String> {
+    let unprefixed: &str = reference.strip_prefix(TRE_PREFIX).unwrap_or(reference);
+    let parts: Vec<&str> = unprefixed.split('-').collect();
+    let (source_system, year, identifier) = match parts[..] {
+        [source_system, year] => (source_system, year, None),
+        [source_system, year, identifier] => (source_system, year, Some(identifier)),
+        _ => {
+            return Err(String::from(
+                "Expected a reference like TDR-2023-ABC, with a source system, a year and an optional identifier",
+            ))
+        }
+    };
+    if source_system.is_empty() || !source_system.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(format!(
+            "The source system '{source_system}' must be upper case letters"
+        ));
+    }
+    if year.len() != 4 || !year.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("The year '{year}' must be four digits"));
+    }
+    if let Some(identifier) = identifier {
+        if identifier.is_empty() || !identifier.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!(
+                "The identifier '{identifier}' must only contain letters and numbers"
+            ));
+        }
+    }
+    Ok(BatchReference::new(
+        source_system.to_string(),
+        year.parse().expect("The year is four digits"),
+        identifier.map(String::from),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch_reference() {
+        let reference = BatchReference::parse("TDR-2023-ABC1").unwrap();
+        assert_eq!(reference.source_system(), "TDR");
+        assert_eq!(reference.year(), 2023);
+        assert_eq!(reference.identifier(), Some("ABC1"));
+        assert_eq!(reference.to_string(), "TDR-2023-ABC1");
+        assert_eq!(
+            BatchReference::parse("TRE-TDR-2023-ABC1").unwrap(),
+            reference
+        );
+        assert_eq!(
+            BatchReference::parse("TDR-2023").unwrap().identifier(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_anonymised_reference_and_file_names() {
+        let reference: BatchReference = "TDR-2023-ABC".parse().unwrap();
+        let anonymised = reference.anonymised();
+        assert_eq!(anonymised.as_str(), "TST-2023-ABC");
+        assert_eq!(
+            anonymised.metadata_file_name(),
+            "TRE-TST-2023-ABC-metadata.json"
+        );
+        assert_eq!(anonymised.xml_file_name(), "TST-2023-ABC.xml");
+        assert_eq!(anonymised.package_path("a.docx"), "TST-2023-ABC/a.docx");
+        assert_eq!(anonymised.folder(), Path::new("TST-2023-ABC"));
+    }
+
+    #[test]
+    fn test_anonymised_reference_only_changes_the_source_system() {
+        let reference = BatchReference::parse("TDR-2023-TDR1").unwrap();
+        assert_eq!(reference.anonymised().as_str(), "TST-2023-TDR1");
+    }
+
+    #[test]
+    fn test_parse_batch_reference_errors() {
+        let reason = |reference: &str| match BatchReference::parse(reference).unwrap_err() {
+            AnonymiserError::InvalidBatchReference { reason, .. } => reason,
+            err => panic!("Unexpected error {err}"),
+        };
+        assert!(reason("test-reference").starts_with("The source system 'test'"));
+        assert_eq!(reason("TDR-23-ABC"), "The year '23' must be four digits");
+        assert_eq!(
+            reason("TDR-2023-A.B"),
+            "The identifier 'A.B' must only contain letters and numbers"
+        );
+        assert!(reason("TDR-2023-ABC-DEF").starts_with("Expected a reference like TDR-2023-ABC"));
+        assert!(reason("").starts_with("Expected a reference like TDR-2023-ABC"));
+        assert_eq!(
+            BatchReference::parse("TDR").unwrap_err().to_string(),
+            "Invalid batch reference 'TDR': Expected a reference like TDR-2023-ABC, with a source system, a year and an optional identifier"
+        );
+    }
+
+    #[test]
+    fn test_parse_package_file_name() {
+        let file_name = PackageFileName::parse("TRE-TDR-2023-ABC.tar.gz").unwrap();
+        assert_eq!(file_name.batch_reference.as_str(), "TDR-2023-ABC");
+        assert_eq!(
+            file_name.with_reference(&file_name.batch_reference.anonymised()),
+            "TRE-TST-2023-ABC.tar.gz"
+        );
+        let file_name = PackageFileName::parse("TDR-2023.tar.gz").unwrap();
+        assert_eq!(
+            file_name.with_reference(&file_name.batch_reference.anonymised()),
+            "TST-2023.tar.gz"
+        );
+        assert_eq!(
+            PackageFileName::parse("TDR-2023.1.tar.gz").err().unwrap(),
+            "The year '2023.1' must be four digits"
+        );
+        assert_eq!(
+            PackageFileName::parse("TDR-2023.zip").err().unwrap(),
+            "The file name must end with .tar.gz"
+        );
+        assert_eq!(
+            BatchReference::from_package_file_name("TRE-TDR-2023-ABC.tar.gz").unwrap(),
+            BatchReference::parse("TDR-2023-ABC").unwrap()
+        );
+        assert!(matches!(
+            BatchReference::from_package_file_name("TDR-2023.zip"),
+            Err(AnonymiserError::InvalidPackageName { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rewrite_references_in_the_metadata_json() {
+        let from = BatchReference::parse("TDR-2023-ABC").unwrap();
+        let to = BatchReference::parse("TST-2023-XYZ").unwrap();
+        let mut metadata = json!({"parameters": {
+            "TRE": {"reference": "TDR-2023-ABC", "payload": {
+                "filename": "judgment.docx",
+                "xml": "TDR-2023-ABC.xml",
+                "metadata": "TRE-TDR-2023-ABC-metadata.json"
+            }},
+            "TDR": {"Consignment-Reference": "TDR-2023-ABCD", "Notes": ["From TDR-2023-ABC, TDR-2023-ABC"]}
+        }});
+        let rewritten_pointers = rewrite_references(&mut metadata, &from, &to);
+        assert_eq!(
+            metadata,
+            json!({"parameters": {
+                "TRE": {"reference": "TST-2023-XYZ", "payload": {
+                    "filename": "judgment.docx",
+                    "xml": "TST-2023-XYZ.xml",
+                    "metadata": "TRE-TST-2023-XYZ-metadata.json"
+                }},
+                "TDR": {"Consignment-Reference": "TDR-2023-ABCD", "Notes": ["From TST-2023-XYZ, TST-2023-XYZ"]}
+            }})
+        );
+        assert_eq!(
+            rewritten_pointers,
+            vec![
+                "/parameters/TDR/Notes/0",
+                "/parameters/TRE/payload/metadata",
+                "/parameters/TRE/payload/xml",
+                "/parameters/TRE/reference"
+            ]
+        );
+    }
+
+    fn map(mapping: &ReferenceMapping, reference: &str) -> String {
+        mapping
+            .map(&BatchReference::parse(reference).unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_random_reference_mapping_keeps_the_format() {
+        let anonymised = map(&ReferenceMapping::Random, "TDR-2023-ABCDEF");
+        assert_ne!(
+            anonymised,
+            map(&ReferenceMapping::Random, "TDR-2023-ABCDEF")
+        );
+        let anonymised = BatchReference::parse(&anonymised).unwrap();
+        assert_eq!(anonymised.source_system(), "TST");
+        assert_eq!(anonymised.year(), 2023);
+        assert_eq!(anonymised.identifier().unwrap().len(), 6);
+        let anonymised =
+            BatchReference::parse(&map(&ReferenceMapping::Random, "TDR-2023")).unwrap();
+        assert_eq!(anonymised.identifier().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_keyed_hash_reference_mapping_is_stable_and_depends_on_the_key() {
+        let mapping = ReferenceMapping::KeyedHash(PseudonymisationKey::new(b"test-key").unwrap());
+        let other_mapping =
+            ReferenceMapping::KeyedHash(PseudonymisationKey::new(b"other-key").unwrap());
+        assert_eq!(map(&mapping, "TDR-2023"), map(&mapping, "TDR-2023"));
+        assert!(map(&mapping, "TDR-2023").starts_with("TST-2023-"));
+        assert_ne!(map(&mapping, "TDR-2023"), map(&mapping, "TDR-2024"));
+        assert_ne!(map(&mapping, "TDR-2023"), map(&other_mapping, "TDR-2023"));
+        assert_eq!(map(&mapping, "TDR-2023-ABC").len(), "TST-2023-ABC".len());
+    }
+
+    #[test]
+    fn test_explicit_reference_mapping_from_csv() {
+        let csv_dir = assert_fs::TempDir::new().unwrap();
+        let csv_path = csv_dir.join("mapping.csv");
+        fs::write(
+            &csv_path,
+            "original,anonymised\nTDR-2023-ABC, TST-2023-XYZ\n\nTDR-2023,TST-2020-A1\n",
+        )
+        .unwrap();
+        let mapping = ReferenceMapping::load_csv(&csv_path).unwrap();
+        assert_eq!(map(&mapping, "TDR-2023-ABC"), "TST-2023-XYZ");
+        assert_eq!(map(&mapping, "TRE-TDR-2023"), "TST-2020-A1");
+        assert_eq!(
+            mapping
+                .map(&BatchReference::parse("TDR-2024").unwrap())
+                .unwrap_err()
+                .to_string(),
+            "Invalid batch reference 'TDR-2024': It is not in the reference mapping"
+        );
+    }
+
+    #[test]
+    fn test_explicit_reference_mapping_errors() {
+        let csv_dir = assert_fs::TempDir::new().unwrap();
+        let csv_path = csv_dir.join("mapping.csv");
+        let error = |csv: &str| {
+            fs::write(&csv_path, csv).unwrap();
+            let err = ReferenceMapping::load_csv(&csv_path)
+                .unwrap_err()
+                .to_string();
+            err.split(": ").skip(1).collect::<Vec<&str>>().join(": ")
+        };
+        assert_eq!(
+            error("TDR-2023,TST-2023,TST-2024"),
+            "Expected an original and an anonymised reference"
+        );
+        assert_eq!(
+            error("TDR-2023,tst"),
+            "Invalid batch reference 'tst': Expected a reference like TDR-2023-ABC, with a source system, a year and an optional identifier"
+        );
+        assert_eq!(
+            error("TDR-2023,TST-2023\nTDR-2024,TST-2023"),
+            "'TST-2023' is used for more than one reference"
+        );
+        assert_eq!(
+            error("TDR-2023,TST-2023\nTDR-2023,TST-2024"),
+            "'TDR-2023' is mapped more than once"
+        );
+        assert!(ReferenceMapping::load_csv(&csv_dir.join("missing.csv")).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! ## Replacement values
+//!
+//! This decides what is written in place of a value that a `mask` rule matches.
+use crate::error::{AnonymiserError, WithPath};
+use crate::fake::{FakeValueGenerator, FakeValueKind};
+use crate::rules::{value_as_string, MASK};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use std::{env, fs, path::Path};
+
+/// The environment variable the pseudonymisation key is read from if there is no key file
+pub const KEY_ENVIRONMENT_VARIABLE: &str = "ANONYMISER_KEY";
+
+/// # The secret key used to generate pseudonyms
+///
+/// The key is never printed in debug output.
+#[derive(Clone, PartialEq)]
+pub struct PseudonymisationKey(Vec<u8>);
+
+impl Debug for PseudonymisationKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PseudonymisationKey(****)")
+    }
+}
+
+impl PseudonymisationKey {
+    pub fn new(key: &[u8]) -> Result<PseudonymisationKey, AnonymiserError> {
+        if key.is_empty() {
+            Err(AnonymiserError::InvalidPolicy(String::from(
+                "The pseudonymisation key is empty",
+            )))
+        } else {
+            Ok(PseudonymisationKey(key.to_vec()))
+        }
+    }
+
+    /// # Read the key from a file, ignoring any trailing new line
+    pub fn from_file(key_file_path: &Path) -> Result<PseudonymisationKey, AnonymiserError> {
+        let key: Vec<u8> = fs::read(key_file_path).with_path(key_file_path)?;
+        let trimmed_length: usize = key.len()
+            - key
+                .iter()
+                .rev()
+                .take_while(|byte| **byte == b'\n' || **byte == b'\r')
+                .count();
+        PseudonymisationKey::new(&key[..trimmed_length])
+    }
+
+    /// # Read the key from the `ANONYMISER_KEY` environment variable
+    pub fn from_env() -> Result<PseudonymisationKey, AnonymiserError> {
+        let key: String = env::var(KEY_ENVIRONMENT_VARIABLE).map_err(|_| {
+            AnonymiserError::InvalidPolicy(format!(
+                "A key file or the {KEY_ENVIRONMENT_VARIABLE} environment variable is needed to pseudonymise"
+            ))
+        })?;
+        PseudonymisationKey::new(key.as_bytes())
+    }
+
+    /// # Create a stable token from a value using HMAC-SHA256
+    pub fn pseudonym(&self, value: &str) -> String {
+        hex::encode(self.mac(value))
+    }
+
+    /// # The HMAC-SHA256 of a value
+    pub(crate) fn mac(&self, value: &str) -> Vec<u8> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes a key of any size");
+        mac.update(value.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// # What a masked value is replaced with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Replacement {
+    /// Replace the value with `XXXXXXXXX`
+    #[default]
+    Mask,
+    /// Replace the value with a keyed hash of it.
+    /// The same value and key always give the same token.
+    Pseudonymise(PseudonymisationKey),
+    /// Replace the value with a realistic fake value of the same kind
+    Fake(FakeValueGenerator),
+}
+
+impl Replacement {
+    /// # Get the replacement for the original value at the pointer
+    ///
+    /// If there is no original value, there is nothing to pseudonymise so the value is masked.
+    /// Fake values for missing fields are chosen from the field name instead.
+    pub fn replace(&self, pointer: &str, original: Option<&Value>) -> Value {
+        let original: Option<&Value> = original.filter(|value| !value.is_null());
+        match (self, original) {
+            (Replacement::Pseudonymise(key), Some(value)) => {
+                json!(key.pseudonym(&value_as_string(value)))
+            }
+            (Replacement::Fake(generator), Some(value)) => {
+                json!(generator.fake_for(&value_as_string(value)))
+            }
+            (Replacement::Fake(generator), None) => {
+                let field_name: &str = pointer.rsplit('/').next().unwrap_or_default();
+                json!(generator.generate(FakeValueKind::of_field(field_name), pointer))
+            }
+            _ => json!(MASK),
+        }
+    }
+
+    /// # Get the replacement for a value found in free text, such as the parser log
+    ///
+    /// Fake values are of the given kind, since the value doesn't come from a metadata field.
+    pub fn replace_text(&self, kind: FakeValueKind, original: &str) -> String {
+        match self {
+            Replacement::Mask => MASK.to_string(),
+            Replacement::Pseudonymise(key) => key.pseudonym(original),
+            Replacement::Fake(generator) => generator.generate(kind, original),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_pseudonym_is_stable_and_depends_on_the_key() {
+        let key = PseudonymisationKey::new(b"test-key").unwrap();
+        let other_key = PseudonymisationKey::new(b"other-key").unwrap();
+        assert_eq!(key.pseudonym("test-name"), key.pseudonym("test-name"));
+        assert_eq!(
+            key.pseudonym("test-name"),
+            "e3609ac3b8258cd3e041d69021aa7847f30e973532566346c213f69dbbf21027"
+        );
+        assert_ne!(key.pseudonym("test-name"), key.pseudonym("other-name"));
+        assert_ne!(key.pseudonym("test-name"), other_key.pseudonym("test-name"));
+    }
+
+    #[test]
+    fn test_replace_masks_missing_values() {
+        let key = PseudonymisationKey::new(b"test-key").unwrap();
+        let replacement = Replacement::Pseudonymise(key);
+        assert_eq!(replacement.replace("/a", None), json!(MASK));
+        assert_eq!(replacement.replace("/a", Some(&Value::Null)), json!(MASK));
+        assert_eq!(
+            Replacement::Mask.replace("/a", Some(&json!("a"))),
+            json!(MASK)
+        );
+    }
+
+    #[test]
+    fn test_replace_with_fake_values() {
+        let generator = FakeValueGenerator::new(1);
+        let replacement = Replacement::Fake(generator);
+        assert_eq!(
+            replacement.replace("/Contact-Name", Some(&json!("Test Person"))),
+            json!(generator.fake_for("Test Person"))
+        );
+        let missing_email = replacement.replace("/Contact-Email", None);
+        assert!(missing_email.as_str().unwrap().ends_with("@example.com"));
+    }
+
+    #[test]
+    fn test_key_from_file_ignores_trailing_new_line() {
+        let key_dir = TempDir::new().unwrap();
+        let key_path = key_dir.join("key");
+        fs::write(&key_path, "test-key\n").unwrap();
+        let key = PseudonymisationKey::from_file(&key_path).unwrap();
+        assert_eq!(key, PseudonymisationKey::new(b"test-key").unwrap());
+    }
+
+    #[test]
+    fn test_empty_key_is_an_error() {
+        let err = PseudonymisationKey::new(b"").unwrap_err();
+        assert_eq!(err.to_string(), "The pseudonymisation key is empty");
+    }
+
+    #[test]
+    fn test_key_debug_output_hides_the_key() {
+        let key = PseudonymisationKey::new(b"test-key").unwrap();
+        assert_eq!(format!("{key:?}"), "PseudonymisationKey(****)");
+    }
+}
@@ -0,0 +1,72 @@
+//! ## Reproducible tar.gz output
+//!
+//! Anonymised packages don't keep anything from the machine or the input headers which created them.
+//! Every entry has a fixed modification time, no owner, and mode 755 for folders or 644 for files.
+//! The gzip header has no timestamp or file name.
+//!
+//! Both `process_package` and `process_package_streaming` write the entries sorted by path, whatever the order of the input,
+//! so the same input and policy always give the same bytes from either of them.
+use flate2::{write::GzEncoder, Compression, GzBuilder};
+use std::io::Write;
+use tar::{EntryType, Header};
+
+/// The modification time of every entry, 2000-01-01T00:00:00Z in seconds since the epoch
+pub const ENTRY_MTIME: u64 = 946_684_800;
+
+/// The operating system in the gzip header, which is "unknown"
+const GZIP_OPERATING_SYSTEM: u8 = 255;
+
+/// # Create a gzip encoder with no timestamp or file name in its header
+pub(crate) fn gz_encoder<W: Write>(writer: W) -> GzEncoder<W> {
+    GzBuilder::new()
+        .mtime(0)
+        .operating_system(GZIP_OPERATING_SYSTEM)
+        .write(writer, Compression::default())
+}
+
+/// # Create a tar header with only the entry type and size from the original
+///
+/// The ownership and time are fixed and the mode is normalised, so nothing about where the package was made is kept.
+pub(crate) fn normalised_header(entry_type: EntryType, size: u64) -> Header {
+    let mut header: Header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(match entry_type.is_dir() {
+        true => 0o755,
+        false => 0o644,
+    });
+    header.set_mtime(ENTRY_MTIME);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gz_encoder_header_has_no_timestamp_or_file_name() {
+        let mut encoder = gz_encoder(Vec::new());
+        encoder.write_all(b"package").unwrap();
+        let gz: Vec<u8> = encoder.finish().unwrap();
+        // The flags byte is followed by the four byte timestamp, the extra flags and the operating system
+        assert_eq!(gz[3], 0);
+        assert_eq!(&gz[4..8], &[0, 0, 0, 0]);
+        assert_eq!(gz[9], GZIP_OPERATING_SYSTEM);
+    }
+
+    #[test]
+    fn test_normalised_header() {
+        let header: Header = normalised_header(EntryType::Regular, 10);
+        assert_eq!(header.size().unwrap(), 10);
+        assert_eq!(header.mode().unwrap(), 0o644);
+        assert_eq!(header.mtime().unwrap(), ENTRY_MTIME);
+        assert_eq!(header.uid().unwrap(), 0);
+        assert_eq!(header.username().unwrap(), Some(""));
+        assert_eq!(
+            normalised_header(EntryType::Directory, 0).mode().unwrap(),
+            0o755
+        );
+    }
+}
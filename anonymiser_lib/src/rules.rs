@@ -0,0 +1,394 @@
+//! ## Redaction rules
+//!
+//! The rules describe which values in the metadata json are sensitive and what should happen to them.
+//!
+//! A rules file is a json array of rules. Each rule has a [JSON pointer](https://datatracker.ietf.org/doc/html/rfc6901)
+//! and an action. A `*` segment in the pointer matches every key of an object or every element of an array.
+//! ```json
+//! [
+//!   {"pointer": "/parameters/TDR/Contact-Email", "action": "mask"},
+//!   {"pointer": "/parameters/TDR/Contact-Name", "action": "hash"},
+//!   {"pointer": "/parameters/TDR/Source-Organization", "action": {"replace": "Test Organisation"}},
+//!   {"pointer": "/parameters/PARSER/attachments/*/name", "action": "drop"}
+//! ]
+//! ```
+//! A `hash` rule replaces the value with a keyed hash, using the same key as the pseudonymise mode,
+//! so the original can't be found by hashing likely values.
+use crate::error::{AnonymiserError, WithPath};
+use crate::replacement::{PseudonymisationKey, Replacement};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{fs, path::Path};
+
+/// The value written in place of masked fields
+pub const MASK: &str = "XXXXXXXXX";
+
+/// # The action to take on a value matched by a rule
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionAction {
+    /// Replace the value using the policy's replacement, which is `XXXXXXXXX` by default
+    Mask,
+    /// Remove the field from the metadata
+    Drop,
+    /// Replace the value with its HMAC-SHA256 using the pseudonymisation key
+    Hash,
+    /// Replace the value with a constant
+    Replace(Value),
+}
+
+/// # A single redaction rule
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedactionRule {
+    pub pointer: String,
+    pub action: RedactionAction,
+}
+
+/// # The set of rules applied to the metadata json
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct RedactionRules(pub Vec<RedactionRule>);
+
+impl Default for RedactionRules {
+    /// The built-in rules mask the TDR contact fields
+    fn default() -> Self {
+        RedactionRules(vec![
+            RedactionRule {
+                pointer: String::from("/parameters/TDR/Contact-Email"),
+                action: RedactionAction::Mask,
+            },
+            RedactionRule {
+                pointer: String::from("/parameters/TDR/Contact-Name"),
+                action: RedactionAction::Mask,
+            },
+        ])
+    }
+}
+
+impl RedactionRules {
+    /// # Load the rules from a json file
+    pub fn load(rules_file_path: &Path) -> Result<RedactionRules, AnonymiserError> {
+        let rules_json: String = fs::read_to_string(rules_file_path).with_path(rules_file_path)?;
+        let rules: RedactionRules =
+            serde_json::from_str(&rules_json).map_err(|source| AnonymiserError::JsonParse {
+                path: rules_file_path.to_path_buf(),
+                source,
+            })?;
+        match rules.0.iter().find(|rule| !is_valid_pointer(&rule.pointer)) {
+            Some(rule) => Err(AnonymiserError::InvalidPolicy(format!(
+                "'{}' is not a valid json pointer",
+                rule.pointer
+            ))),
+            None => Ok(rules),
+        }
+    }
+
+    /// # Apply the rules to the metadata json
+    ///
+    /// Mask and replace rules with a pointer without wildcards will add the field if it is missing.
+    /// Hash and drop rules only apply to fields which are present.
+    /// Hash rules mask the value if there is no key, so a value is never hashed without one.
+    ///
+    /// Returns the pointers of the values which were changed.
+    pub fn apply(
+        &self,
+        json_value: &mut Value,
+        replacement: &Replacement,
+        hash_key: Option<&PseudonymisationKey>,
+    ) -> Vec<String> {
+        let mut changed_pointers: Vec<String> = Vec::new();
+        for rule in &self.0 {
+            let mut pointers: Vec<String> = matching_pointers(json_value, &rule.pointer);
+            // Dropping array elements shifts the later ones so work backwards
+            pointers.reverse();
+            for pointer in pointers {
+                if apply_action(json_value, &pointer, &rule.action, replacement, hash_key) {
+                    changed_pointers.push(pointer);
+                }
+            }
+        }
+        changed_pointers.sort();
+        changed_pointers.dedup();
+        changed_pointers
+    }
+
+    /// # Whether any rule hashes values, so a key is needed
+    pub fn uses_hash(&self) -> bool {
+        self.0
+            .iter()
+            .any(|rule| rule.action == RedactionAction::Hash)
+    }
+}
+
+/// # Helper function to check a pointer is empty or starts with `/`
+fn is_valid_pointer(pointer: &str) -> bool {
+    pointer.is_empty() || pointer.starts_with('/')
+}
+
+/// # Split a json pointer into its unescaped segments
+fn pointer_segments(pointer: &str) -> Vec<String> {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+/// # Escape a single segment so it can be added to a json pointer
+pub(crate) fn escape_segment(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+/// # Get the json pointer and value of every string and number in a json value
+pub(crate) fn json_values(json_value: &Value) -> Vec<(String, String)> {
+    let mut values: Vec<(String, String)> = Vec::new();
+    collect_json_values(json_value, String::new(), &mut values);
+    values
+}
+
+fn collect_json_values(json_value: &Value, pointer: String, values: &mut Vec<(String, String)>) {
+    match json_value {
+        Value::Object(map) => map.iter().for_each(|(key, child)| {
+            collect_json_values(child, format!("{pointer}/{}", escape_segment(key)), values)
+        }),
+        Value::Array(children) => children.iter().enumerate().for_each(|(index, child)| {
+            collect_json_values(child, format!("{pointer}/{index}"), values)
+        }),
+        Value::String(_) | Value::Number(_) => values.push((pointer, value_as_string(json_value))),
+        Value::Null | Value::Bool(_) => (),
+    }
+}
+
+/// # Expand a pointer which may contain wildcards into the pointers it matches
+///
+/// A pointer without wildcards is returned as it is, whether or not the value exists.
+fn matching_pointers(json_value: &Value, pointer: &str) -> Vec<String> {
+    let segments: Vec<String> = pointer_segments(pointer);
+    if !segments.iter().any(|segment| segment == "*") {
+        return vec![pointer.to_string()];
+    }
+    let mut pointers: Vec<String> = Vec::new();
+    expand_segments(json_value, &segments, String::new(), &mut pointers);
+    pointers
+}
+
+fn expand_segments(json_value: &Value, segments: &[String], prefix: String, out: &mut Vec<String>) {
+    let Some((segment, rest)) = segments.split_first() else {
+        out.push(prefix);
+        return;
+    };
+    let children: Vec<(String, &Value)> = match (segment.as_str(), json_value) {
+        ("*", Value::Object(map)) => map.iter().map(|(k, v)| (k.clone(), v)).collect(),
+        ("*", Value::Array(values)) => values
+            .iter()
+            .enumerate()
+            .map(|(index, v)| (index.to_string(), v))
+            .collect(),
+        (key, Value::Object(map)) => map
+            .get(key)
+            .map(|v| vec![(key.to_string(), v)])
+            .unwrap_or_default(),
+        (index, Value::Array(values)) => index
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| values.get(i))
+            .map(|v| vec![(index.to_string(), v)])
+            .unwrap_or_default(),
+        _ => vec![],
+    };
+    for (key, child) in children {
+        let child_prefix: String = format!("{prefix}/{}", escape_segment(&key));
+        expand_segments(child, rest, child_prefix, out);
+    }
+}
+
+/// # Apply an action to the value at a single pointer
+///
+/// Returns `true` if the metadata was changed.
+fn apply_action(
+    json_value: &mut Value,
+    pointer: &str,
+    action: &RedactionAction,
+    replacement: &Replacement,
+    hash_key: Option<&PseudonymisationKey>,
+) -> bool {
+    match action {
+        RedactionAction::Mask => {
+            let masked_value: Value = replacement.replace(pointer, json_value.pointer(pointer));
+            set_value(json_value, pointer, masked_value)
+        }
+        RedactionAction::Replace(replacement) => {
+            set_value(json_value, pointer, replacement.clone())
+        }
+        RedactionAction::Hash => match json_value.pointer_mut(pointer) {
+            Some(value) => {
+                let hashed: Value = match hash_key {
+                    Some(hash_key) => json!(hash_key.pseudonym(&value_as_string(value))),
+                    None => json!(MASK),
+                };
+                let changed: bool = *value != hashed;
+                *value = hashed;
+                changed
+            }
+            None => false,
+        },
+        RedactionAction::Drop => remove_value(json_value, pointer),
+    }
+}
+
+/// # Helper function to get a string value without the surrounding quotes
+pub(crate) fn value_as_string(value: &Value) -> String {
+    value
+        .as_str()
+        .map(|value| value.to_string())
+        .unwrap_or(value.to_string())
+}
+
+/// # Set the value at the pointer, creating any missing objects on the way
+fn set_value(json_value: &mut Value, pointer: &str, new_value: Value) -> bool {
+    let mut current: &mut Value = json_value;
+    for segment in pointer_segments(pointer) {
+        if current.is_null() {
+            *current = json!({});
+        }
+        current = match current {
+            Value::Object(map) => map.entry(segment).or_insert(Value::Null),
+            Value::Array(values) => match segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| values.get_mut(i))
+            {
+                Some(value) => value,
+                None => return false,
+            },
+            _ => return false,
+        };
+    }
+    let changed: bool = *current != new_value;
+    *current = new_value;
+    changed
+}
+
+/// # Remove the value at the pointer from its parent
+fn remove_value(json_value: &mut Value, pointer: &str) -> bool {
+    let Some((parent_pointer, last)) = pointer.rsplit_once('/') else {
+        return false;
+    };
+    let key: String = last.replace("~1", "/").replace("~0", "~");
+    match json_value.pointer_mut(parent_pointer) {
+        Some(Value::Object(map)) => map.remove(&key).is_some(),
+        Some(Value::Array(values)) => match key.parse::<usize>() {
+            Ok(index) if index < values.len() => {
+                values.remove(index);
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_default_rules_mask_the_contact_fields() {
+        let mut json_value = json!({"parameters": {"TDR": {"Contact-Email": "a@b.com"}}});
+        let changed = RedactionRules::default().apply(&mut json_value, &Replacement::Mask, None);
+        assert_eq!(json_value["parameters"]["TDR"]["Contact-Email"], MASK);
+        assert_eq!(json_value["parameters"]["TDR"]["Contact-Name"], MASK);
+        assert_eq!(
+            changed,
+            vec![
+                "/parameters/TDR/Contact-Email",
+                "/parameters/TDR/Contact-Name"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_rules_from_file() {
+        let rules_dir = TempDir::new().unwrap();
+        let rules_path = rules_dir.join("rules.json");
+        fs::write(
+            &rules_path,
+            r#"[{"pointer": "/a", "action": "drop"}, {"pointer": "/b", "action": {"replace": 1}}]"#,
+        )
+        .unwrap();
+        let rules = RedactionRules::load(&rules_path).unwrap();
+        assert_eq!(
+            rules.0,
+            vec![
+                RedactionRule {
+                    pointer: String::from("/a"),
+                    action: RedactionAction::Drop
+                },
+                RedactionRule {
+                    pointer: String::from("/b"),
+                    action: RedactionAction::Replace(json!(1))
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_rules_rejects_invalid_pointer() {
+        let rules_dir = TempDir::new().unwrap();
+        let rules_path = rules_dir.join("rules.json");
+        fs::write(&rules_path, r#"[{"pointer": "a", "action": "drop"}]"#).unwrap();
+        let err = RedactionRules::load(&rules_path).unwrap_err();
+        assert_eq!(err.to_string(), "'a' is not a valid json pointer");
+    }
+
+    #[test]
+    fn test_apply_drop_hash_and_replace_with_wildcards() {
+        let mut json_value = json!({
+            "people": [{"name": "a", "role": "judge"}, {"name": "b", "role": "party"}],
+            "email": "test@example.com",
+            "other": "unchanged"
+        });
+        let rules = RedactionRules(vec![
+            RedactionRule {
+                pointer: String::from("/people/*/name"),
+                action: RedactionAction::Drop,
+            },
+            RedactionRule {
+                pointer: String::from("/email"),
+                action: RedactionAction::Hash,
+            },
+            RedactionRule {
+                pointer: String::from("/people/1/role"),
+                action: RedactionAction::Replace(json!("test-role")),
+            },
+            RedactionRule {
+                pointer: String::from("/missing"),
+                action: RedactionAction::Hash,
+            },
+        ]);
+        let key = PseudonymisationKey::new(b"test-key").unwrap();
+        let changed = rules.apply(&mut json_value, &Replacement::Mask, Some(&key));
+        assert_eq!(
+            json_value,
+            json!({
+                "people": [{"role": "judge"}, {"role": "test-role"}],
+                "email": key.pseudonym("test@example.com"),
+                "other": "unchanged"
+            })
+        );
+        assert_ne!(
+            json_value["email"],
+            "973dfe463ec85785f5f95af5ba3906eedb2d931c24e69824a89ea65dba4e813b"
+        );
+        assert_eq!(
+            changed,
+            vec![
+                "/email",
+                "/people/0/name",
+                "/people/1/name",
+                "/people/1/role"
+            ]
+        );
+    }
+}
@@ -0,0 +1,725 @@
+//! ## Streaming package processor
+//!
+//! This anonymises a package by reading entries from the input tar.gz and writing the transformed entries
+//! straight to the output tar.gz, so nothing is extracted to disk.
+use crate::archive::{
+    archive_entries, bad_archive, open_archive, read_entries, read_package_index, PackageIndex,
+};
+use crate::audit::ProcessedPackage;
+use crate::error::AnonymiserError;
+use crate::publish::PendingFile;
+use crate::transform::{PackageTransform, WriteEntry};
+use crate::{package_names, PackageNames, Policy};
+use flate2::read::GzDecoder;
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tar::Entry;
+
+/// # Streaming package processor
+///
+/// This makes the same changes as `process_package` and writes the same bytes to the output tar.gz,
+/// but it only needs space for the output file.
+///
+/// The first pass over the input reads the entry paths and the metadata json. If the package has images or attachments,
+/// or the docx is scrubbed, there is a pass to read them, so their checksums can be set in the metadata json before it is written.
+/// The output entries are sorted by path, and the input is read again to copy them in that order.
+/// Packages are usually sorted already, so this is one more pass. An entry which is needed after a later one
+/// is found by reading the input again from the start, so the input is never held in memory.
+///
+/// The audit record is written next to the output tar.gz in the same way.
+/// Both are written to temporary files and only renamed into the output directory once they are complete.
+pub fn process_package_streaming(
+    dir_output: &Path,
+    file: &Path,
+    policy: &Policy,
+) -> Result<ProcessedPackage, AnonymiserError> {
+    let names: PackageNames = package_names(file, &policy.reference_mapping)?;
+    let output_tar_gz_path: PathBuf = dir_output.join(&names.output_tar_gz_file_name);
+    let metadata_path: PathBuf = names
+        .input_batch_reference
+        .folder()
+        .join(names.input_batch_reference.metadata_file_name());
+
+    let PackageIndex {
+        entries,
+        metadata_json_value,
+    } = read_package_index(file, &metadata_path, policy.limits)?;
+    let transform: PackageTransform =
+        PackageTransform::new(names, policy, &entries, metadata_json_value, |paths| {
+            read_entries(file, paths)
+        })?;
+    transform.check()?;
+
+    let output_tar_gz: PendingFile = PendingFile::new(&output_tar_gz_path)?;
+    let mut archive = open_archive(file)?;
+    let mut input_entries = InputEntries {
+        file,
+        entries: archive_entries(&mut archive, file)?,
+        passed: HashSet::new(),
+    };
+    transform.write(
+        output_tar_gz.as_file(),
+        &output_tar_gz_path,
+        |input_path, write_entry| input_entries.with_entry(input_path, write_entry),
+    )?;
+    output_tar_gz.publish()?;
+    transform.audit(file, &output_tar_gz_path)
+}
+
+/// # Finds the input entries in the order the output needs them
+struct InputEntries<'a, I> {
+    file: &'a Path,
+    entries: I,
+    /// The paths of the entries which have been read past
+    passed: HashSet<PathBuf>,
+}
+
+impl<'a, I> InputEntries<'a, I>
+where
+    I: Iterator<Item = Result<Entry<'a, GzDecoder<File>>, AnonymiserError>>,
+{
+    /// # Call `write_entry` with the size and contents of the entry with the path
+    ///
+    /// The entries are read in order. If the entry has already been read past, the input is read again from the start.
+    fn with_entry(
+        &mut self,
+        input_path: &Path,
+        write_entry: &mut WriteEntry,
+    ) -> Result<(), AnonymiserError> {
+        if self.passed.contains(input_path) {
+            let mut archive = open_archive(self.file)?;
+            for entry in archive_entries(&mut archive, self.file)? {
+                let mut entry = entry?;
+                if entry.path().map_err(bad_archive(self.file))? == input_path {
+                    return write_entry(entry.size(), &mut entry);
+                }
+            }
+        } else {
+            for entry in self.entries.by_ref() {
+                let mut entry = entry?;
+                let entry_path: PathBuf =
+                    entry.path().map_err(bad_archive(self.file))?.to_path_buf();
+                if entry_path == input_path {
+                    return write_entry(entry.size(), &mut entry);
+                }
+                self.passed.insert(entry_path);
+            }
+        }
+        Err(AnonymiserError::MissingEntry {
+            path: input_path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allow_list::{AllowList, FileDecision, UnexpectedFileAction};
+    use crate::{process_package, DocxReplacement, ParserLogMode, TextScrambler, XmlReplacement};
+    use assert_fs::TempDir;
+    use image::ImageFormat;
+    use serde_json::Value;
+    use sha256::digest;
+    use testlib::{
+        create_package, create_package_with_files, judgment_xml, read_package_entries, test_docx,
+        test_image, valid_json, valid_json_with_contact_details,
+    };
+
+    /// # Check both processors wrote exactly the same tar.gz
+    fn assert_same_bytes(streamed_tar_path: &Path, extracted_tar_path: &Path) {
+        assert_eq!(
+            std::fs::read(streamed_tar_path).unwrap(),
+            std::fs::read(extracted_tar_path).unwrap()
+        );
+    }
+
+    /// # Process a package with both processors and check they wrote exactly the same tar.gz
+    ///
+    /// Returns the streamed package, then the extracted one.
+    /// The output folders are made next to the tar.gz, so they are removed with its input folder.
+    fn process_both(tar_path: &Path, policy: &Policy) -> (ProcessedPackage, ProcessedPackage) {
+        let output_dir = || {
+            tempfile::tempdir_in(tar_path.parent().unwrap())
+                .unwrap()
+                .into_path()
+        };
+        let extracted = process_package(&output_dir(), tar_path, policy).unwrap();
+        let streamed = process_package_streaming(&output_dir(), tar_path, policy).unwrap();
+        assert_same_bytes(&streamed.output_path, &extracted.output_path);
+        (streamed, extracted)
+    }
+
+    /// # Write a package with the files in the order given and no folder entries
+    fn write_unsorted_package(input_dir: &TempDir, files: &[(&str, &[u8])]) -> PathBuf {
+        let tar_path: PathBuf = input_dir.join("TDR-2023.tar.gz");
+        let tar_gz: File = File::create(&tar_path).unwrap();
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+            tar_gz,
+            flate2::Compression::default(),
+        ));
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, *contents).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+        tar_path
+    }
+
+    /// # The entry paths of a tar.gz, in the order they were written
+    fn entry_names(tar_path: &Path) -> Vec<String> {
+        let mut archive = open_archive(tar_path).unwrap();
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_streaming_output_matches_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package_with_files(
+            &input_dir,
+            valid_json(),
+            None,
+            &[
+                ("TDR-2023.xml", b"<xml/>"),
+                ("parser.log", b"log"),
+                ("images/image1.png", b"png"),
+            ],
+        );
+        let policy = Policy {
+            allow_list: AllowList::new(&[String::from("images/*.png")], UnexpectedFileAction::Fail)
+                .unwrap(),
+            ..Policy::default()
+        };
+        let (streamed, extracted) = process_both(&tar_path, &policy);
+
+        assert_eq!(
+            streamed.output_path.file_name(),
+            extracted.output_path.file_name()
+        );
+        assert_eq!(
+            entry_names(&streamed.output_path),
+            vec![
+                "TST-2023/",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/images/",
+                "TST-2023/images/image1.png",
+                "TST-2023/test.docx"
+            ]
+        );
+        assert_eq!(
+            streamed
+                .output_path
+                .parent()
+                .unwrap()
+                .read_dir()
+                .unwrap()
+                .count(),
+            2,
+            "Only the tar.gz file and audit record are written"
+        );
+    }
+
+    #[test]
+    fn test_streaming_sorts_an_unsorted_package_in_the_same_way_as_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = write_unsorted_package(
+            &input_dir,
+            &[
+                ("TDR-2023/notes/b.txt", b"b"),
+                ("TDR-2023/test.docx", b""),
+                ("TDR-2023/notes/a.txt", b"a"),
+                (
+                    "TDR-2023/TRE-TDR-2023-metadata.json",
+                    valid_json().as_bytes(),
+                ),
+            ],
+        );
+        let policy = Policy {
+            allow_list: AllowList::new(&[], UnexpectedFileAction::Keep).unwrap(),
+            ..Policy::default()
+        };
+        let (streamed, _) = process_both(&tar_path, &policy);
+
+        assert_eq!(
+            entry_names(&streamed.output_path),
+            vec![
+                "TST-2023/",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/notes/",
+                "TST-2023/notes/a.txt",
+                "TST-2023/notes/b.txt",
+                "TST-2023/test.docx"
+            ]
+        );
+        let entries = read_package_entries(&streamed.output_path);
+        assert_eq!(entries["TST-2023/notes/a.txt"], b"a");
+        assert_eq!(entries["TST-2023/notes/b.txt"], b"b");
+    }
+
+    #[test]
+    fn test_streaming_scrubbed_docx_matches_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package_with_files(
+            &input_dir,
+            valid_json(),
+            None,
+            &[("test.docx", &test_docx())],
+        );
+        let policy = Policy {
+            docx: DocxReplacement::Scrub(TextScrambler::new(1)),
+            ..Policy::default()
+        };
+        let (streamed, _) = process_both(&tar_path, &policy);
+
+        let streamed_entries = read_package_entries(&streamed.output_path);
+        assert_ne!(streamed_entries["TST-2023/test.docx"], test_docx());
+    }
+
+    #[test]
+    fn test_streaming_anonymised_xml_matches_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = create_package_with_files(
+            &input_dir,
+            valid_json(),
+            None,
+            &[("TDR-2023.xml", judgment_xml().as_bytes())],
+        );
+        let policy = Policy {
+            xml: XmlReplacement::Anonymise(TextScrambler::new(1)),
+            ..Policy::default()
+        };
+        let (streamed, _) = process_both(&tar_path, &policy);
+
+        let streamed_entries = read_package_entries(&streamed.output_path);
+        assert!(!streamed_entries.contains_key("TST-2023/TDR-2023.xml"));
+        let xml = String::from_utf8(streamed_entries["TST-2023/TST-2023.xml"].clone()).unwrap();
+        assert!(xml.starts_with("<?xml"));
+        assert!(!xml.contains("Smith"));
+    }
+
+    #[test]
+    fn test_streaming_scrubbed_parser_log_matches_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let log: &[u8] = b"WARNING test-name <test@example.com> uploaded TDR-2023\nINFO done\n";
+        let tar_path = create_package_with_files(
+            &input_dir,
+            valid_json_with_contact_details(),
+            None,
+            &[("parser.log", log)],
+        );
+        let policy = Policy {
+            parser_log: ParserLogMode::Scrub,
+            ..Policy::default()
+        };
+        let (streamed, _) = process_both(&tar_path, &policy);
+
+        let streamed_entries = read_package_entries(&streamed.output_path);
+        assert_eq!(
+            streamed_entries["TST-2023/parser.log"],
+            b"WARNING XXXXXXXXX <XXXXXXXXX> uploaded TST-2023\nINFO done\n"
+        );
+    }
+
+    #[test]
+    fn test_streaming_audit_record_matches_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let files: [(&str, &[u8]); 2] = [("TDR-2023.xml", b"<a/>"), ("parser.log", b"log")];
+        let tar_path = create_package_with_files(&input_dir, valid_json(), None, &files);
+        let (streamed, extracted) = process_both(&tar_path, &Policy::default());
+
+        assert_eq!(streamed.audit.removed, extracted.audit.removed);
+        assert_eq!(
+            streamed.audit.removed,
+            vec!["TDR-2023/TDR-2023.xml", "TDR-2023/parser.log"]
+        );
+        assert_eq!(streamed.audit.regenerated, extracted.audit.regenerated);
+        assert_eq!(
+            streamed.audit.metadata_changes,
+            extracted.audit.metadata_changes
+        );
+        assert_eq!(streamed.audit.input_sha256, extracted.audit.input_sha256);
+        assert_eq!(
+            streamed.audit_path,
+            streamed.output_path.with_file_name("TST-2023.audit.json")
+        );
+        assert!(streamed.audit_path.exists());
+    }
+
+    #[test]
+    fn test_streaming_reference_rewrites_match_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json().replace(
+            r#""filename": "test.docx""#,
+            r#""filename": "TDR-2023.docx", "reference": "TDR-2023", "xml": "TDR-2023.xml""#,
+        );
+        let files: [(&str, &[u8]); 1] = [("TDR-2023.docx", b"")];
+        let tar_path = create_package_with_files(&input_dir, &json, None, &files);
+        let (streamed, extracted) = process_both(&tar_path, &Policy::default());
+
+        let entries = read_package_entries(&streamed.output_path);
+        assert!(!entries["TST-2023/TST-2023.docx"].is_empty());
+        assert!(!entries.contains_key("TST-2023/TDR-2023.docx"));
+        let metadata_json: Value =
+            serde_json::from_slice(&entries["TST-2023/TRE-TST-2023-metadata.json"]).unwrap();
+        assert_eq!(
+            metadata_json["parameters"]["TRE"]["payload"],
+            serde_json::json!({"filename": "TST-2023.docx", "reference": "TST-2023", "xml": "TST-2023.xml"})
+        );
+        assert_eq!(
+            streamed.audit.reference_rewrites,
+            extracted.audit.reference_rewrites
+        );
+        assert_eq!(
+            streamed.audit.reference_rewrites,
+            vec![
+                "/parameters/TRE/payload/filename",
+                "/parameters/TRE/payload/reference",
+                "/parameters/TRE/payload/xml"
+            ]
+        );
+        assert_eq!(streamed.audit.regenerated, vec!["TST-2023/TST-2023.docx"]);
+    }
+
+    #[test]
+    fn test_streaming_finds_the_files_from_the_payload() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json().replace(
+            r#""filename": "test.docx""#,
+            r#""filename": "test.docx", "xml": "judgment.xml", "log": "logs/tre.log", "images": ["TDR-2023-image1.png"]"#,
+        );
+        let image: Vec<u8> = test_image(4, 3, ImageFormat::Png);
+        let files: [(&str, &[u8]); 4] = [
+            ("judgment.xml", b"<a/>"),
+            ("TDR-2023.xml", b"<b/>"),
+            ("logs/tre.log", b"log"),
+            ("TDR-2023-image1.png", &image),
+        ];
+        let tar_path = create_package_with_files(&input_dir, &json, None, &files);
+        let policy = Policy {
+            allow_list: AllowList::new(&[], UnexpectedFileAction::Keep).unwrap(),
+            ..Policy::default()
+        };
+        let (streamed, extracted) = process_both(&tar_path, &policy);
+
+        assert_eq!(
+            entry_names(&streamed.output_path),
+            vec![
+                "TST-2023/",
+                "TST-2023/TDR-2023.xml",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/TST-2023-image1.png",
+                "TST-2023/logs/",
+                "TST-2023/test.docx"
+            ]
+        );
+        assert_eq!(streamed.audit.removed, extracted.audit.removed);
+        assert_eq!(
+            streamed.audit.removed,
+            vec!["TDR-2023/judgment.xml", "TDR-2023/logs/tre.log"]
+        );
+    }
+
+    #[test]
+    fn test_streaming_writes_the_docx_if_it_is_missing() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json().replace("test.docx", "missing.docx");
+        let tar_path = create_package(&input_dir, &json, None);
+        let (streamed, _) = process_both(&tar_path, &Policy::default());
+
+        assert_eq!(
+            entry_names(&streamed.output_path),
+            vec![
+                "TST-2023/",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/missing.docx"
+            ]
+        );
+        let entries = read_package_entries(&streamed.output_path);
+        assert!(!entries["TST-2023/missing.docx"].is_empty());
+    }
+
+    #[test]
+    fn test_streaming_error_if_not_a_tar_file() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let tar_path = input_dir.join("TDR-2023.tar.gz");
+        std::fs::write(&tar_path, "test").unwrap();
+        let err =
+            process_package_streaming(&output_dir, &tar_path, &Policy::default()).unwrap_err();
+        assert!(matches!(err, AnonymiserError::BadArchive { .. }));
+    }
+
+    #[test]
+    fn test_streaming_images_and_attachments_match_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let json = valid_json()
+            .replace(
+                r#""filename": "test.docx""#,
+                r#""filename": "test.docx", "images": ["image1.jpg", "image2.png", "image3.gif"]"#,
+            )
+            .replace(
+                r#""name": "test""#,
+                r#""name": "test", "attachments": [{"name": "TDR-2023-annex.docx", "sha256": "abc"}, "annex.pdf"]"#,
+            );
+        let image: Vec<u8> = test_image(40, 30, ImageFormat::Jpeg);
+        let annex: Vec<u8> = test_docx();
+        let files: [(&str, &[u8]); 4] = [
+            ("image1.jpg", &image),
+            ("image2.png", b"png"),
+            ("TDR-2023-annex.docx", &annex),
+            ("annex.pdf", b"pdf"),
+        ];
+        let tar_path = create_package_with_files(&input_dir, &json, None, &files);
+        let (streamed, extracted) = process_both(&tar_path, &Policy::default());
+
+        let entries = read_package_entries(&streamed.output_path);
+        assert_eq!(
+            entry_names(&streamed.output_path),
+            vec![
+                "TST-2023/",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/TST-2023-annex.docx",
+                "TST-2023/image1.jpg",
+                "TST-2023/test.docx"
+            ]
+        );
+        assert_ne!(entries["TST-2023/image1.jpg"], image);
+        assert_ne!(entries["TST-2023/TST-2023-annex.docx"], annex);
+        let metadata_json: Value =
+            serde_json::from_slice(&entries["TST-2023/TRE-TST-2023-metadata.json"]).unwrap();
+        assert_eq!(
+            metadata_json["parameters"]["PARSER"]["attachments"][0],
+            serde_json::json!({
+                "name": "TST-2023-annex.docx",
+                "sha256": digest(&entries["TST-2023/TST-2023-annex.docx"])
+            })
+        );
+
+        let streamed_audit = streamed.audit;
+        assert_eq!(streamed_audit, extracted.audit);
+        assert_eq!(
+            streamed_audit.regenerated,
+            vec![
+                "TST-2023/TST-2023-annex.docx",
+                "TST-2023/image1.jpg",
+                "TST-2023/test.docx"
+            ]
+        );
+        assert_eq!(
+            streamed_audit.removed,
+            vec!["TDR-2023/annex.pdf", "TDR-2023/image2.png"]
+        );
+        assert_eq!(
+            streamed_audit.unexpected,
+            vec![
+                "TDR-2023/image2.png: the image format is not recognised, so it was removed",
+                "TDR-2023/image3.gif: listed in the metadata json but is missing from the package",
+                "TDR-2023/annex.pdf: only docx attachments can be anonymised, so it was removed"
+            ]
+        );
+        assert!(streamed_audit
+            .metadata_changes
+            .contains(&String::from("/parameters/PARSER/attachments/0/sha256")));
+    }
+
+    #[test]
+    fn test_streaming_dropped_files_match_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let files: [(&str, &[u8]); 2] = [("notes.txt", b"notes"), ("TDR-2023.xml", b"<a/>")];
+        let tar_path = create_package_with_files(&input_dir, valid_json(), None, &files);
+        let policy = Policy {
+            allow_list: AllowList::new(&[], UnexpectedFileAction::Drop).unwrap(),
+            ..Policy::default()
+        };
+        let (streamed, extracted) = process_both(&tar_path, &policy);
+
+        let entries = read_package_entries(&streamed.output_path);
+        assert!(!entries.contains_key("TST-2023/notes.txt"));
+        assert_eq!(streamed.audit.removed, extracted.audit.removed);
+        assert_eq!(
+            streamed.audit.removed,
+            vec!["TDR-2023/TDR-2023.xml", "TDR-2023/notes.txt"]
+        );
+        assert_eq!(streamed.audit.files, extracted.audit.files);
+        let decisions: Vec<(&str, FileDecision)> = streamed
+            .audit
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.decision))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                ("TDR-2023/TDR-2023.xml", FileDecision::Expected),
+                (
+                    "TDR-2023/TRE-TDR-2023-metadata.json",
+                    FileDecision::Expected
+                ),
+                ("TDR-2023/notes.txt", FileDecision::Dropped),
+                ("TDR-2023/test.docx", FileDecision::Expected)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_streaming_files_outside_the_package_folder_match_process_package() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = write_unsorted_package(
+            &input_dir,
+            &[
+                (
+                    "TDR-2023/TRE-TDR-2023-metadata.json",
+                    valid_json().as_bytes(),
+                ),
+                ("TDR-2023/test.docx", b""),
+                ("notes.txt", b"notes"),
+            ],
+        );
+        for (action, expected_entry_names, expected_removed) in [
+            (
+                UnexpectedFileAction::Keep,
+                vec![
+                    "TST-2023/",
+                    "TST-2023/TRE-TST-2023-metadata.json",
+                    "TST-2023/test.docx",
+                    "notes.txt",
+                ],
+                vec![],
+            ),
+            (
+                UnexpectedFileAction::Drop,
+                vec![
+                    "TST-2023/",
+                    "TST-2023/TRE-TST-2023-metadata.json",
+                    "TST-2023/test.docx",
+                ],
+                vec!["notes.txt"],
+            ),
+        ] {
+            let policy = Policy {
+                allow_list: AllowList::new(&[], action).unwrap(),
+                ..Policy::default()
+            };
+            let (streamed, extracted) = process_both(&tar_path, &policy);
+
+            assert_eq!(entry_names(&streamed.output_path), expected_entry_names);
+            assert_eq!(streamed.audit, extracted.audit);
+            assert_eq!(streamed.audit.removed, expected_removed);
+        }
+
+        let output_dir = TempDir::new().unwrap();
+        let err =
+            process_package(&output_dir.to_path_buf(), &tar_path, &Policy::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected files in the package: notes.txt"
+        );
+    }
+
+    #[test]
+    fn test_streaming_error_if_there_are_unexpected_files() {
+        let input_dir = TempDir::new().unwrap();
+        let files: [(&str, &[u8]); 1] = [("notes.txt", b"notes")];
+        let tar_path = create_package_with_files(&input_dir, valid_json(), None, &files);
+        let output_dir = TempDir::new().unwrap();
+        let err =
+            process_package_streaming(&output_dir, &tar_path, &Policy::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected files in the package: TDR-2023/notes.txt"
+        );
+        assert_eq!(output_dir.read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_streaming_output_does_not_depend_on_the_input_headers() {
+        let files: [(&str, &[u8]); 1] = [("TDR-2023.xml", b"<xml/>")];
+        let first_input_dir = TempDir::new().unwrap();
+        let first_tar_path =
+            create_package_with_files(&first_input_dir, valid_json(), None, &files);
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let second_input_dir = TempDir::new().unwrap();
+        let second_tar_path =
+            create_package_with_files(&second_input_dir, valid_json(), None, &files);
+        assert_ne!(
+            std::fs::read(&first_tar_path).unwrap(),
+            std::fs::read(&second_tar_path).unwrap()
+        );
+
+        let first_output_dir = TempDir::new().unwrap();
+        let second_output_dir = TempDir::new().unwrap();
+        let first =
+            process_package_streaming(&first_output_dir, &first_tar_path, &Policy::default())
+                .unwrap();
+        let second =
+            process_package_streaming(&second_output_dir, &second_tar_path, &Policy::default())
+                .unwrap();
+        assert_eq!(
+            std::fs::read(&first.output_path).unwrap(),
+            std::fs::read(&second.output_path).unwrap()
+        );
+        let mut archive = open_archive(&first.output_path).unwrap();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), crate::ENTRY_MTIME);
+            assert_eq!(entry.header().username().unwrap(), Some(""));
+        }
+    }
+
+    #[test]
+    fn test_streaming_leaves_nothing_in_the_output_if_writing_fails() {
+        let input_dir = TempDir::new().unwrap();
+        let files: [(&str, &[u8]); 1] = [("TDR-2023.xml", b"<akomaNtoso><p>Name</b>")];
+        let tar_path = create_package_with_files(&input_dir, valid_json(), None, &files);
+        let policy = Policy {
+            xml: XmlReplacement::Anonymise(TextScrambler::new(1)),
+            ..Policy::default()
+        };
+        let output_dir = TempDir::new().unwrap();
+        let err = process_package_streaming(&output_dir, &tar_path, &policy).unwrap_err();
+        assert!(matches!(err, AnonymiserError::XmlParse { .. }));
+        assert_eq!(output_dir.read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_streaming_and_process_package_both_refuse_a_duplicated_entry() {
+        let input_dir = TempDir::new().unwrap();
+        let tar_path = write_unsorted_package(
+            &input_dir,
+            &[
+                (
+                    "TDR-2023/TRE-TDR-2023-metadata.json",
+                    valid_json().as_bytes(),
+                ),
+                ("TDR-2023/test.docx", b"first"),
+                ("TDR-2023/test.docx", b"second"),
+            ],
+        );
+        let extracted_output_dir = TempDir::new().unwrap();
+        let streamed_output_dir = TempDir::new().unwrap();
+        let extracted_err = process_package(
+            &extracted_output_dir.to_path_buf(),
+            &tar_path,
+            &Policy::default(),
+        )
+        .unwrap_err();
+        let streamed_err =
+            process_package_streaming(&streamed_output_dir, &tar_path, &Policy::default())
+                .unwrap_err();
+
+        for err in [&extracted_err, &streamed_err] {
+            assert!(matches!(
+                err,
+                AnonymiserError::DuplicateEntry { entry, .. }
+                    if entry == Path::new("TDR-2023/test.docx")
+            ));
+        }
+        assert_eq!(streamed_output_dir.read_dir().unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,444 @@
+//! ## Package transforms
+//!
+//! Both processors and the dry run plan make the same decisions about a package: which entries are renamed,
+//! dropped or regenerated, and what the metadata json becomes. The transform makes them once, from the entry paths
+//! and the metadata json, and lists every entry of the output package sorted by path.
+//! The processors only differ in where they read the input entries from, so they always write the same bytes.
+use crate::allow_list::{check_file_reports, dropped_files, FileReport};
+use crate::archive::PackageEntries;
+use crate::audit::{AuditRecord, PackageChanges, ProcessedPackage};
+use crate::error::{AnonymiserError, WithPath};
+use crate::media::{anonymise_media, MediaChanges};
+use crate::parser_log::{LogRedactions, ParserLogMode};
+use crate::payload::PackageModel;
+use crate::pii::check_pii_findings;
+use crate::reference::rewrite_references;
+use crate::reproducible::{gz_encoder, normalised_header};
+use crate::xml::{anonymise_xml, XmlReplacement};
+use crate::TextScrambler;
+use crate::{
+    anonymise_metadata_json, files_to_delete, generate_docx, DocxReplacement, PackageNames, Policy,
+};
+use flate2::write::GzEncoder;
+use serde_json::Value;
+use sha256::digest;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tar::{Builder, EntryType, Header};
+
+/// # Calls the function it is given with the size and contents of an input entry
+pub(crate) type WriteEntry<'f> = dyn FnMut(u64, &mut dyn Read) -> Result<(), AnonymiserError> + 'f;
+
+/// # What is written for an entry of the output package
+enum OutputEntry<'a> {
+    Folder,
+    /// New contents, such as the metadata json, the docx and the image placeholders
+    Contents(Vec<u8>),
+    /// A copy of the input entry
+    Copy(PathBuf),
+    /// The input xml, anonymised with the scrambler
+    AnonymisedXml(PathBuf, &'a TextScrambler),
+    /// The input parser log, scrubbed
+    ScrubbedLog(PathBuf),
+}
+
+/// # The changes to a package, decided before anything is written
+pub(crate) struct PackageTransform<'a> {
+    policy: &'a Policy,
+    pub(crate) names: PackageNames,
+    input_model: PackageModel,
+    original_metadata_json_value: Value,
+    metadata_json_value: Value,
+    /// The output path of each input entry, in the order of the input, or `None` if it is left out
+    pub(crate) entries: Vec<(PathBuf, Option<PathBuf>)>,
+    /// The entries of the output package, sorted by path
+    outputs: BTreeMap<PathBuf, OutputEntry<'a>>,
+    pub(crate) changes: PackageChanges,
+}
+
+impl<'a> PackageTransform<'a> {
+    /// # Decide the changes to a package from its entry paths and metadata json
+    ///
+    /// `read` returns the contents of the input entries it is given, leaving out any which aren't in the package.
+    /// It is called once, for the images, the attachments and the docx if it is scrubbed,
+    /// because their new checksums are needed for the metadata json.
+    ///
+    /// Files which aren't allowed and personal data which fail the package are only listed here, so the dry run
+    /// can show them. `check` returns them as an error.
+    pub(crate) fn new(
+        names: PackageNames,
+        policy: &'a Policy,
+        entries: &PackageEntries,
+        mut metadata_json_value: Value,
+        read: impl FnOnce(&[PathBuf]) -> Result<HashMap<PathBuf, Vec<u8>>, AnonymiserError>,
+    ) -> Result<PackageTransform<'a>, AnonymiserError> {
+        let input_folder: &Path = names.input_batch_reference.folder();
+        let output_folder: &Path = names.output_batch_reference.folder();
+        let original_metadata_json_value: Value = metadata_json_value.clone();
+        let input_model: PackageModel =
+            PackageModel::from_metadata(&metadata_json_value, &names.input_batch_reference)?;
+        let reference_rewrites: Vec<String> = rewrite_references(
+            &mut metadata_json_value,
+            &names.input_batch_reference,
+            &names.output_batch_reference,
+        );
+        let output_model: PackageModel =
+            PackageModel::from_metadata(&metadata_json_value, &names.output_batch_reference)?;
+        let files: Vec<FileReport> = policy.allow_list.decide(
+            &entries.file_paths,
+            &names.input_batch_reference,
+            &input_model,
+        );
+
+        let docx_input_path: PathBuf = input_folder.join(&input_model.document);
+        let mut read_paths: Vec<PathBuf> = input_model
+            .images
+            .iter()
+            .chain(&input_model.attachments)
+            .map(|file_name| input_folder.join(file_name))
+            .collect();
+        if let DocxReplacement::Scrub(_) = policy.docx {
+            read_paths.push(docx_input_path.clone());
+        }
+        let contents: HashMap<PathBuf, Vec<u8>> = read(&read_paths)?;
+        let media: MediaChanges = anonymise_media(
+            &names.input_batch_reference,
+            &input_model,
+            &output_model,
+            &metadata_json_value,
+            &policy.docx,
+            |file_name| Ok(contents.get(&input_folder.join(file_name)).cloned()),
+        )?;
+        let (docx_file_name, docx) = generate_docx(
+            &metadata_json_value,
+            &policy.docx,
+            contents.get(&docx_input_path).map(Vec::as_slice),
+        )?;
+        let (metadata_changes, pii_findings) = anonymise_metadata_json(
+            &mut metadata_json_value,
+            digest(&docx),
+            &media.checksums,
+            policy,
+        );
+
+        let deleted_paths: HashSet<PathBuf> = files_to_delete(&input_model, policy)
+            .into_iter()
+            .chain(media.removed.iter().map(String::as_str))
+            .map(|file_name| input_folder.join(file_name))
+            .chain(dropped_files(&files))
+            .collect();
+        let metadata_output_path: PathBuf =
+            output_folder.join(names.output_batch_reference.metadata_file_name());
+        let mut renamed_paths: HashMap<PathBuf, PathBuf> = input_model
+            .renamed_files(&output_model)
+            .into_iter()
+            .map(|(input_file_name, output_file_name)| {
+                (
+                    input_folder.join(input_file_name),
+                    output_folder.join(output_file_name),
+                )
+            })
+            .collect();
+        renamed_paths.insert(
+            input_folder.join(names.input_batch_reference.metadata_file_name()),
+            metadata_output_path.clone(),
+        );
+        let xml_input_path: PathBuf = input_folder.join(&input_model.xml);
+        let parser_log_input_path: PathBuf = input_folder.join(&input_model.log);
+        let file_paths: HashSet<&PathBuf> = entries.file_paths.iter().collect();
+
+        let mut entry_outputs: Vec<(PathBuf, Option<PathBuf>)> = Vec::new();
+        let mut outputs: BTreeMap<PathBuf, OutputEntry<'a>> = BTreeMap::new();
+        let mut removed: Vec<String> = Vec::new();
+        let mut regenerated: Vec<String> = vec![path_string(&output_folder.join(&docx_file_name))];
+        for entry_path in &entries.entry_paths {
+            let is_file: bool = file_paths.contains(entry_path);
+            if is_file && deleted_paths.contains(entry_path) {
+                removed.push(path_string(entry_path));
+                entry_outputs.push((entry_path.clone(), None));
+                continue;
+            }
+            let output_path: PathBuf = match (
+                renamed_paths.get(entry_path),
+                entry_path.strip_prefix(input_folder),
+            ) {
+                (Some(renamed_path), _) => renamed_path.clone(),
+                (None, Ok(relative_path)) => output_folder.join(relative_path),
+                (None, Err(_)) => entry_path.clone(),
+            };
+            let output: OutputEntry = match &policy.xml {
+                _ if !is_file => OutputEntry::Folder,
+                XmlReplacement::Anonymise(scrambler) if *entry_path == xml_input_path => {
+                    regenerated.push(path_string(&output_path));
+                    OutputEntry::AnonymisedXml(entry_path.clone(), scrambler)
+                }
+                _ if *entry_path == parser_log_input_path
+                    && policy.parser_log == ParserLogMode::Scrub =>
+                {
+                    regenerated.push(path_string(&output_path));
+                    OutputEntry::ScrubbedLog(entry_path.clone())
+                }
+                _ => OutputEntry::Copy(entry_path.clone()),
+            };
+            entry_outputs.push((entry_path.clone(), Some(output_path.clone())));
+            outputs.insert(output_path, output);
+        }
+
+        outputs.insert(
+            metadata_output_path,
+            OutputEntry::Contents(metadata_json_value.to_string().into_bytes()),
+        );
+        outputs.insert(
+            output_folder.join(&docx_file_name),
+            OutputEntry::Contents(docx),
+        );
+        for (file_name, contents) in media.replaced {
+            let output_path: PathBuf = output_folder.join(file_name);
+            regenerated.push(path_string(&output_path));
+            outputs.insert(output_path, OutputEntry::Contents(contents));
+        }
+        // Every folder in the output package has an entry, whether or not the input had one
+        let folders: Vec<PathBuf> = outputs
+            .keys()
+            .flat_map(|output_path| output_path.ancestors().skip(1))
+            .filter(|folder| !folder.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .collect();
+        for folder in folders {
+            outputs.entry(folder).or_insert(OutputEntry::Folder);
+        }
+
+        Ok(PackageTransform {
+            policy,
+            names,
+            input_model,
+            original_metadata_json_value,
+            metadata_json_value,
+            entries: entry_outputs,
+            outputs,
+            changes: PackageChanges {
+                metadata_changes,
+                removed,
+                regenerated,
+                reference_rewrites,
+                pii_findings,
+                unexpected: media.unexpected,
+                files,
+            },
+        })
+    }
+
+    /// # Fail if the policy fails packages with the unexpected files or personal data which were found
+    pub(crate) fn check(&self) -> Result<(), AnonymiserError> {
+        check_file_reports(&self.changes.files)?;
+        check_pii_findings(&self.changes.pii_findings, self.policy.pii)
+    }
+
+    /// # Write the output package to `tar_gz`
+    ///
+    /// The entries are sorted by path and their headers are normalised, so the same input and policy always give
+    /// the same tar.gz. `with_entry` calls the function it is given with the size and contents of the input entry
+    /// at a path, and returns a `MissingEntry` error if there isn't one. `tar_path` is only used in errors.
+    pub(crate) fn write(
+        &self,
+        tar_gz: &File,
+        tar_path: &Path,
+        mut with_entry: impl FnMut(&Path, &mut WriteEntry) -> Result<(), AnonymiserError>,
+    ) -> Result<(), AnonymiserError> {
+        let log_redactions = LogRedactions::new(
+            &self.names.input_batch_reference,
+            &self.names.output_batch_reference,
+            &self.original_metadata_json_value,
+            &self.metadata_json_value,
+            &self.changes.metadata_changes,
+            &self.policy.replacement,
+        );
+        let mut tar: Builder<GzEncoder<&File>> = Builder::new(gz_encoder(tar_gz));
+        for (output_path, output) in &self.outputs {
+            match output {
+                OutputEntry::Folder => {
+                    let mut header: Header = normalised_header(EntryType::Directory, 0);
+                    // Folder entries end with a slash
+                    tar.append_data(&mut header, output_path.join(""), io::empty())
+                        .with_path(tar_path)?
+                }
+                OutputEntry::Contents(contents) => {
+                    append_bytes(&mut tar, output_path, contents).with_path(tar_path)?
+                }
+                OutputEntry::Copy(input_path) => with_entry(input_path, &mut |size, contents| {
+                    let mut header: Header = normalised_header(EntryType::Regular, size);
+                    tar.append_data(&mut header, output_path, contents)
+                        .with_path(tar_path)
+                })?,
+                OutputEntry::AnonymisedXml(input_path, scrambler) => {
+                    let xml: Vec<u8> = read_input(input_path, &mut with_entry)?;
+                    let anonymised_xml: Vec<u8> =
+                        anonymise_xml(&self.input_model.xml, &xml, scrambler)?;
+                    append_bytes(&mut tar, output_path, &anonymised_xml).with_path(tar_path)?
+                }
+                OutputEntry::ScrubbedLog(input_path) => {
+                    let log: String = String::from_utf8(read_input(input_path, &mut with_entry)?)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                        .with_path(input_path)?;
+                    let scrubbed_log: String = log_redactions.scrub(&log);
+                    append_bytes(&mut tar, output_path, scrubbed_log.as_bytes())
+                        .with_path(tar_path)?
+                }
+            }
+        }
+        tar.into_inner()
+            .and_then(|encoder| encoder.finish())
+            .with_path(tar_path)?;
+        Ok(())
+    }
+
+    /// # Write the audit record of the changes next to the output tar.gz
+    pub(crate) fn audit(
+        self,
+        input_file: &Path,
+        output_tar_gz_path: &Path,
+    ) -> Result<ProcessedPackage, AnonymiserError> {
+        AuditRecord::new(
+            input_file,
+            output_tar_gz_path,
+            self.names.input_batch_reference.as_str(),
+            self.names.output_batch_reference.as_str(),
+            self.changes,
+            self.policy,
+        )?
+        .write(output_tar_gz_path)
+    }
+}
+
+/// # Helper function to read the whole of an input entry
+fn read_input(
+    input_path: &Path,
+    with_entry: &mut impl FnMut(&Path, &mut WriteEntry) -> Result<(), AnonymiserError>,
+) -> Result<Vec<u8>, AnonymiserError> {
+    let mut contents: Vec<u8> = Vec::new();
+    with_entry(input_path, &mut |_, entry| {
+        entry.read_to_end(&mut contents).with_path(input_path)?;
+        Ok(())
+    })?;
+    Ok(contents)
+}
+
+/// # Helper function to add a file entry with new contents
+fn append_bytes(
+    tar: &mut Builder<GzEncoder<&File>>,
+    path: &Path,
+    contents: &[u8],
+) -> io::Result<()> {
+    let mut header: Header = normalised_header(EntryType::Regular, contents.len() as u64);
+    tar.append_data(&mut header, path, contents)
+}
+
+pub(crate) fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allow_list::{AllowList, UnexpectedFileAction};
+    use crate::archive::open_archive;
+    use crate::{package_names, ReferenceMapping};
+    use assert_fs::TempDir;
+    use testlib::valid_json;
+
+    fn transform<'a>(entry_paths: &[&str], policy: &'a Policy) -> PackageTransform<'a> {
+        let entries = PackageEntries {
+            entry_paths: entry_paths.iter().map(PathBuf::from).collect(),
+            file_paths: entry_paths
+                .iter()
+                .filter(|entry_path| !entry_path.ends_with('/'))
+                .map(PathBuf::from)
+                .collect(),
+        };
+        let names: PackageNames =
+            package_names(Path::new("TDR-2023.tar.gz"), &ReferenceMapping::PrefixSwap).unwrap();
+        let metadata_json_value: Value = serde_json::from_str(valid_json()).unwrap();
+        PackageTransform::new(names, policy, &entries, metadata_json_value, |_| {
+            Ok(HashMap::new())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new_lists_the_output_path_of_each_entry() {
+        let policy = Policy::default();
+        let transform = transform(
+            &[
+                "TDR-2023/",
+                "TDR-2023/TDR-2023.xml",
+                "TDR-2023/TRE-TDR-2023-metadata.json",
+                "TDR-2023/test.docx",
+            ],
+            &policy,
+        );
+        let output_path = |path: &str| Some(PathBuf::from(path));
+        assert_eq!(
+            transform.entries,
+            vec![
+                (PathBuf::from("TDR-2023/"), output_path("TST-2023/")),
+                (PathBuf::from("TDR-2023/TDR-2023.xml"), None),
+                (
+                    PathBuf::from("TDR-2023/TRE-TDR-2023-metadata.json"),
+                    output_path("TST-2023/TRE-TST-2023-metadata.json")
+                ),
+                (
+                    PathBuf::from("TDR-2023/test.docx"),
+                    output_path("TST-2023/test.docx")
+                ),
+            ]
+        );
+        assert_eq!(transform.changes.removed, vec!["TDR-2023/TDR-2023.xml"]);
+        assert_eq!(transform.changes.regenerated, vec!["TST-2023/test.docx"]);
+    }
+
+    #[test]
+    fn test_write_sorts_the_entries_and_adds_the_folders() {
+        let policy = Policy {
+            allow_list: AllowList::new(&[], UnexpectedFileAction::Keep).unwrap(),
+            ..Policy::default()
+        };
+        let transform = transform(
+            &[
+                "TDR-2023/notes/b.txt",
+                "TDR-2023/TRE-TDR-2023-metadata.json",
+                "TDR-2023/notes/a.txt",
+            ],
+            &policy,
+        );
+        let tar_dir = TempDir::new().unwrap();
+        let tar_path: PathBuf = tar_dir.join("TST-2023.tar.gz");
+        let tar_gz: File = File::create(&tar_path).unwrap();
+        transform
+            .write(&tar_gz, &tar_path, |input_path, write_entry| {
+                let contents: &[u8] = input_path.as_os_str().as_encoded_bytes();
+                write_entry(contents.len() as u64, &mut &contents[..])
+            })
+            .unwrap();
+
+        let mut archive = open_archive(&tar_path).unwrap();
+        let entry_names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(
+            entry_names,
+            vec![
+                "TST-2023/",
+                "TST-2023/TRE-TST-2023-metadata.json",
+                "TST-2023/notes/",
+                "TST-2023/notes/a.txt",
+                "TST-2023/notes/b.txt",
+                "TST-2023/test.docx"
+            ]
+        );
+    }
+}
@@ -0,0 +1,552 @@
+//! ## Package verification
+//!
+//! Verification checks an anonymised package against the original one before it is shared.
+//! It looks for the sensitive values of the original package in every entry of the anonymised package,
+//! and checks the metadata checksum matches the docx in the package.
+use crate::archive::{
+    archive_entries, bad_archive, open_archive, read_package_index, EntryChecker, ExtractionLimits,
+    PackageIndex,
+};
+use crate::error::AnonymiserError;
+use crate::reference::ReferenceMapping;
+use crate::rules::json_values;
+use crate::{docx_file_name, package_names, PackageNames, CHECKSUM_POINTER};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::Serialize;
+use serde_json::Value;
+use sha256::digest;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// Values shorter than this are not searched for, as they match too much unrelated text
+const MINIMUM_VALUE_LENGTH: usize = 3;
+
+/// The judgment name from the parser, which is published with the judgment so is kept by the default policy
+const JUDGMENT_NAME_POINTER: &str = "/parameters/PARSER/name";
+
+/// # A sensitive value found in the anonymised package
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Leak {
+    /// The entry in the anonymised package
+    pub file: String,
+    /// Where in the entry the value was found, such as a json pointer, a docx part or a line number
+    pub location: String,
+    /// Where the value came from in the original package. The value itself is never included
+    pub field: String,
+}
+
+/// # The result of comparing the metadata checksum with the docx in the package
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChecksumVerification {
+    pub docx_file: String,
+    /// The checksum in the metadata json
+    pub expected: Option<String>,
+    /// The checksum of the docx in the package, if it is there
+    pub actual: Option<String>,
+    pub matches: bool,
+}
+
+/// # The result of verifying an anonymised package
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct VerificationReport {
+    pub original_file: String,
+    pub anonymised_file: String,
+    /// Whether there are no leaks and the checksum matches
+    pub passed: bool,
+    pub leaks: Vec<Leak>,
+    pub checksum: ChecksumVerification,
+}
+
+impl Display for VerificationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let result: &str = if self.passed { "PASS" } else { "FAIL" };
+        writeln!(
+            f,
+            "Verify {} against {}: {result}",
+            self.anonymised_file, self.original_file
+        )?;
+        for leak in &self.leaks {
+            writeln!(
+                f,
+                "  Leak: {} found in {} at {}",
+                leak.field, leak.file, leak.location
+            )?;
+        }
+        let ChecksumVerification {
+            docx_file,
+            expected,
+            actual,
+            matches,
+        } = &self.checksum;
+        if *matches {
+            writeln!(f, "  Checksum: {docx_file} matches the metadata")
+        } else {
+            writeln!(
+                f,
+                "  Checksum: {docx_file} expected {}, found {}",
+                expected.as_deref().unwrap_or("nothing"),
+                actual.as_deref().unwrap_or("nothing")
+            )
+        }
+    }
+}
+
+/// # A value from the original package which must not be in the anonymised package
+struct SensitiveValue {
+    field: String,
+    /// The value in lower case, so it is found whatever its case
+    value: String,
+}
+
+/// # Verify an anonymised package against the original package
+///
+/// The sensitive values are the contact fields and any names from the parser other than the judgment name
+/// in the original metadata, and the TDR batch reference. Every entry of the anonymised package is checked
+/// against the extraction limits before it is read, and then searched for them:
+///
+/// * Every value of a json file, with the json pointer as the location.
+/// * Every xml part of a docx, with the part name as the location. The text of split runs is joined first.
+/// * Every line of any other text file, with the line number as the location.
+/// * The path of each entry.
+///
+/// The search ignores case. Binary files other than a docx are not searched.
+pub fn verify_package(
+    original: &Path,
+    anonymised: &Path,
+) -> Result<VerificationReport, AnonymiserError> {
+    let PackageNames {
+        input_batch_reference,
+        ..
+    } = package_names(original, &ReferenceMapping::PrefixSwap)?;
+    let original_metadata_path: PathBuf = input_batch_reference
+        .folder()
+        .join(input_batch_reference.metadata_file_name());
+    let PackageIndex {
+        metadata_json_value: original_metadata_json_value,
+        ..
+    } = read_package_index(
+        original,
+        &original_metadata_path,
+        ExtractionLimits::default(),
+    )?;
+    let sensitive_values: Vec<SensitiveValue> = sensitive_values(
+        input_batch_reference.as_str(),
+        &original_metadata_json_value,
+    );
+
+    let PackageNames {
+        input_batch_reference: anonymised_batch_reference,
+        ..
+    } = package_names(anonymised, &ReferenceMapping::PrefixSwap)?;
+    let metadata_path: PathBuf = anonymised_batch_reference
+        .folder()
+        .join(anonymised_batch_reference.metadata_file_name());
+
+    let mut leaks: Vec<Leak> = Vec::new();
+    let mut checksums: HashMap<PathBuf, String> = HashMap::new();
+    let mut metadata_json_value: Option<Value> = None;
+    let mut checker: EntryChecker = EntryChecker::new(anonymised, ExtractionLimits::default());
+    let mut archive = open_archive(anonymised)?;
+    for entry in archive_entries(&mut archive, anonymised)? {
+        let mut entry = entry?;
+        checker.check(&entry)?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let entry_path: PathBuf = entry.path().map_err(bad_archive(anonymised))?.to_path_buf();
+        let mut contents: Vec<u8> = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .map_err(bad_archive(anonymised))?;
+
+        let file: String = entry_path.display().to_string();
+        find_leaks(&sensitive_values, &file, "entry path", &[&file], &mut leaks);
+        let json_value: Option<Value> = if entry_path == metadata_path {
+            let metadata: Value =
+                serde_json::from_slice(&contents).map_err(|source| AnonymiserError::JsonParse {
+                    path: metadata_path.clone(),
+                    source,
+                })?;
+            metadata_json_value = Some(metadata.clone());
+            Some(metadata)
+        } else if is_extension(&entry_path, "json") {
+            serde_json::from_slice(&contents).ok()
+        } else {
+            None
+        };
+        let docx_parts: Option<Vec<(String, Vec<String>)>> = is_extension(&entry_path, "docx")
+            .then(|| docx_part_texts(&contents))
+            .flatten();
+        if let Some(json_value) = &json_value {
+            for (pointer, value) in json_values(json_value) {
+                find_leaks(&sensitive_values, &file, &pointer, &[&value], &mut leaks);
+            }
+        } else if let Some(docx_parts) = docx_parts {
+            for (part_name, texts) in docx_parts {
+                let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+                find_leaks(&sensitive_values, &file, &part_name, &texts, &mut leaks);
+            }
+        } else if let Ok(text) = std::str::from_utf8(&contents) {
+            for (index, line) in text.lines().enumerate() {
+                let location: String = format!("line {}", index + 1);
+                find_leaks(&sensitive_values, &file, &location, &[line], &mut leaks);
+            }
+        }
+        checksums.insert(entry_path, digest(&contents));
+    }
+
+    let metadata_json_value: Value = metadata_json_value.ok_or(AnonymiserError::MissingEntry {
+        path: metadata_path.clone(),
+    })?;
+    let docx_path: PathBuf = anonymised_batch_reference
+        .folder()
+        .join(docx_file_name(&metadata_json_value)?);
+    let expected: Option<String> = metadata_json_value
+        .pointer(CHECKSUM_POINTER)
+        .and_then(|checksum| checksum.as_str())
+        .map(String::from);
+    let actual: Option<String> = checksums.remove(&docx_path);
+    let checksum = ChecksumVerification {
+        docx_file: docx_path.display().to_string(),
+        matches: expected.is_some() && expected == actual,
+        expected,
+        actual,
+    };
+
+    Ok(VerificationReport {
+        original_file: file_name(original),
+        anonymised_file: file_name(anonymised),
+        passed: leaks.is_empty() && checksum.matches,
+        leaks,
+        checksum,
+    })
+}
+
+/// # Find the sensitive values in the original metadata
+///
+/// These are the batch reference, the `Contact-` fields from TDR and any field with a name in it from the parser,
+/// except the judgment name.
+fn sensitive_values(batch_reference: &str, metadata_json_value: &Value) -> Vec<SensitiveValue> {
+    let mut sensitive_values: Vec<SensitiveValue> = vec![SensitiveValue {
+        field: String::from("batch reference"),
+        value: batch_reference.to_lowercase(),
+    }];
+    for (pointer, value) in json_values(metadata_json_value) {
+        let is_contact_field: bool = pointer.starts_with("/parameters/TDR/Contact-");
+        let is_parser_name: bool = pointer
+            .strip_prefix("/parameters/PARSER/")
+            .is_some_and(|field| field.to_lowercase().contains("name"))
+            && pointer != JUDGMENT_NAME_POINTER;
+        if is_contact_field || is_parser_name {
+            sensitive_values.push(SensitiveValue {
+                field: pointer,
+                value: value.to_lowercase(),
+            });
+        }
+    }
+    sensitive_values.retain(|sensitive_value| {
+        sensitive_value.value.trim().chars().count() >= MINIMUM_VALUE_LENGTH
+    });
+    sensitive_values
+}
+
+/// # Add a leak for each sensitive value found in any of the texts of a location
+fn find_leaks(
+    sensitive_values: &[SensitiveValue],
+    file: &str,
+    location: &str,
+    texts: &[&str],
+    leaks: &mut Vec<Leak>,
+) {
+    let texts: Vec<String> = texts.iter().map(|text| text.to_lowercase()).collect();
+    for sensitive_value in sensitive_values {
+        if texts
+            .iter()
+            .any(|text| text.contains(&sensitive_value.value))
+        {
+            leaks.push(Leak {
+                file: file.to_string(),
+                location: location.to_string(),
+                field: sensitive_value.field.clone(),
+            });
+        }
+    }
+}
+
+/// # Get the texts to search in each xml part of a docx
+///
+/// The texts are the raw xml and the unescaped text of the part joined together, so a name split
+/// across runs is still found. Returns `None` if the file is not a valid docx.
+fn docx_part_texts(docx: &[u8]) -> Option<Vec<(String, Vec<String>)>> {
+    let mut archive = ZipArchive::new(Cursor::new(docx)).ok()?;
+    let mut parts: Vec<(String, Vec<String>)> = Vec::new();
+    for index in 0..archive.len() {
+        let mut part = archive.by_index(index).ok()?;
+        if !part.name().ends_with(".xml") && !part.name().ends_with(".rels") {
+            continue;
+        }
+        let mut xml: Vec<u8> = Vec::new();
+        part.read_to_end(&mut xml).ok()?;
+        let text: String = xml_text(&xml);
+        parts.push((
+            part.name().to_string(),
+            vec![String::from_utf8_lossy(&xml).to_string(), text],
+        ));
+    }
+    Some(parts)
+}
+
+/// # Join the unescaped text nodes of an xml document
+///
+/// If the xml can't be parsed, the text read until then is returned.
+fn xml_text(xml: &[u8]) -> String {
+    let mut reader = Reader::from_reader(xml);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut text: String = String::new();
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Text(node)) => match node.unescape() {
+                Ok(node_text) => text.push_str(&node_text),
+                Err(_) => text.push_str(&String::from_utf8_lossy(&node)),
+            },
+            Ok(Event::CData(data)) => text.push_str(&String::from_utf8_lossy(&data)),
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => (),
+        }
+        buffer.clear();
+    }
+    text
+}
+
+fn is_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .is_some_and(|path_extension| path_extension.eq_ignore_ascii_case(extension))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{process_package, DocxReplacement, ParserLogMode, Policy};
+    use crate::{RedactionAction, RedactionRule, RedactionRules, TextScrambler};
+    use assert_fs::TempDir;
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+    use std::fs::File;
+    use tar::{Builder, Header};
+    use testlib::{create_package_with_files, test_docx};
+
+    fn original_json() -> String {
+        json!({"parameters": {
+            "PARSER": {"name": "Smith v Jones"},
+            "TDR": {
+                "Contact-Email": "test@example.com",
+                "Contact-Name": "Test Person",
+                "Document-Checksum-sha256": "abc"
+            },
+            "TRE": {"payload": {"filename": "judgment.docx"}}
+        }})
+        .to_string()
+    }
+
+    fn anonymise(files: &[(&str, &[u8])], policy: &Policy) -> ([TempDir; 2], PathBuf, PathBuf) {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let original = create_package_with_files(&input_dir, &original_json(), None, files);
+        let anonymised = process_package(&output_dir.to_path_buf(), &original, policy)
+            .unwrap()
+            .output_path;
+        ([input_dir, output_dir], original, anonymised)
+    }
+
+    fn scrub_policy() -> Policy {
+        let mut rules: Vec<RedactionRule> = RedactionRules::default().0;
+        rules.push(RedactionRule {
+            pointer: String::from("/parameters/PARSER/name"),
+            action: RedactionAction::Mask,
+        });
+        Policy {
+            rules: RedactionRules(rules),
+            docx: DocxReplacement::Scrub(TextScrambler::new(1)),
+            parser_log: ParserLogMode::Scrub,
+            ..Policy::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_package_passes_a_scrubbed_package() {
+        let docx = test_docx();
+        let files: [(&str, &[u8]); 2] = [
+            ("judgment.docx", &docx),
+            ("parser.log", b"WARNING Test Person uploaded TDR-2023\n"),
+        ];
+        let (_dirs, original, anonymised) = anonymise(&files, &scrub_policy());
+        let report = verify_package(&original, &anonymised).unwrap();
+        assert_eq!(report.leaks, vec![]);
+        assert!(report.checksum.matches);
+        assert!(report.passed);
+        assert_eq!(report.original_file, "TDR-2023.tar.gz");
+        assert_eq!(report.anonymised_file, "TST-2023.tar.gz");
+    }
+
+    #[test]
+    fn test_verify_package_reports_leaks_by_file_and_location() {
+        let files: [(&str, &[u8]); 2] = [
+            ("judgment.docx", b""),
+            (
+                "parser.log",
+                b"INFO done\nWARNING test@EXAMPLE.com uploaded TDR-2023\n",
+            ),
+        ];
+        let (_dirs, original, anonymised) = anonymise(
+            &files,
+            &Policy {
+                parser_log: ParserLogMode::Keep,
+                ..Policy::default()
+            },
+        );
+        let report = verify_package(&original, &anonymised).unwrap();
+        let leak = |file: &str, location: &str, field: &str| Leak {
+            file: file.to_string(),
+            location: location.to_string(),
+            field: field.to_string(),
+        };
+        let mut leaks = report.leaks.clone();
+        leaks.sort_by(|a, b| (&a.file, &a.location).cmp(&(&b.file, &b.location)));
+        assert_eq!(
+            leaks,
+            vec![
+                leak("TST-2023/parser.log", "line 2", "batch reference"),
+                leak(
+                    "TST-2023/parser.log",
+                    "line 2",
+                    "/parameters/TDR/Contact-Email"
+                ),
+            ]
+        );
+        assert!(report.checksum.matches);
+        assert!(!report.passed);
+        assert!(!report.to_string().contains("test@example.com"));
+    }
+
+    #[test]
+    fn test_verify_package_passes_the_output_of_the_default_policy() {
+        let (_dirs, original, anonymised) =
+            anonymise(&[("judgment.docx", &test_docx())], &Policy::default());
+        let report = verify_package(&original, &anonymised).unwrap();
+        assert_eq!(report.leaks, vec![]);
+        assert!(report.checksum.matches);
+        assert!(report.passed);
+    }
+
+    #[test]
+    fn test_verify_package_reports_other_parser_names() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let json = json!({"parameters": {
+            "PARSER": {"name": "Smith v Jones", "judge-name": "Judge Person"},
+            "TDR": {"Document-Checksum-sha256": "abc"},
+            "TRE": {"payload": {"filename": "judgment.docx"}}
+        }})
+        .to_string();
+        let original = create_package_with_files(&input_dir, &json, None, &[]);
+        let anonymised = process_package(&output_dir.to_path_buf(), &original, &Policy::default())
+            .unwrap()
+            .output_path;
+        let report = verify_package(&original, &anonymised).unwrap();
+        let fields: Vec<&str> = report
+            .leaks
+            .iter()
+            .map(|leak| leak.field.as_str())
+            .collect();
+        assert_eq!(fields, vec!["/parameters/PARSER/judge-name"]);
+    }
+
+    #[test]
+    fn test_verify_package_error_if_an_entry_is_a_link() {
+        let input_dir = TempDir::new().unwrap();
+        let original = create_package_with_files(&input_dir, &original_json(), None, &[]);
+        let anonymised = input_dir.join("TST-2023.tar.gz");
+        let tar_gz = GzEncoder::new(File::create(&anonymised).unwrap(), Compression::default());
+        let mut tar = Builder::new(tar_gz);
+        let mut header = Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, "TST-2023/judgment.docx", "/etc/passwd")
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let err = verify_package(&original, &anonymised).unwrap_err();
+        assert!(matches!(err, AnonymiserError::LinkEntry { .. }));
+    }
+
+    /// # Write a tar.gz with the entries, without changing anything in them
+    fn write_package(path: &Path, entries: &[(&str, &[u8])]) {
+        let tar_gz = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        let mut tar = Builder::new(tar_gz);
+        for (entry_path, contents) in entries {
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, entry_path, *contents).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_verify_package_fails_if_the_checksum_does_not_match() {
+        let input_dir = TempDir::new().unwrap();
+        let original = create_package_with_files(&input_dir, &original_json(), None, &[]);
+        let anonymised = input_dir.join("TST-2023.tar.gz");
+        let metadata = json!({"parameters": {
+            "TDR": {"Document-Checksum-sha256": "abc"},
+            "TRE": {"payload": {"filename": "judgment.docx"}}
+        }})
+        .to_string();
+        write_package(
+            &anonymised,
+            &[
+                ("TST-2023/TRE-TST-2023-metadata.json", metadata.as_bytes()),
+                ("TST-2023/judgment.docx", b""),
+            ],
+        );
+
+        let report = verify_package(&original, &anonymised).unwrap();
+        assert_eq!(report.leaks, vec![]);
+        assert_eq!(
+            report.checksum,
+            ChecksumVerification {
+                docx_file: String::from("TST-2023/judgment.docx"),
+                expected: Some(String::from("abc")),
+                actual: Some(digest("")),
+                matches: false,
+            }
+        );
+        assert!(!report.passed);
+        assert!(report
+            .to_string()
+            .contains("Checksum: TST-2023/judgment.docx expected abc, found e3b0"));
+    }
+
+    #[test]
+    fn test_verify_package_error_if_the_metadata_is_missing() {
+        let input_dir = TempDir::new().unwrap();
+        let original = create_package_with_files(&input_dir, &original_json(), None, &[]);
+        let anonymised = input_dir.join("TST-2023.tar.gz");
+        write_package(&anonymised, &[("TST-2023/judgment.docx", b"")]);
+        let err = verify_package(&original, &anonymised).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'TST-2023/TRE-TST-2023-metadata.json' is missing from the package"
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! ## Watch mode
+//!
+//! With `--watch`, the script keeps running and anonymises each tar.gz file as it lands in the input folder,
+//! including any which are already there when it starts.
+//!
+//! The input folder is checked every poll interval. A file is only picked up once its size and modification time
+//! haven't changed for the settle time, so files which are still being copied are left alone.
+//! Each file is processed once, unless it is replaced with a different file of the same name.
+//!
+//! The files are found in the same way as without `--watch`, so `--recursive`, `--include` and `--exclude` apply,
+//! but the output, `processed`, `failed` and `skipped` folders are never searched.
+//!
+//! With `--move-processed`, each file is moved afterwards to the `processed`, `failed` or `skipped` folder in the input folder,
+//! depending on whether it succeeded, failed or was skipped, keeping its path relative to the input folder.
+//! Errors reading the input folder or moving a file are logged and the watch carries on, so a passing problem
+//! with the file system doesn't stop it. A file which failed with an error that might not happen again,
+//! such as running out of disk space, is left in the input folder and tried again at the next poll.
+//! The watch stops between polls once the stop flag is set, so a package is never left half processed.
+use crate::batch::{process_batch, relative_name, BatchOptions, BatchSummary};
+use crate::error::{AnonymiserError, WithPath};
+use crate::input::{find_input_files, InputFilter};
+use crate::Policy;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The folder in the input folder which processed files are moved to
+pub const PROCESSED_FOLDER: &str = "processed";
+
+/// The folder in the input folder which files that failed are moved to
+pub const FAILED_FOLDER: &str = "failed";
+
+/// The folder in the input folder which skipped files are moved to
+pub const SKIPPED_FOLDER: &str = "skipped";
+
+/// How often the stop flag is checked while waiting for the next poll
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// # How the input folder is watched
+#[derive(Clone, Copy, Debug)]
+pub struct WatchOptions {
+    /// How often the input folder is checked for new files
+    pub poll_interval: Duration,
+    /// How long a file must be unchanged before it is processed
+    pub settle_time: Duration,
+    /// Move each file to the processed, failed or skipped folder once it has been processed
+    pub move_processed: bool,
+    /// How each batch of new files is processed
+    pub batch: BatchOptions,
+}
+
+/// # The size and modification time of a file, which change while it is being written
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileState {
+    size: u64,
+    modified: SystemTime,
+}
+
+/// # Tracks the files in the input folder until they are ready to be processed
+#[derive(Default)]
+struct StabilityTracker {
+    /// Files which haven't been processed, with their state and when it was first seen
+    pending: HashMap<PathBuf, (FileState, Instant)>,
+    /// Files which have been processed, with their state when they were
+    processed: HashMap<PathBuf, FileState>,
+}
+
+impl StabilityTracker {
+    /// # Update the tracker with the files in the input folder and return the ones which have settled
+    fn update(
+        &mut self,
+        files: Vec<(PathBuf, FileState)>,
+        now: Instant,
+        settle_time: Duration,
+    ) -> Vec<PathBuf> {
+        let mut pending: HashMap<PathBuf, (FileState, Instant)> = HashMap::new();
+        for (file, state) in files {
+            if self.processed.get(&file) == Some(&state) {
+                continue;
+            }
+            let first_seen: Instant = match self.pending.get(&file) {
+                Some((pending_state, first_seen)) if *pending_state == state => *first_seen,
+                _ => now,
+            };
+            pending.insert(file, (state, first_seen));
+        }
+        self.pending = pending;
+        let mut settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, first_seen))| now.duration_since(*first_seen) >= settle_time)
+            .map(|(file, _)| file.clone())
+            .collect();
+        settled.sort();
+        settled
+    }
+
+    /// # Record that a file has been processed, so it isn't processed again unless it changes
+    fn processed(&mut self, file: &Path) {
+        if let Some((state, _)) = self.pending.remove(file) {
+            self.processed.insert(file.to_path_buf(), state);
+        }
+    }
+}
+
+/// # Watch the input folder and anonymise each new package until `stop` is set
+///
+/// Files which aren't matched by the filter are ignored. A package which fails is logged and doesn't stop the watch,
+/// and neither does an error reading the input folder or moving a file.
+pub fn watch(
+    dir_input: &Path,
+    dir_output: &Path,
+    policy: &Policy,
+    filter: &InputFilter,
+    options: WatchOptions,
+    stop: &AtomicBool,
+) {
+    log::info!("Watching {} for new packages", dir_input.display());
+    let mut tracker: StabilityTracker = StabilityTracker::default();
+    let batch_options = BatchOptions {
+        fail_fast: false,
+        ..options.batch
+    };
+    let ignored: Vec<PathBuf> = vec![
+        dir_output.to_path_buf(),
+        dir_input.join(PROCESSED_FOLDER),
+        dir_input.join(FAILED_FOLDER),
+        dir_input.join(SKIPPED_FOLDER),
+    ];
+    while !stop.load(Ordering::SeqCst) {
+        match package_files(dir_input, filter, &ignored) {
+            Ok(files) => {
+                let settled: Vec<PathBuf> =
+                    tracker.update(files, Instant::now(), options.settle_time);
+                if !settled.is_empty() {
+                    match process_batch(dir_input, dir_output, &settled, policy, batch_options) {
+                        Ok(summary) => {
+                            for file in &settled {
+                                if !is_retryable(dir_input, file, &summary) {
+                                    tracker.processed(file);
+                                }
+                            }
+                            if options.move_processed {
+                                move_processed_files(dir_input, &settled, &summary);
+                            }
+                        }
+                        Err(err) => log::error!("Error: {}", err),
+                    }
+                }
+            }
+            Err(err) => log::error!("Error: {}", err),
+        }
+        let next_poll: Instant = Instant::now() + options.poll_interval;
+        while !stop.load(Ordering::SeqCst) && Instant::now() < next_poll {
+            thread::sleep(STOP_CHECK_INTERVAL.min(options.poll_interval));
+        }
+    }
+    log::info!("Stopped watching {}", dir_input.display());
+}
+
+/// # The packages in the input folder and their states
+fn package_files(
+    dir_input: &Path,
+    filter: &InputFilter,
+    ignored: &[PathBuf],
+) -> Result<Vec<(PathBuf, FileState)>, AnonymiserError> {
+    let mut files: Vec<(PathBuf, FileState)> = Vec::new();
+    for file in find_input_files(dir_input, filter, ignored)?.files {
+        // The file may have been moved or deleted since the folder was read
+        let Ok(metadata) = fs::metadata(&file) else {
+            continue;
+        };
+        let state = FileState {
+            size: metadata.len(),
+            modified: metadata.modified().with_path(&file)?,
+        };
+        files.push((file, state));
+    }
+    Ok(files)
+}
+
+/// # Whether a file failed in the batch with an error which might not happen if it is tried again
+fn is_retryable(dir_input: &Path, file: &Path, summary: &BatchSummary) -> bool {
+    let file_name: String = relative_name(dir_input, file);
+    summary
+        .failed
+        .iter()
+        .any(|failed| failed.retryable && file_name == failed.file)
+}
+
+/// # Move each file to the processed, failed or skipped folder, depending on what happened to it
+///
+/// A file which can't be moved is logged and left where it is,
+/// and so is a file which will be tried again.
+fn move_processed_files(dir_input: &Path, files: &[PathBuf], summary: &BatchSummary) {
+    for file in files {
+        if is_retryable(dir_input, file, summary) {
+            continue;
+        }
+        let file_name: String = relative_name(dir_input, file);
+        let folder: &str = if summary.succeeded.contains(&file_name) {
+            PROCESSED_FOLDER
+        } else if summary.failed.iter().any(|failed| file_name == failed.file) {
+            FAILED_FOLDER
+        } else if summary
+            .skipped
+            .iter()
+            .any(|skipped| file_name == skipped.file)
+        {
+            SKIPPED_FOLDER
+        } else {
+            continue;
+        };
+        if let Err(err) = move_file(file, &dir_input.join(folder).join(&file_name)) {
+            log::error!("Error: {}", err);
+        }
+    }
+}
+
+/// # Move a file, creating the folder it is moved to
+fn move_file(file: &Path, moved_path: &Path) -> Result<(), AnonymiserError> {
+    if let Some(moved_folder) = moved_path.parent() {
+        fs::create_dir_all(moved_folder).with_path(moved_folder)?;
+    }
+    fs::rename(file, moved_path).with_path(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::BatchItem;
+    use assert_fs::TempDir;
+    use std::fs::write;
+    use testlib::{create_package, valid_json};
+
+    fn state(size: u64) -> FileState {
+        FileState {
+            size,
+            modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_stability_tracker_waits_for_files_to_settle() {
+        let mut tracker: StabilityTracker = StabilityTracker::default();
+        let settle_time: Duration = Duration::from_secs(5);
+        let start: Instant = Instant::now();
+        let file: PathBuf = PathBuf::from("TDR-2023.tar.gz");
+
+        assert!(tracker
+            .update(vec![(file.clone(), state(1))], start, settle_time)
+            .is_empty());
+        // The file is still growing, so it has to settle again
+        let grown: Instant = start + Duration::from_secs(4);
+        assert!(tracker
+            .update(vec![(file.clone(), state(2))], grown, settle_time)
+            .is_empty());
+        assert!(tracker
+            .update(
+                vec![(file.clone(), state(2))],
+                start + Duration::from_secs(8),
+                settle_time
+            )
+            .is_empty());
+        let settled: Instant = grown + settle_time;
+        assert_eq!(
+            tracker.update(vec![(file.clone(), state(2))], settled, settle_time),
+            vec![file.clone()]
+        );
+
+        tracker.processed(&file);
+        let later: Instant = settled + Duration::from_secs(60);
+        assert!(tracker
+            .update(vec![(file.clone(), state(2))], later, settle_time)
+            .is_empty());
+        // A new file with the same name is processed again
+        tracker.update(vec![(file.clone(), state(3))], later, settle_time);
+        assert_eq!(
+            tracker.update(
+                vec![(file.clone(), state(3))],
+                later + settle_time,
+                settle_time
+            ),
+            vec![file]
+        );
+    }
+
+    #[test]
+    fn test_watch_processes_new_packages_and_moves_them() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let options = WatchOptions {
+            poll_interval: Duration::from_millis(20),
+            settle_time: Duration::from_millis(50),
+            move_processed: true,
+            batch: BatchOptions::default(),
+        };
+        let stop: AtomicBool = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                watch(
+                    &input_dir,
+                    &output_dir,
+                    &Policy::default(),
+                    &InputFilter::default(),
+                    options,
+                    &stop,
+                )
+            });
+            create_package(&input_dir, valid_json(), None);
+            write(input_dir.join("TDR-2024.tar.gz"), "not a tar.gz").unwrap();
+            write(input_dir.join("notes.txt"), "notes").unwrap();
+            let deadline: Instant = Instant::now() + Duration::from_secs(20);
+            while !input_dir
+                .join(FAILED_FOLDER)
+                .join("TDR-2024.tar.gz")
+                .exists()
+                || !input_dir
+                    .join(PROCESSED_FOLDER)
+                    .join("TDR-2023.tar.gz")
+                    .exists()
+            {
+                assert!(Instant::now() < deadline, "The packages weren't processed");
+                thread::sleep(Duration::from_millis(20));
+            }
+            stop.store(true, Ordering::SeqCst);
+            watcher.join().unwrap();
+        });
+        assert!(output_dir.join("TST-2023.tar.gz").exists());
+        assert!(input_dir.join("notes.txt").exists());
+        assert!(!input_dir.join("TDR-2023.tar.gz").exists());
+    }
+
+    #[test]
+    fn test_move_processed_files_moves_each_file_by_its_outcome() {
+        let input_dir = TempDir::new().unwrap();
+        let files: Vec<PathBuf> = [
+            "TDR-2023.tar.gz",
+            "TDR-2024.tar.gz",
+            "notes.txt",
+            "TDR-2025.tar.gz",
+            "gone.tar.gz",
+        ]
+        .iter()
+        .map(|file_name| input_dir.join(file_name))
+        .collect();
+        for file in &files[..4] {
+            write(file, "").unwrap();
+        }
+        let item = |file: &str| BatchItem {
+            file: String::from(file),
+            reason: String::from("test"),
+            retryable: false,
+        };
+        let summary = BatchSummary {
+            succeeded: vec![String::from("TDR-2023.tar.gz")],
+            failed: vec![
+                item("TDR-2024.tar.gz"),
+                BatchItem {
+                    retryable: true,
+                    ..item("TDR-2025.tar.gz")
+                },
+                item("gone.tar.gz"),
+            ],
+            skipped: vec![item("notes.txt")],
+        };
+        // The missing file is logged and doesn't stop the others being moved
+        move_processed_files(&input_dir, &files, &summary);
+        assert!(input_dir
+            .join(PROCESSED_FOLDER)
+            .join("TDR-2023.tar.gz")
+            .exists());
+        assert!(input_dir
+            .join(FAILED_FOLDER)
+            .join("TDR-2024.tar.gz")
+            .exists());
+        assert!(input_dir.join(SKIPPED_FOLDER).join("notes.txt").exists());
+        assert!(!input_dir.join(PROCESSED_FOLDER).join("notes.txt").exists());
+        // The file which will be tried again is left where it is
+        assert!(input_dir.join("TDR-2025.tar.gz").exists());
+        assert!(is_retryable(&input_dir, &files[3], &summary));
+        assert!(!is_retryable(&input_dir, &files[1], &summary));
+    }
+
+    #[test]
+    fn test_watch_carries_on_if_the_input_folder_cannot_be_read() {
+        let input_dir = TempDir::new().unwrap();
+        let output_dir = TempDir::new().unwrap();
+        let missing_dir: PathBuf = input_dir.join("missing");
+        let options = WatchOptions {
+            poll_interval: Duration::from_millis(20),
+            settle_time: Duration::from_millis(50),
+            move_processed: false,
+            batch: BatchOptions::default(),
+        };
+        let stop: AtomicBool = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                watch(
+                    &missing_dir,
+                    &output_dir,
+                    &Policy::default(),
+                    &InputFilter::default(),
+                    options,
+                    &stop,
+                )
+            });
+            thread::sleep(Duration::from_millis(100));
+            assert!(!watcher.is_finished());
+            fs::create_dir(&missing_dir).unwrap();
+            create_package(&input_dir, valid_json(), None);
+            fs::rename(
+                input_dir.join("TDR-2023.tar.gz"),
+                missing_dir.join("TDR-2023.tar.gz"),
+            )
+            .unwrap();
+            let deadline: Instant = Instant::now() + Duration::from_secs(20);
+            while !output_dir.join("TST-2023.tar.gz").exists() {
+                assert!(Instant::now() < deadline, "The package wasn't processed");
+                thread::sleep(Duration::from_millis(20));
+            }
+            stop.store(true, Ordering::SeqCst);
+            watcher.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,297 @@
+//! ## LegalDocML anonymisation
+//!
+//! The parser writes the judgment as Akoma Ntoso xml. This keeps the element structure and the attributes
+//! which don't identify the judgment, and scrambles the rest.
+use crate::error::AnonymiserError;
+use crate::fake::TextScrambler;
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesCData, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::Path;
+
+/// The elements in the references which describe a person or organisation
+const PERSON_ELEMENTS: [&[u8]; 2] = [b"TLCPerson", b"TLCOrganization"];
+
+/// The schemes of uris without a host, such as `mailto:jane@example.org`, which are kept when the rest is scrambled
+const OPAQUE_SCHEMES: [&str; 2] = ["mailto:", "tel:"];
+
+/// The attribute holding the display name of a person or organisation, such as a party or judge
+const SHOW_AS_ATTRIBUTE: &[u8] = b"showAs";
+
+/// # What happens to the LegalDocML xml in the package
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum XmlReplacement {
+    /// Don't copy the xml to the anonymised package
+    #[default]
+    Delete,
+    /// Keep the structure of the xml and scramble the text, names and identifiers in it
+    Anonymise(TextScrambler),
+}
+
+/// # Anonymise the LegalDocML xml
+///
+/// This scrambles:
+///
+/// * Every text node, which includes the party and judge names in the header.
+/// * The `showAs` display names in the references, which include the names of the people and organisations.
+/// * The `value` attribute of the FRBR elements, which contain the uri and name of the original judgment.
+///   The scheme and host of a uri are kept so it is still a valid uri.
+/// * The ids and hrefs of the people and organisations, which are often made from their names.
+///   Every reference to an id is changed to the same new id.
+/// * The hrefs of the links in the judgment, such as `mailto:` links to a party's email address.
+///   The hrefs of the other references, such as the roles, are kept.
+///
+/// All other elements and attributes are copied unchanged.
+pub fn anonymise_xml(
+    xml_file_name: &str,
+    xml: &[u8],
+    scrambler: &TextScrambler,
+) -> Result<Vec<u8>, AnonymiserError> {
+    person_ids(xml)
+        .and_then(|person_ids| {
+            XmlAnonymiser {
+                scrambler: scrambler.clone(),
+                person_ids,
+                scrambled_ids: HashMap::new(),
+            }
+            .anonymise(xml)
+        })
+        .map_err(|source| AnonymiserError::XmlParse {
+            path: Path::new(xml_file_name).to_path_buf(),
+            source,
+        })
+}
+
+/// # Scrambles the parts of the xml which identify the judgment or the people in it
+struct XmlAnonymiser {
+    scrambler: TextScrambler,
+    /// The ids of the people and organisations in the references
+    person_ids: HashSet<String>,
+    /// The scrambled ids, so every reference to a person gets the same new id
+    scrambled_ids: HashMap<String, String>,
+}
+
+impl XmlAnonymiser {
+    /// # Copy the xml events, scrambling the sensitive ones
+    fn anonymise(&mut self, xml: &[u8]) -> quick_xml::Result<Vec<u8>> {
+        let mut reader = Reader::from_reader(xml);
+        let mut writer = Writer::new(Cursor::new(Vec::new()));
+        let mut buffer: Vec<u8> = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buffer)? {
+                Event::Eof => break,
+                Event::Start(element) => {
+                    writer.write_event(Event::Start(self.anonymise_element(&element)?))?
+                }
+                Event::Empty(element) => {
+                    writer.write_event(Event::Empty(self.anonymise_element(&element)?))?
+                }
+                Event::Text(text) if !is_whitespace(&text) => {
+                    let scrambled: String = scramble_value(&mut self.scrambler, &text.unescape()?);
+                    writer.write_event(Event::Text(BytesText::new(&scrambled)))?
+                }
+                Event::CData(data) => {
+                    let text: String = String::from_utf8_lossy(&data.into_inner()).to_string();
+                    let scrambled: String = self.scrambler.scramble(&text);
+                    writer.write_event(Event::CData(BytesCData::new(scrambled)))?
+                }
+                event => writer.write_event(event)?,
+            }
+            buffer.clear();
+        }
+        Ok(writer.into_inner().into_inner())
+    }
+
+    /// # Copy an element, scrambling the attributes which identify the judgment or the people in it
+    fn anonymise_element<'a>(&mut self, element: &BytesStart) -> quick_xml::Result<BytesStart<'a>> {
+        let name: String = String::from_utf8_lossy(element.name().as_ref()).to_string();
+        let is_frbr_element: bool = element.local_name().as_ref().starts_with(b"FRBR");
+        let is_person_element: bool = PERSON_ELEMENTS.contains(&element.local_name().as_ref());
+        let is_reference_element: bool =
+            element.local_name().as_ref().starts_with(b"TLC") && !is_person_element;
+        let mut anonymised_element: BytesStart = BytesStart::new(name);
+        for attribute in element.attributes() {
+            let attribute: Attribute = attribute?;
+            let value: String = attribute.unescape_value()?.to_string();
+            let anonymised_value: Option<String> = match attribute.key.local_name().into_inner() {
+                SHOW_AS_ATTRIBUTE => Some(scramble_value(&mut self.scrambler, &value)),
+                b"value" if is_frbr_element => Some(scramble_value(&mut self.scrambler, &value)),
+                b"eId" if self.person_ids.contains(&value) => Some(self.scrambled_id(&value)),
+                b"refersTo" => self.scrambled_references(&value),
+                b"href" if value.starts_with('#') => self.scrambled_references(&value),
+                b"href" if !is_reference_element => {
+                    Some(scramble_value(&mut self.scrambler, &value))
+                }
+                _ => None,
+            };
+            match anonymised_value {
+                Some(anonymised_value) => anonymised_element
+                    .push_attribute((attribute.key.as_ref(), anonymised_value.as_bytes())),
+                None => anonymised_element.push_attribute(attribute),
+            }
+        }
+        Ok(anonymised_element)
+    }
+
+    /// # Scramble the references to people in a space separated list like `#smith #jones`
+    ///
+    /// Returns `None` if the list doesn't refer to any people.
+    fn scrambled_references(&mut self, references: &str) -> Option<String> {
+        let mut changed: bool = false;
+        let scrambled: Vec<String> = references
+            .split(' ')
+            .map(|reference| match reference.strip_prefix('#') {
+                Some(id) if self.person_ids.contains(id) => {
+                    changed = true;
+                    format!("#{}", self.scrambled_id(id))
+                }
+                _ => reference.to_string(),
+            })
+            .collect();
+        changed.then(|| scrambled.join(" "))
+    }
+
+    fn scrambled_id(&mut self, id: &str) -> String {
+        if let Some(scrambled_id) = self.scrambled_ids.get(id) {
+            return scrambled_id.clone();
+        }
+        let scrambled_id: String = self.scrambler.scramble(id);
+        self.scrambled_ids
+            .insert(id.to_string(), scrambled_id.clone());
+        scrambled_id
+    }
+}
+
+/// # Find the ids of the people and organisations in the references
+fn person_ids(xml: &[u8]) -> quick_xml::Result<HashSet<String>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut person_ids: HashSet<String> = HashSet::new();
+    loop {
+        match reader.read_event_into(&mut buffer)? {
+            Event::Eof => break,
+            Event::Start(element) | Event::Empty(element)
+                if PERSON_ELEMENTS.contains(&element.local_name().as_ref()) =>
+            {
+                for attribute in element.attributes() {
+                    let attribute: Attribute = attribute?;
+                    if attribute.key.local_name().as_ref() == b"eId" {
+                        person_ids.insert(attribute.unescape_value()?.to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+        buffer.clear();
+    }
+    Ok(person_ids)
+}
+
+/// # Scramble a value, keeping the scheme and host if it is a uri
+///
+/// A uri without a host, such as `mailto:`, keeps its scheme.
+pub(crate) fn scramble_value(scrambler: &mut TextScrambler, value: &str) -> String {
+    if let Some(scheme) = OPAQUE_SCHEMES
+        .iter()
+        .find(|scheme| value.starts_with(*scheme))
+    {
+        return format!("{scheme}{}", scrambler.scramble(&value[scheme.len()..]));
+    }
+    let host_end: Option<usize> = value.find("://").map(|scheme_end| {
+        value[scheme_end + 3..]
+            .find('/')
+            .map_or(value.len(), |path_start| scheme_end + 3 + path_start)
+    });
+    match host_end {
+        Some(host_end) => format!(
+            "{}{}",
+            &value[..host_end],
+            scrambler.scramble(&value[host_end..])
+        ),
+        None => scrambler.scramble(value),
+    }
+}
+
+/// # Helper function to check if a text node only contains whitespace
+fn is_whitespace(text: &BytesText) -> bool {
+    text.iter().all(|byte| byte.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testlib::judgment_xml;
+
+    fn anonymised_judgment() -> String {
+        let xml = anonymise_xml(
+            "TDR-2023.xml",
+            judgment_xml().as_bytes(),
+            &TextScrambler::new(1),
+        )
+        .unwrap();
+        String::from_utf8(xml).unwrap()
+    }
+
+    #[test]
+    fn test_anonymise_xml_scrubs_names_and_identifiers() {
+        let xml = anonymised_judgment();
+        assert!(!xml.to_lowercase().contains("smith"));
+        assert!(!xml.contains("Mr Smith"));
+        assert!(!xml.contains("Jones"));
+        assert!(!xml.contains("ewca/civ/2023/123"));
+        assert!(xml.contains(r#"<FRBRthis value="https://caselaw.nationalarchives.gov.uk/"#));
+        assert!(xml.contains(" &amp; "));
+    }
+
+    #[test]
+    fn test_anonymise_xml_keeps_the_structure_and_other_attributes() {
+        let xml = anonymised_judgment();
+        assert!(xml.starts_with(r#"<?xml version="1.0" encoding="utf-8"?>"#));
+        assert!(xml.contains(r#"<FRBRdate date="2023-01-01" name="judgment"/>"#));
+        assert!(xml.contains(r#"<TLCRole eId="appellant" href="/appellant" "#));
+        assert!(xml.contains(r##" role="#appellant">"##));
+        assert!(xml.contains(r##"<judge refersTo="#judge">"##));
+        assert_eq!(xml.lines().count(), judgment_xml().lines().count());
+        assert_eq!(
+            xml.matches('<').count(),
+            judgment_xml().matches('<').count()
+        );
+    }
+
+    #[test]
+    fn test_anonymise_xml_changes_every_reference_to_a_person_to_the_same_id() {
+        let xml = anonymised_judgment();
+        let person_id = xml
+            .split(r#"<TLCPerson eId=""#)
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert_eq!(person_id.len(), "smith".len());
+        assert!(xml.contains(&format!(r##"<party refersTo="#{person_id}" "##)));
+    }
+
+    #[test]
+    fn test_anonymise_xml_error_if_invalid_xml() {
+        let err = anonymise_xml("TDR-2023.xml", b"<a></b>", &TextScrambler::new(1)).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Cannot parse the xml in TDR-2023.xml: "));
+    }
+
+    #[test]
+    fn test_anonymise_xml_scrambles_links_in_the_judgment() {
+        let judgment: String = judgment_xml().replace(
+            "<judge ",
+            r#"<a href="mailto:mr.smith@example.org">Email</a><a href="https://example.org/smith">Web</a><judge "#,
+        );
+        let xml =
+            anonymise_xml("TDR-2023.xml", judgment.as_bytes(), &TextScrambler::new(1)).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(!xml.contains("mr.smith@example.org"));
+        assert!(!xml.contains("/smith"));
+        assert!(xml.contains(r#"<a href="mailto:"#));
+        assert!(xml.contains(r#"<a href="https://example.org/"#));
+    }
+}
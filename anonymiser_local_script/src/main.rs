@@ -14,55 +14,339 @@
 //! anonymiser --input /path/to/input --output /path/to/output
 //! ```
 //!
+//! To use your own redaction rules for the metadata json instead of the built-in ones
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --rules /path/to/rules.json
+//! ```
+//!
+//! To replace values with a stable keyed hash instead of XXXXXXXXX, use the pseudonymise mode.
+//! The key is read from the file passed in `--key-file` or from the `ANONYMISER_KEY` environment variable.
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --mode pseudonymise --key-file /path/to/key
+//! ```
+//!
+//! Some downstream validation needs values of the right type, such as a valid email address.
+//! The fake mode replaces values with generated ones. The seed makes the output reproducible.
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --mode fake --seed 1
+//! ```
+//!
+//! To see what would be changed in each package without writing anything to the output folder.
+//! A package which can't be planned is listed with its error, and the script exits with status 2 once every package is planned
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --dry-run
+//! anonymiser --input /path/to/input --output /path/to/output --dry-run --format json
+//! ```
+//!
+//! To anonymise each package without extracting it to the output folder first, which needs less disk space.
+//! The tar.gz files are exactly the same as without `--streaming`
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --streaming
+//! ```
+//!
+//! By default, the docx in each package is replaced with a new one containing only the judgment name.
+//! To keep the structure of the original docx and replace its text with scrambled text of the same shape
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --docx scrub --seed 1
+//! ```
+//!
+//! The LegalDocML xml is deleted from each package by default. To keep it with the text, names and judgment uri scrambled
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --xml anonymise
+//! ```
+//!
+//! The parser log is deleted from each package by default. It can be kept unchanged, or kept with the batch references,
+//! metadata values, emails and file paths scrubbed from each line
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --parser-log scrub
+//! anonymiser --input /path/to/input --output /path/to/output --parser-log keep
+//! ```
+//!
+//! The rest of the metadata json is searched for email addresses, UK phone numbers, National Insurance numbers and postcodes.
+//! By default, a warning is logged for each one and it is listed in the audit record. They can be redacted instead,
+//! or the package can be left unprocessed
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --pii redact
+//! anonymiser --input /path/to/input --output /path/to/output --pii fail
+//! ```
+//!
+//! By default, the anonymised package has the same batch reference with `TST` as the source system, so `TDR-2023-ABC`
+//! becomes `TST-2023-ABC`. So the original reference can't be read from the anonymised package, the identifier can be
+//! replaced with a random one, with a keyed hash of the reference using the same key as the pseudonymise mode,
+//! or with the reference from a CSV file of `original,anonymised` lines.
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --reference-mode random
+//! anonymiser --input /path/to/input --output /path/to/output --reference-mode keyed-hash --key-file /path/to/key
+//! anonymiser --input /path/to/input --output /path/to/output --reference-mode csv --reference-csv /path/to/mapping.csv
+//! ```
+//!
+//! Each image listed in the metadata json is replaced with a grey placeholder of the same format and dimensions,
+//! and each docx attachment is regenerated in the same way as the docx. Any other attachment is removed,
+//! and is listed in the `unexpected` field of the audit record along with any listed file which is missing.
+//!
+//! Each package fails if it has files which aren't named in its metadata json. Other files can be allowed with glob patterns
+//! relative to the package folder, and any files which are still unexpected can be dropped or kept instead
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --allow-file 'images/*.png' --allow-file 'notes/**'
+//! anonymiser --input /path/to/input --output /path/to/output --unexpected-files drop
+//! ```
+//!
+//! Each package is checked before it is extracted. Packages with absolute paths, `..` components, links or devices are refused,
+//! and so are packages with more files or larger files than the limits, which can be changed
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --max-total-size 1073741824 --max-entry-size 104857600 --max-entries 1000
+//! ```
+//!
+//! Packages are extracted in a private folder in the system temporary directory, which can be changed with `TMPDIR`,
+//! and it is always removed afterwards. Only the finished tar.gz and audit record are written to the output folder.
+//!
+//! A package which fails doesn't stop the others.
+//! A summary of the packages which succeeded, failed or were skipped, with the reasons, is printed at the end,
+//! and the script exits with status 2 if any package failed. To stop at the first failure with status 1 instead
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --fail-fast
+//! ```
+//!
+//! To process several packages at once. Each log line is then tagged with the worker thread which wrote it, such as
+//! `[anonymiser_lib::batch@anonymiser-worker-1]`, and the summary is still in the order of the input files
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --jobs 4
+//! ```
+//!
+//! To keep running and anonymise each tar.gz file as it lands in the input folder. A file is only picked up once it hasn't
+//! changed for the settle time, so files which are still being copied are left alone. Each file can be moved to the
+//! `processed`, `failed` or `skipped` folder in the input folder afterwards. Ctrl-C stops the watch once the current packages have finished
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --watch --move-processed
+//! anonymiser --input /path/to/input --output /path/to/output --watch --poll-interval 10 --settle-time 30
+//! ```
+//!
+//! An audit record is written next to each anonymised package, so `TST-xxx.tar.gz` has a `TST-xxx.audit.json`.
+//! It lists the input and output checksums, the metadata fields, files changed and the policy used, without any original values.
+//!
+//! To check an anonymised package doesn't contain the contact details, judgment name or batch reference of the original,
+//! and that its checksum matches the docx. The command exits with a non zero status if the check fails.
+//! ```bash
+//! anonymiser verify /path/to/input/TDR-2023.tar.gz /path/to/output/TST-2023.tar.gz
+//! anonymiser verify /path/to/input/TDR-2023.tar.gz /path/to/output/TST-2023.tar.gz --format json
+//! ```
+//!
+//! By default, only the `*.tar.gz` files at the top of the input folder are processed, and any other files are skipped
+//! and listed in the summary. To search its subfolders too, and choose the files with glob patterns relative to the input folder.
+//! With `--mirror`, each package is written to the same subfolder of the output folder as it is in the input folder.
+//! Without it, packages in different subfolders with the same file name would overwrite each other, so only the first one is processed
+//! and the others fail.
+//! ```bash
+//! anonymiser --input /path/to/input --output /path/to/output --recursive --mirror
+//! anonymiser --input /path/to/input --output /path/to/output --recursive --include '2023/**/*.tar.gz' --exclude '*/old/*'
+//! ```
+//!
 //! ## Running with docker
 //! ```bash
 //! docker run -v /path/to/input:/input -v /path/to/output:/output public.ecr.aws/u4s1g5v1/anonymiser
 //! ```
 //!
-//! The input path must only contain the tar.gz files you're converting.
+//! Any files in the input path which aren't tar.gz files are skipped and listed in the summary.
 //!
 use anonymiser_lib::*;
 use clap::Parser;
 use log::{self, LevelFilter};
+use serde_json::json;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use simple_logger::SimpleLogger;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{path::PathBuf, process::exit};
 
-/// # The input files and output directory
+/// The exit status if any package in the batch failed. Other errors exit with 1
+const EXIT_PACKAGES_FAILED: i32 = 2;
+
+/// # The input files and the input and output directories
 struct Files {
+    dir_input: PathBuf,
     dir_output: PathBuf,
     files: Vec<PathBuf>,
+    /// The files in the input directory which aren't processed, and why
+    skipped: Vec<BatchItem>,
+}
+
+/// # Expand the home folder and environment variables in the input or output argument
+fn expand_path(path: &Option<String>) -> PathBuf {
+    let path: &str = path.as_deref().expect("The input and output are required");
+    PathBuf::from(shellexpand::full(path).unwrap().to_string())
 }
 
 /// # Process the input arguments
 ///
-/// Returns the `Files` struct with a list of files in the input directory and the output directory as a `PathBuf` struct.
-fn files_from_input_arguments(opt: Opt) -> Files {
-    let dir_input: PathBuf = PathBuf::from(shellexpand::full(&opt.input).unwrap().to_string());
-    let dir_output: PathBuf = PathBuf::from(shellexpand::full(&opt.output).unwrap().to_string());
-    let files = files_in_input_dir(&dir_input).unwrap();
-    Files { dir_output, files }
+/// Returns the `Files` struct with the input and output directories, the packages found in the input directory
+/// and the files which were skipped. The output directory is never searched.
+/// Clap makes sure the input and output are set unless a subcommand is used.
+fn files_from_input_arguments(opt: &Opt) -> Result<Files, AnonymiserError> {
+    let dir_input: PathBuf = expand_path(&opt.input);
+    let dir_output: PathBuf = expand_path(&opt.output);
+    let input_files: InputFiles = find_input_files(
+        &dir_input,
+        &opt.input_filter()?,
+        std::slice::from_ref(&dir_output),
+    )?;
+    for skipped in &input_files.skipped {
+        log::warn!("Skipped {}: {}", skipped.file, skipped.reason);
+    }
+    Ok(Files {
+        dir_input,
+        dir_output,
+        files: input_files.files,
+        skipped: input_files.skipped,
+    })
+}
+
+/// # Print the plan for each package without processing it
+///
+/// A package which can't be planned is printed with its error instead of a plan, and doesn't stop the others.
+/// Exits with a non zero status once every package is printed if any of them couldn't be planned.
+fn print_plans(files: &[PathBuf], policy: &Policy, format: OutputFormat) {
+    let plans: Vec<(String, Result<PackagePlan, AnonymiserError>)> = files
+        .iter()
+        .map(|file| {
+            let file_name: String = file
+                .file_name()
+                .map(|file_name| file_name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            (file_name, plan_package(file, policy))
+        })
+        .collect();
+    match format {
+        OutputFormat::Text => {
+            for (file_name, plan) in &plans {
+                match plan {
+                    Ok(plan) => print!("{plan}"),
+                    Err(err) => print!("Package {file_name}\n  Error: {err}\n"),
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let plans: Vec<serde_json::Value> = plans
+                .iter()
+                .map(|(file_name, plan)| match plan {
+                    Ok(plan) => serde_json::to_value(plan).unwrap(),
+                    Err(err) => json!({"input_file": file_name, "error": err.to_string()}),
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&plans).unwrap());
+        }
+    }
+    if plans.iter().any(|(_, plan)| plan.is_err()) {
+        exit(EXIT_PACKAGES_FAILED);
+    }
+}
+
+/// # Verify an anonymised package and print the report
+///
+/// Exits with a non zero status if the package fails verification.
+fn verify(original: &str, anonymised: &str, format: OutputFormat) {
+    let expand = |path: &str| PathBuf::from(shellexpand::full(path).unwrap().to_string());
+    let report: VerificationReport = verify_package(&expand(original), &expand(anonymised))
+        .unwrap_or_else(|err| {
+            log::error!("Error: {}", err);
+            exit(1);
+        });
+    match format {
+        OutputFormat::Text => print!("{report}"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+    }
+    if !report.passed {
+        exit(1);
+    }
+}
+
+/// # Watch the input folder until the script is interrupted
+///
+/// The first SIGINT or SIGTERM stops the watch once the current packages have finished.
+fn watch_input(opt: &Opt, files: &Files, policy: &Policy, batch: BatchOptions) {
+    let stop: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop)).unwrap();
+    }
+    let options = WatchOptions {
+        poll_interval: Duration::from_secs(opt.poll_interval),
+        settle_time: Duration::from_secs(opt.settle_time),
+        move_processed: opt.move_processed,
+        batch,
+    };
+    let filter: InputFilter = opt.input_filter().unwrap_or_else(|err| {
+        log::error!("Error: {}", err);
+        exit(1);
+    });
+    watch(
+        &files.dir_input,
+        &files.dir_output,
+        policy,
+        &filter,
+        options,
+        &stop,
+    );
 }
 
 /// # The entrypoint for the anonymiser script
 fn main() {
+    let opt: Opt = Opt::parse();
     SimpleLogger::new()
         .with_level(LevelFilter::Info)
+        .with_threads(opt.jobs.get() > 1)
         .init()
         .unwrap();
-    let files_from_input = files_from_input_arguments(Opt::parse());
-    for file in files_from_input.files {
-        match process_package(&files_from_input.dir_output, &file) {
-            Ok(_) => {
-                log::info!(
-                    "Processed {}",
-                    file.file_name().and_then(|name| name.to_str()).unwrap()
-                )
-            }
-            Err(err) => {
-                log::error!("Error: {:?}", err);
-                exit(1);
-            }
-        };
+    if let Some(Command::Verify {
+        original,
+        anonymised,
+    }) = &opt.command
+    {
+        verify(original, anonymised, opt.format);
+        return;
+    }
+    let policy: Policy = opt.policy().unwrap_or_else(|err| {
+        log::error!("Error: {}", err);
+        exit(1);
+    });
+    let files_from_input: Files = files_from_input_arguments(&opt).unwrap_or_else(|err| {
+        log::error!("Error: {}", err);
+        exit(1);
+    });
+    if opt.dry_run {
+        print_plans(&files_from_input.files, &policy, opt.format);
+        return;
+    }
+    let options = BatchOptions {
+        streaming: opt.streaming,
+        fail_fast: opt.fail_fast,
+        jobs: opt.jobs,
+        mirror: opt.mirror,
+    };
+    if opt.watch {
+        watch_input(&opt, &files_from_input, &policy, options);
+        return;
+    }
+    let mut summary: BatchSummary = process_batch(
+        &files_from_input.dir_input,
+        &files_from_input.dir_output,
+        &files_from_input.files,
+        &policy,
+        options,
+    )
+    .unwrap_or_else(|err| {
+        log::error!("Error: {}", err);
+        exit(1);
+    });
+    summary.skipped.extend(files_from_input.skipped);
+    summary
+        .skipped
+        .sort_by(|first, second| first.file.cmp(&second.file));
+    match opt.format {
+        OutputFormat::Text => print!("{summary}"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+    }
+    if summary.has_failures() {
+        exit(EXIT_PACKAGES_FAILED);
     }
 }
 
@@ -71,13 +355,14 @@ mod test {
     use crate::files_from_input_arguments;
     use anonymiser_lib::Opt;
     use assert_fs::TempDir;
+    use clap::Parser;
     use std::fs::write;
     use std::path::{Path, PathBuf};
 
     #[test]
     fn test_files_can_be_retrieved_from_input_arguments() {
         let input_dir = TempDir::new().unwrap();
-        let test_file_names = ["file1", "file2", "file3"];
+        let test_file_names = ["file1.tar.gz", "file2.tar.gz", "file3.tar.gz", "notes.txt"];
         let _ = test_file_names.map(|file_name| {
             let file_path = input_dir.join(PathBuf::from(file_name));
             write(file_path.clone(), "".as_bytes()).unwrap();
@@ -85,8 +370,10 @@ mod test {
         });
         let input = input_dir.to_str().unwrap().to_string();
         let output = TempDir::new().unwrap().to_str().unwrap().to_string();
-        let opt = Opt { input, output };
-        let files_result = files_from_input_arguments(opt);
+        let opt = Opt::parse_from(["anonymiser", "--input", &input, "--output", &output]);
+        let files_result = files_from_input_arguments(&opt).unwrap();
+        assert_eq!(files_result.skipped.len(), 1);
+        assert_eq!(files_result.skipped[0].file, "notes.txt");
         let mut files = files_result.files;
 
         fn get_file_name(file_path: &Path) -> &str {
@@ -99,8 +386,8 @@ mod test {
         let _ = &files.sort();
 
         assert_eq!(files.len(), 3);
-        assert_eq!(get_file_name(&files[0]), "file1");
-        assert_eq!(get_file_name(&files[1]), "file2");
-        assert_eq!(get_file_name(&files[2]), "file3")
+        assert_eq!(get_file_name(&files[0]), "file1.tar.gz");
+        assert_eq!(get_file_name(&files[1]), "file2.tar.gz");
+        assert_eq!(get_file_name(&files[2]), "file3.tar.gz")
     }
 }
@@ -19,15 +19,9 @@ fn creates_a_valid_test_package() -> Result<(), Box<dyn std::error::Error>> {
         .arg(output_dir.path().to_str().unwrap());
     cmd.assert().success();
 
-    let output_tar_gz: PathBuf = output_dir
-        .read_dir()
-        .unwrap()
-        .last()
-        .unwrap()
-        .unwrap()
-        .path();
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
     decompress_test_file(&output_tar_gz, &output_dir);
-    let metadata_json = get_metadata_json_fields(&output_dir.to_owned());
+    let metadata_json = get_metadata_json_fields(&output_dir);
     assert_eq!(metadata_json.contact_email, "XXXXXXXXX");
     assert_eq!(metadata_json.contact_name, "XXXXXXXXX");
     assert_eq!(
@@ -41,7 +35,7 @@ fn creates_a_valid_test_package() -> Result<(), Box<dyn std::error::Error>> {
 fn error_if_invalid_tar_file() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd: Command = Command::cargo_bin("anonymiser")?;
     let input_dir: TempDir = TempDir::new().unwrap();
-    write(input_dir.join(Path::new("test.tar.gz")), "").unwrap();
+    write(input_dir.join(Path::new("TDR-2023.tar.gz")), "").unwrap();
     let output_dir: TempDir = TempDir::new().unwrap();
     cmd.arg("--input")
         .arg(input_dir.path().to_str().unwrap())
@@ -78,7 +72,7 @@ fn error_if_filename_batch_id_mismatch() -> Result<(), Box<dyn std::error::Error
     create_package(
         &input_dir,
         valid_json(),
-        Some(String::from("INVALID-BATCH")),
+        Some(String::from("TDR-2024.tar.gz")),
     );
     let output_dir: TempDir = TempDir::new().unwrap();
     cmd.arg("--input")
@@ -91,3 +85,859 @@ fn error_if_filename_batch_id_mismatch() -> Result<(), Box<dyn std::error::Error
         .stdout(predicate::str::contains("No such file or directory"));
     Ok(())
 }
+
+#[test]
+fn error_if_package_name_is_malformed() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(
+        &input_dir,
+        valid_json(),
+        Some(String::from("TDR-2023.v2.tar.gz")),
+    );
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap());
+
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "TDR-2023.v2.tar.gz': The year '2023.v2' must be four digits",
+    ));
+    Ok(())
+}
+
+#[test]
+fn applies_the_rules_file() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let rules_dir: TempDir = TempDir::new().unwrap();
+    let rules_path: PathBuf = rules_dir.join("rules.json");
+    write(
+        &rules_path,
+        r#"[
+          {"pointer": "/parameters/TDR/Contact-Email", "action": {"replace": "test@example.com"}},
+          {"pointer": "/parameters/TDR/Contact-Name", "action": "mask"}
+        ]"#,
+    )
+    .unwrap();
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--rules")
+        .arg(rules_path.to_str().unwrap());
+    cmd.assert().success();
+
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
+    decompress_test_file(&output_tar_gz, &output_dir);
+    let metadata_json = get_metadata_json_fields(&output_dir);
+    assert_eq!(metadata_json.contact_email, "test@example.com");
+    assert_eq!(metadata_json.contact_name, "XXXXXXXXX");
+    Ok(())
+}
+
+#[test]
+fn error_if_rules_file_is_invalid() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let rules_path: PathBuf = input_dir.join("rules.json");
+    write(&rules_path, r#"[{"pointer": "/a", "action": "unknown"}]"#).unwrap();
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--rules")
+        .arg(rules_path.to_str().unwrap());
+
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("unknown variant `unknown`"));
+    Ok(())
+}
+
+#[test]
+fn pseudonymises_with_the_key_from_the_environment() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json_with_contact_details(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.env("ANONYMISER_KEY", "test-key")
+        .arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--mode")
+        .arg("pseudonymise");
+    cmd.assert().success();
+
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
+    decompress_test_file(&output_tar_gz, &output_dir);
+    let metadata_json = get_metadata_json_fields(&output_dir);
+    assert_eq!(
+        metadata_json.contact_email,
+        "d14d5a9ddcc8da5e05d99c4792ac3d2fc7842d73fb63711666d64b82c66f5969"
+    );
+    assert_eq!(
+        metadata_json.contact_name,
+        "e3609ac3b8258cd3e041d69021aa7847f30e973532566346c213f69dbbf21027"
+    );
+    Ok(())
+}
+
+#[test]
+fn error_if_pseudonymising_without_a_key() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.env_remove("ANONYMISER_KEY")
+        .arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--mode")
+        .arg("pseudonymise");
+
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "A key file or the ANONYMISER_KEY environment variable is needed to pseudonymise",
+    ));
+    Ok(())
+}
+
+#[test]
+fn hashes_with_the_key_and_fails_without_one() -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json_with_contact_details(), None);
+    let rules_dir: TempDir = TempDir::new().unwrap();
+    let rules_path: PathBuf = rules_dir.join("rules.json");
+    write(
+        &rules_path,
+        r#"[{"pointer": "/parameters/TDR/Contact-Name", "action": "hash"}]"#,
+    )?;
+    let output_dir: TempDir = TempDir::new().unwrap();
+    let anonymise = || -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+        cmd.arg("--input")
+            .arg(input_dir.path().to_str().unwrap())
+            .arg("--output")
+            .arg(output_dir.path().to_str().unwrap())
+            .arg("--rules")
+            .arg(rules_path.to_str().unwrap());
+        Ok(cmd)
+    };
+
+    anonymise()?
+        .env_remove("ANONYMISER_KEY")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "A key file or the ANONYMISER_KEY environment variable is needed",
+        ));
+    assert_eq!(output_dir.read_dir().unwrap().count(), 0);
+
+    anonymise()?
+        .env("ANONYMISER_KEY", "test-key")
+        .assert()
+        .success();
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
+    decompress_test_file(&output_tar_gz, &output_dir);
+    let metadata_json = get_metadata_json_fields(&output_dir);
+    assert_eq!(
+        metadata_json.contact_name,
+        "e3609ac3b8258cd3e041d69021aa7847f30e973532566346c213f69dbbf21027"
+    );
+    Ok(())
+}
+
+#[test]
+fn writes_fake_values_for_the_seed() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json_with_contact_details(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--mode")
+        .arg("fake")
+        .arg("--seed")
+        .arg("1");
+    cmd.assert().success();
+
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
+    decompress_test_file(&output_tar_gz, &output_dir);
+    let metadata_json = get_metadata_json_fields(&output_dir);
+    assert!(metadata_json.contact_email.ends_with("@example.com"));
+    assert_ne!(metadata_json.contact_name, "test-name");
+    assert_ne!(metadata_json.contact_name, "XXXXXXXXX");
+    Ok(())
+}
+
+#[test]
+fn dry_run_prints_the_plan_without_writing() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--dry-run");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Batch reference: TDR-2023 -> TST-2023",
+        ))
+        .stdout(predicate::str::contains("Regenerate: TST-2023/test.docx"))
+        .stdout(predicate::str::contains(
+            "Change metadata: /parameters/TDR/Contact-Email",
+        ));
+    assert_eq!(output_dir.read_dir().unwrap().count(), 0);
+    Ok(())
+}
+
+#[test]
+fn dry_run_lists_packages_which_cannot_be_planned() -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir: TempDir = TempDir::new().unwrap();
+    write(input_dir.join("TDR-2022.tar.gz"), "not a tar.gz")?;
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    let dry_run = |format: &str| -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+        cmd.arg("--input")
+            .arg(input_dir.path().to_str().unwrap())
+            .arg("--output")
+            .arg(output_dir.path().to_str().unwrap())
+            .args(["--dry-run", "--format", format]);
+        Ok(cmd)
+    };
+
+    dry_run("text")?
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains(
+            "Package TDR-2022.tar.gz\n  Error: Cannot read the archive",
+        ))
+        .stdout(predicate::str::contains(
+            "Batch reference: TDR-2023 -> TST-2023",
+        ));
+    let output = dry_run("json")?
+        .assert()
+        .code(2)
+        .get_output()
+        .stdout
+        .clone();
+    let plans: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(plans[0]["input_file"], "TDR-2022.tar.gz");
+    assert!(plans[0]["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Cannot read the archive"));
+    assert_eq!(plans[1]["output_file"], "TST-2023.tar.gz");
+    assert_eq!(output_dir.read_dir().unwrap().count(), 0);
+    Ok(())
+}
+
+#[test]
+fn dry_run_prints_json() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--dry-run")
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let plans: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(plans[0]["output_file"], "TST-2023.tar.gz");
+    assert_eq!(plans[0]["regenerated"][0], "TST-2023/test.docx");
+    assert_eq!(output_dir.read_dir().unwrap().count(), 0);
+    Ok(())
+}
+
+#[test]
+fn creates_a_valid_test_package_when_streaming() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--streaming");
+    cmd.assert().success();
+
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
+    decompress_test_file(&output_tar_gz, &output_dir);
+    let metadata_json = get_metadata_json_fields(&output_dir);
+    assert_eq!(metadata_json.contact_email, "XXXXXXXXX");
+    assert_eq!(
+        metadata_json.checksum,
+        "9330f5cb8b67a81d3bfdedc5b9f5b84952a2c0d2f76a3208b84901febdf4db6a"
+    );
+    Ok(())
+}
+
+#[test]
+fn scrubs_the_original_docx() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package_with_files(
+        &input_dir,
+        valid_json(),
+        None,
+        &[("test.docx", &test_docx())],
+    );
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--docx")
+        .arg("scrub");
+    cmd.assert().success();
+
+    let output_tar_gz: PathBuf = output_dir.join("TST-2023.tar.gz");
+    let entries = read_package_entries(&output_tar_gz);
+    let docx: &Vec<u8> = &entries["TST-2023/test.docx"];
+    assert_ne!(docx, &test_docx());
+    assert_eq!(&docx[..2], b"PK");
+    Ok(())
+}
+
+#[test]
+fn anonymises_the_xml() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package_with_files(
+        &input_dir,
+        valid_json(),
+        None,
+        &[("TDR-2023.xml", judgment_xml().as_bytes())],
+    );
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--xml")
+        .arg("anonymise");
+    cmd.assert().success();
+
+    let entries = read_package_entries(&output_dir.join("TST-2023.tar.gz"));
+    let xml: &str = std::str::from_utf8(&entries["TST-2023/TST-2023.xml"])?;
+    assert!(xml.contains("<akomaNtoso"));
+    assert!(!xml.contains("Smith"));
+    Ok(())
+}
+
+#[test]
+fn keeps_the_parser_log_unchanged() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let log: &[u8] = b"WARNING test@example.com TDR-2023\n";
+    create_package_with_files(&input_dir, valid_json(), None, &[("parser.log", log)]);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--parser-log")
+        .arg("keep");
+    cmd.assert().success();
+
+    let entries = read_package_entries(&output_dir.join("TST-2023.tar.gz"));
+    assert_eq!(entries["TST-2023/parser.log"], log);
+    Ok(())
+}
+
+#[test]
+fn writes_an_audit_record() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package_with_files(
+        &input_dir,
+        valid_json_with_contact_details(),
+        None,
+        &[("parser.log", b"log")],
+    );
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap());
+    cmd.assert().success();
+
+    let audit: serde_json::Value =
+        serde_json::from_str(&read_to_string(output_dir.join("TST-2023.audit.json"))?)?;
+    assert_eq!(audit["input_file"], "TDR-2023.tar.gz");
+    assert_eq!(audit["output_file"], "TST-2023.tar.gz");
+    assert_eq!(audit["output_batch_reference"], "TST-2023");
+    assert_eq!(audit["removed"], serde_json::json!(["TDR-2023/parser.log"]));
+    assert_eq!(
+        audit["regenerated"],
+        serde_json::json!(["TST-2023/test.docx"])
+    );
+    assert_eq!(
+        audit["metadata_changes"],
+        serde_json::json!([
+            "/parameters/TDR/Contact-Email",
+            "/parameters/TDR/Contact-Name",
+            "/parameters/TDR/Document-Checksum-sha256"
+        ])
+    );
+    assert_eq!(audit["output_sha256"].as_str().unwrap().len(), 64);
+    assert!(!audit.to_string().contains("test@example.com"));
+    Ok(())
+}
+
+#[test]
+fn verify_passes_an_anonymised_package() -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let json = valid_json_with_contact_details().replace(r#""name": "test""#, r#""uri": "test""#);
+    let original: PathBuf = create_package(&input_dir, &json, None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    Command::cargo_bin("anonymiser")?
+        .arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .assert()
+        .success();
+
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    cmd.arg("verify")
+        .arg(original.to_str().unwrap())
+        .arg(output_dir.join("TST-2023.tar.gz").to_str().unwrap());
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Verify TST-2023.tar.gz against TDR-2023.tar.gz: PASS",
+        ))
+        .stdout(predicate::str::contains(
+            "Checksum: TST-2023/test.docx matches the metadata",
+        ));
+    Ok(())
+}
+
+#[test]
+fn verify_fails_if_the_package_leaks_values() -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let files: [(&str, &[u8]); 1] = [("parser.log", b"WARNING test@example.com uploaded\n")];
+    let original: PathBuf =
+        create_package_with_files(&input_dir, valid_json_with_contact_details(), None, &files);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    Command::cargo_bin("anonymiser")?
+        .arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--parser-log")
+        .arg("keep")
+        .assert()
+        .success();
+
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    cmd.arg("verify")
+        .arg(original.to_str().unwrap())
+        .arg(output_dir.join("TST-2023.tar.gz").to_str().unwrap())
+        .arg("--format")
+        .arg("json");
+    let output = cmd.assert().failure().get_output().stdout.clone();
+    let report: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!(report["passed"], false);
+    assert!(report["leaks"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!({
+            "file": "TST-2023/parser.log",
+            "location": "line 1",
+            "field": "/parameters/TDR/Contact-Email"
+        })));
+    assert!(!String::from_utf8(output)?.contains("test@example.com"));
+    Ok(())
+}
+
+#[test]
+fn error_if_the_metadata_contains_personal_data() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let json = valid_json().replace(
+        r#""name": "test""#,
+        r#""name": "test", "notes": "from test@example.com""#,
+    );
+    create_package(&input_dir, &json, None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--pii")
+        .arg("fail");
+
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "Possible personal data found in the metadata json: email address at /parameters/PARSER/notes",
+    ));
+    assert!(!output_dir.join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn uses_the_reference_from_the_csv_file() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    let csv_dir: TempDir = TempDir::new().unwrap();
+    let csv_path: PathBuf = csv_dir.join("mapping.csv");
+    write(&csv_path, "original,anonymised\nTDR-2023,TST-2020-XYZ\n")?;
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--reference-mode")
+        .arg("csv")
+        .arg("--reference-csv")
+        .arg(csv_path.to_str().unwrap());
+    cmd.assert().success();
+
+    let output_tar_gz: PathBuf = output_dir.join("TST-2020-XYZ.tar.gz");
+    let entries = read_package_entries(&output_tar_gz);
+    assert!(entries.contains_key("TST-2020-XYZ/TRE-TST-2020-XYZ-metadata.json"));
+    assert!(entries.keys().all(|path| path.starts_with("TST-2020-XYZ/")));
+    let audit: serde_json::Value =
+        serde_json::from_slice(&read(output_dir.join("TST-2020-XYZ.audit.json"))?)?;
+    assert_eq!(audit["output_batch_reference"], "TST-2020-XYZ");
+    assert_eq!(audit["policy"]["reference_mapping"], "explicit");
+    Ok(())
+}
+
+#[test]
+fn keyed_hash_references_depend_on_the_key() -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_file_names = |key: &str| -> Vec<String> {
+        let output_dir: TempDir = TempDir::new().unwrap();
+        Command::cargo_bin("anonymiser")
+            .unwrap()
+            .env("ANONYMISER_KEY", key)
+            .arg("--input")
+            .arg(input_dir.path().to_str().unwrap())
+            .arg("--output")
+            .arg(output_dir.path().to_str().unwrap())
+            .arg("--reference-mode")
+            .arg("keyed-hash")
+            .assert()
+            .success();
+        let mut file_names: Vec<String> = read_dir(output_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        file_names.sort();
+        file_names
+    };
+    let file_names = output_file_names("test-key");
+    assert_eq!(file_names, output_file_names("test-key"));
+    assert_ne!(file_names, output_file_names("other-key"));
+    assert_eq!(file_names.len(), 2);
+    assert!(file_names[0].starts_with("TST-2023-"));
+    assert!(file_names[1].starts_with("TST-2023-") && file_names[1].ends_with(".tar.gz"));
+    Ok(())
+}
+
+#[test]
+fn error_if_the_reference_is_not_in_the_csv_file() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    let csv_dir: TempDir = TempDir::new().unwrap();
+    let csv_path: PathBuf = csv_dir.join("mapping.csv");
+    write(&csv_path, "TDR-2024,TST-2024-XYZ\n")?;
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--reference-mode")
+        .arg("csv")
+        .arg("--reference-csv")
+        .arg(csv_path.to_str().unwrap());
+
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "Invalid batch reference 'TDR-2023': It is not in the reference mapping",
+    ));
+    Ok(())
+}
+
+#[test]
+fn error_if_the_package_has_unexpected_files() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package_with_files(&input_dir, valid_json(), None, &[("notes.txt", b"notes")]);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap());
+
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "Unexpected files in the package: TDR-2023/notes.txt",
+    ));
+    assert!(!output_dir.join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn keeps_allowed_files_and_drops_unexpected_files() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package_with_files(
+        &input_dir,
+        valid_json(),
+        None,
+        &[("notes/a.txt", b"a"), ("notes.txt", b"notes")],
+    );
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--allow-file")
+        .arg("notes/*.txt")
+        .arg("--unexpected-files")
+        .arg("drop");
+    cmd.assert().success();
+
+    let entries = read_package_entries(&output_dir.join("TST-2023.tar.gz"));
+    assert_eq!(entries["TST-2023/notes/a.txt"], b"a");
+    assert!(!entries.contains_key("TST-2023/notes.txt"));
+    let audit: serde_json::Value =
+        serde_json::from_str(&read_to_string(output_dir.join("TST-2023.audit.json"))?)?;
+    assert_eq!(audit["removed"], serde_json::json!(["TDR-2023/notes.txt"]));
+    assert_eq!(
+        audit["files"],
+        serde_json::json!([
+            {"path": "TDR-2023/TRE-TDR-2023-metadata.json", "decision": "expected"},
+            {"path": "TDR-2023/notes.txt", "decision": "dropped"},
+            {"path": "TDR-2023/notes/a.txt", "decision": "allowed"},
+            {"path": "TDR-2023/test.docx", "decision": "expected"}
+        ])
+    );
+    assert_eq!(
+        audit["policy"]["allowed_files"],
+        serde_json::json!(["notes/*.txt"])
+    );
+    Ok(())
+}
+
+#[test]
+fn error_if_an_entry_is_larger_than_the_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package_with_files(
+        &input_dir,
+        valid_json(),
+        None,
+        &[("TDR-2023.xml", b"<xml/>")],
+    );
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--max-entry-size")
+        .arg("5");
+
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "is larger than the limit of 5 bytes",
+    ));
+    assert!(!output_dir.join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn processes_the_other_packages_and_prints_a_summary_if_one_fails(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    write(input_dir.join("TDR-2022.tar.gz"), "")?;
+    create_package(&input_dir, valid_json(), None);
+    write(input_dir.join("notes.txt"), "notes")?;
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap());
+
+    cmd.assert()
+        .code(2)
+        .stdout(predicate::str::contains(
+            "Summary: 1 succeeded, 1 failed, 1 skipped",
+        ))
+        .stdout(predicate::str::contains("  Failed: TDR-2022.tar.gz: "))
+        .stdout(predicate::str::contains(
+            "  Skipped: notes.txt: not matched by the include patterns",
+        ));
+    assert!(output_dir.join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn stops_at_the_first_failure_with_fail_fast() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    write(input_dir.join("TDR-2022.tar.gz"), "")?;
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--fail-fast");
+
+    cmd.assert()
+        .code(1)
+        .stdout(predicate::str::contains("Summary").not());
+    assert!(!output_dir.join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn prints_the_summary_as_json() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_package(&input_dir, valid_json(), None);
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--format")
+        .arg("json");
+
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let stdout: String = String::from_utf8(output)?;
+    let summary: serde_json::Value = serde_json::from_str(&stdout[stdout.find('{').unwrap()..])?;
+    assert_eq!(summary["succeeded"], serde_json::json!(["TDR-2023.tar.gz"]));
+    assert_eq!(summary["failed"], serde_json::json!([]));
+    Ok(())
+}
+
+#[test]
+fn processes_packages_in_parallel_with_jobs() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    write(input_dir.join("TDR-2022.tar.gz"), "")?;
+    create_package(&input_dir, valid_json(), None);
+    write(input_dir.join("TDR-2024.tar.gz"), "")?;
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .arg("--jobs")
+        .arg("2");
+
+    cmd.assert()
+        .code(2)
+        .stdout(
+            predicate::str::is_match(r"@anonymiser-worker-\d\] Processed TDR-2023\.tar\.gz")
+                .unwrap(),
+        )
+        .stdout(predicate::str::contains(
+            "Summary: 1 succeeded, 2 failed, 0 skipped\n  Failed: TDR-2022.tar.gz: ",
+        ))
+        .stdout(predicate::str::contains("\n  Failed: TDR-2024.tar.gz: "));
+    assert!(output_dir.join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn watches_the_input_folder_until_interrupted() -> Result<(), Box<dyn std::error::Error>> {
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let output_dir: TempDir = TempDir::new().unwrap();
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("anonymiser"))
+        .arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .args(["--watch", "--poll-interval", "1", "--settle-time", "1"])
+        .arg("--move-processed")
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    create_package(&input_dir, valid_json(), None);
+    let processed_path: PathBuf = input_dir.join("processed").join("TDR-2023.tar.gz");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    while !processed_path.exists() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()?;
+    let output = child.wait_with_output()?;
+
+    assert!(output.status.success());
+    assert!(processed_path.exists());
+    assert!(output_dir.join("TST-2023.tar.gz").exists());
+    assert!(String::from_utf8(output.stdout)?.contains("Stopped watching"));
+    Ok(())
+}
+
+#[test]
+fn finds_packages_in_subfolders_and_mirrors_them_with_recursive(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    create_dir_all(input_dir.join("2023").join("old"))?;
+    rename(
+        create_package(&input_dir, valid_json(), None),
+        input_dir.join("2023").join("TDR-2023.tar.gz"),
+    )?;
+    // The package is built from this folder, which would be found too
+    remove_dir_all(input_dir.join("TDR-2023"))?;
+    write(
+        input_dir.join("2023").join("old").join("TDR-2022.tar.gz"),
+        "",
+    )?;
+    write(input_dir.join("2023").join("notes.txt"), "notes")?;
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.path().to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap())
+        .args(["--recursive", "--mirror", "--exclude", "*/old/*"]);
+
+    cmd.assert().success().stdout(predicate::str::contains(
+        "Summary: 1 succeeded, 0 failed, 2 skipped\n  Skipped: 2023/notes.txt: not matched by the include patterns\n  Skipped: 2023/old/TDR-2022.tar.gz: matched by the exclude patterns",
+    ));
+    assert!(output_dir.join("2023").join("TST-2023.tar.gz").exists());
+    Ok(())
+}
+
+#[test]
+fn error_if_the_input_folder_is_missing() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd: Command = Command::cargo_bin("anonymiser")?;
+    let input_dir: TempDir = TempDir::new().unwrap();
+    let output_dir: TempDir = TempDir::new().unwrap();
+    cmd.arg("--input")
+        .arg(input_dir.join("missing").to_str().unwrap())
+        .arg("--output")
+        .arg(output_dir.path().to_str().unwrap());
+
+    cmd.assert()
+        .code(1)
+        .stdout(predicate::str::contains("Cannot read the input folder"));
+    Ok(())
+}
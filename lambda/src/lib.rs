@@ -6,18 +6,40 @@
 //! ```json
 //! {
 //!   "parameters": {
+//!     "status": "ok",
+//!     "reference": "TDR-2023-ABC",
 //!     "s3Bucket": "input-bucket",
 //!     "s3Key": "TRE-TDR-2023-ABC.tar.gz"
 //!   }
 //! }
 //! ```
 //! The lambda will:
+//! * Check the reference is a valid batch reference, and the same as the reference in the name of the package
 //! * Download the file from S3 to local disk
-//! * Anonymise it using the anonymise library
-//! * Upload it to S3 using the `OUTPUT_BUCKET` environment variable
-//! * Send the SQS message to the queue specified in the `OUTPUT_QUEUE` environment variable
+//! * Anonymise it using the anonymise library, streaming it to the output file so only the input and output need space in `/tmp`.
+//!   The streaming processor writes the same bytes as the script's `process_package`, so the lambda and the script agree
+//! * Upload it and its audit record to S3 using the `OUTPUT_BUCKET` environment variable
+//! * Send the SQS message with the anonymised reference, such as `TST-2023-ABC`, to the queue specified in the `OUTPUT_QUEUE` environment variable
+//!
+//! The anonymised reference is chosen by the `REFERENCE_MODE` environment variable. It is `prefix-swap` by default,
+//! which only replaces the source system. `random` replaces the identifier with a random one, and `keyed-hash`
+//! replaces it with a keyed hash of the reference using the key in the `ANONYMISER_KEY` environment variable.
+//! `csv` looks the reference up in the CSV file at the path in the `REFERENCE_MAPPING_CSV` environment variable,
+//! with a line such as `TDR-2023-ABC,TST-2023-XYZ` for each reference.
+//! The same reference is used for the package folder, the metadata file, the tar.gz file and the message.
+//!
+//! Files in the package which aren't named in the metadata json fail the package. Other files can be allowed with
+//! a comma separated list of glob patterns in the `ALLOWED_FILES` environment variable, such as `images/*.png,notes/**`.
+//! The `UNEXPECTED_FILES` environment variable can be set to `drop` or `keep` to leave out or copy any other files instead.
+//!
+//! Packages with absolute paths, `..` components, links or devices are refused. So are packages over the limits in the
+//! `MAX_TOTAL_SIZE` and `MAX_ENTRY_SIZE` environment variables, in bytes, and `MAX_ENTRIES`. They have the same defaults as the script.
 
-use anonymiser_lib::process_package;
+use anonymiser_lib::{
+    process_package_streaming, AllowList, BatchReference, ExtractionLimits, Policy,
+    ProcessedPackage, PseudonymisationKey, ReferenceMapping, UnexpectedFileAction,
+    DEFAULT_MAX_ENTRIES, DEFAULT_MAX_ENTRY_SIZE, DEFAULT_MAX_TOTAL_SIZE,
+};
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::{BehaviorVersion, SdkConfig};
 use aws_lambda_events::sqs::SqsMessage;
@@ -64,6 +86,19 @@ pub async fn process_record(
 
     let message_body: MessageBody = serde_json::from_str(body)?;
     let parameters = message_body.parameters;
+    let batch_reference: BatchReference = BatchReference::parse(&parameters.reference)?;
+    let package_file_name: &str = Path::new(&parameters.s3_key)
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .unwrap_or(&parameters.s3_key);
+    if BatchReference::from_package_file_name(package_file_name)? != batch_reference {
+        return Err(format!(
+            "The reference '{}' doesn't match the package {}",
+            parameters.reference, parameters.s3_key
+        )
+        .into());
+    }
+    let policy: Policy = policy_from_env()?;
     let input_file_path = download(
         &s3_client,
         parameters.s3_bucket,
@@ -73,24 +108,43 @@ pub async fn process_record(
     .await?;
     let output_path = &working_directory.join(PathBuf::from("output"));
     fs::create_dir_all(output_path)?;
-    let output_tar_path = process_package(output_path, &input_file_path)?;
-    let file_name = output_tar_path
+    let processed_package: ProcessedPackage =
+        process_package_streaming(output_path, &input_file_path, &policy)?;
+    let file_name = processed_package
+        .output_path
         .file_name()
         .and_then(|file_name_as_os_string| file_name_as_os_string.to_str())
         .expect("Cannot parse file name from output path");
+    let audit_file_name = processed_package
+        .audit_path
+        .file_name()
+        .and_then(|file_name_as_os_string| file_name_as_os_string.to_str())
+        .expect("Cannot parse file name from audit path");
 
     let output_bucket = std::env::var("OUTPUT_BUCKET")?;
-    upload(&s3_client, &output_tar_path, &output_bucket, file_name).await?;
+    upload(
+        &s3_client,
+        &processed_package.output_path,
+        &output_bucket,
+        file_name,
+    )
+    .await?;
+    upload(
+        &s3_client,
+        &processed_package.audit_path,
+        &output_bucket,
+        audit_file_name,
+    )
+    .await?;
 
     let output_queue = std::env::var("OUTPUT_QUEUE")?;
-    let reference = parameters.reference.replace("TDR", "TST");
     let status = parameters.status;
     let output_message_body = MessageBody {
         parameters: S3Details {
             s3_bucket: output_bucket,
             s3_key: file_name.to_string(),
             status,
-            reference,
+            reference: processed_package.audit.output_batch_reference,
         },
     };
     let message_string = serde_json::to_string(&output_message_body)?;
@@ -103,6 +157,72 @@ pub async fn process_record(
     Ok(output_path.clone())
 }
 
+/// # Creates the anonymisation policy
+///
+/// This uses the default policy with the reference mapping from the `REFERENCE_MODE` environment variable.
+fn policy_from_env() -> Result<Policy, Error> {
+    let reference_mapping: ReferenceMapping = match std::env::var("REFERENCE_MODE").ok().as_deref()
+    {
+        None | Some("prefix-swap") => ReferenceMapping::PrefixSwap,
+        Some("random") => ReferenceMapping::Random,
+        Some("keyed-hash") => ReferenceMapping::KeyedHash(PseudonymisationKey::from_env()?),
+        Some("csv") => {
+            let csv_file_path: String = std::env::var("REFERENCE_MAPPING_CSV").map_err(|_| {
+                "The REFERENCE_MAPPING_CSV environment variable is needed for REFERENCE_MODE 'csv'"
+            })?;
+            ReferenceMapping::load_csv(Path::new(&csv_file_path))?
+        }
+        Some(mode) => {
+            return Err(format!(
+                "Unknown REFERENCE_MODE '{mode}', expected prefix-swap, random, keyed-hash or csv"
+            )
+            .into())
+        }
+    };
+    let unexpected_files: UnexpectedFileAction =
+        match std::env::var("UNEXPECTED_FILES").ok().as_deref() {
+            None | Some("fail") => UnexpectedFileAction::Fail,
+            Some("drop") => UnexpectedFileAction::Drop,
+            Some("keep") => UnexpectedFileAction::Keep,
+            Some(action) => {
+                return Err(format!(
+                    "Unknown UNEXPECTED_FILES '{action}', expected fail, drop or keep"
+                )
+                .into())
+            }
+        };
+    let allowed_files: Vec<String> = std::env::var("ALLOWED_FILES")
+        .map(|patterns| {
+            patterns
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Policy {
+        reference_mapping,
+        allow_list: AllowList::new(&allowed_files, unexpected_files)?,
+        limits: ExtractionLimits {
+            max_total_size: limit_from_env("MAX_TOTAL_SIZE", DEFAULT_MAX_TOTAL_SIZE)?,
+            max_entries: limit_from_env("MAX_ENTRIES", DEFAULT_MAX_ENTRIES)?,
+            max_entry_size: limit_from_env("MAX_ENTRY_SIZE", DEFAULT_MAX_ENTRY_SIZE)?,
+        },
+        ..Policy::default()
+    })
+}
+
+/// # Read an extraction limit from an environment variable, or use the default if it isn't set
+fn limit_from_env(name: &str, default: u64) -> Result<u64, Error> {
+    match std::env::var(name) {
+        Ok(limit) => limit
+            .parse()
+            .map_err(|_| format!("{name} must be a whole number, not '{limit}'").into()),
+        Err(_) => Ok(default),
+    }
+}
+
 /// # Uploads the specified file
 ///
 /// This will upload the contents of the file in `body_path` to the `bucket` with the specified `key`
@@ -175,7 +295,15 @@ async fn create_s3_client(potential_endpoint_url: Option<&str>) -> S3Client {
 
 #[cfg(test)]
 mod test {
-    use crate::{aws_config, create_s3_client};
+    use crate::{aws_config, create_s3_client, policy_from_env};
+    use anonymiser_lib::{
+        BatchReference, ExtractionLimits, ReferenceMapping, UnexpectedFileAction,
+    };
+    use assert_fs::TempDir;
+    use std::sync::Mutex;
+
+    /// The policy tests change environment variables, so they can't run at the same time
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
 
     #[tokio::test]
     async fn test_create_client_with_default_region() {
@@ -199,4 +327,95 @@ mod test {
             "https://example.com"
         );
     }
+
+    #[test]
+    fn test_policy_from_env_reference_mapping() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("REFERENCE_MODE");
+        assert_eq!(
+            policy_from_env().unwrap().reference_mapping,
+            ReferenceMapping::PrefixSwap
+        );
+        std::env::set_var("REFERENCE_MODE", "random");
+        assert_eq!(
+            policy_from_env().unwrap().reference_mapping,
+            ReferenceMapping::Random
+        );
+        std::env::set_var("REFERENCE_MODE", "sequential");
+        assert_eq!(
+            policy_from_env().err().unwrap().to_string(),
+            "Unknown REFERENCE_MODE 'sequential', expected prefix-swap, random, keyed-hash or csv"
+        );
+        std::env::remove_var("REFERENCE_MODE");
+    }
+
+    #[test]
+    fn test_policy_from_env_csv_reference_mapping() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        std::env::set_var("REFERENCE_MODE", "csv");
+        std::env::remove_var("REFERENCE_MAPPING_CSV");
+        assert_eq!(
+            policy_from_env().err().unwrap().to_string(),
+            "The REFERENCE_MAPPING_CSV environment variable is needed for REFERENCE_MODE 'csv'"
+        );
+        let csv_dir = TempDir::new().unwrap();
+        let csv_file_path = csv_dir.join("mapping.csv");
+        std::fs::write(
+            &csv_file_path,
+            "original,anonymised\nTDR-2023-ABC,TST-2023-XYZ\n",
+        )
+        .unwrap();
+        std::env::set_var("REFERENCE_MAPPING_CSV", &csv_file_path);
+        let reference_mapping = policy_from_env().unwrap().reference_mapping;
+        assert_eq!(
+            reference_mapping
+                .map(&BatchReference::parse("TDR-2023-ABC").unwrap())
+                .unwrap()
+                .as_str(),
+            "TST-2023-XYZ"
+        );
+        std::env::remove_var("REFERENCE_MAPPING_CSV");
+        std::env::remove_var("REFERENCE_MODE");
+    }
+
+    #[test]
+    fn test_policy_from_env_allow_list() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("UNEXPECTED_FILES");
+        std::env::remove_var("ALLOWED_FILES");
+        let allow_list = policy_from_env().unwrap().allow_list;
+        assert_eq!(allow_list.action, UnexpectedFileAction::Fail);
+        assert!(allow_list.patterns().is_empty());
+        std::env::set_var("UNEXPECTED_FILES", "drop");
+        std::env::set_var("ALLOWED_FILES", "images/*.png, notes/**,");
+        let allow_list = policy_from_env().unwrap().allow_list;
+        assert_eq!(allow_list.action, UnexpectedFileAction::Drop);
+        assert_eq!(allow_list.patterns(), ["images/*.png", "notes/**"]);
+        std::env::set_var("UNEXPECTED_FILES", "ignore");
+        assert_eq!(
+            policy_from_env().err().unwrap().to_string(),
+            "Unknown UNEXPECTED_FILES 'ignore', expected fail, drop or keep"
+        );
+        std::env::remove_var("UNEXPECTED_FILES");
+        std::env::remove_var("ALLOWED_FILES");
+    }
+
+    #[test]
+    fn test_policy_from_env_limits() {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("MAX_TOTAL_SIZE");
+        std::env::set_var("MAX_ENTRIES", "10");
+        let limits = policy_from_env().unwrap().limits;
+        assert_eq!(limits.max_entries, 10);
+        assert_eq!(
+            limits.max_total_size,
+            ExtractionLimits::default().max_total_size
+        );
+        std::env::set_var("MAX_ENTRIES", "ten");
+        assert_eq!(
+            policy_from_env().err().unwrap().to_string(),
+            "MAX_ENTRIES must be a whole number, not 'ten'"
+        );
+        std::env::remove_var("MAX_ENTRIES");
+    }
 }
@@ -5,7 +5,6 @@ use std::env::set_var;
 use std::fs::{read, write};
 use std::path::PathBuf;
 use testlib::*;
-use tokio;
 use wiremock::http::Method;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, MockServer, ResponseTemplate};
@@ -25,7 +24,7 @@ async fn downloads_the_live_package_uploads_anonymised_package_send_to_queue() {
         .unwrap();
     let test_upload_key = test_download_key.replace("TDR", "TST");
     let test_string = format!(
-        r#"{{"parameters": {{"status":"ok","reference":"test-reference", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#,
+        r#"{{"parameters": {{"status":"ok","reference":"TDR-2023", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#,
     );
     let message = SqsMessage {
         body: Some(test_string),
@@ -34,6 +33,7 @@ async fn downloads_the_live_package_uploads_anonymised_package_send_to_queue() {
 
     let get_object_path = format!("/{test_input_bucket}/{test_download_key}");
     let put_object_path = format!("/{test_output_bucket}/{test_upload_key}");
+    let put_audit_path = format!("/{test_output_bucket}/TST-2023.audit.json");
     let mock_s3_server = MockServer::start().await;
     let mock_sqs_server = MockServer::start().await;
     let bytes = read(tar_path).unwrap();
@@ -44,7 +44,12 @@ async fn downloads_the_live_package_uploads_anonymised_package_send_to_queue() {
         .mount(&mock_s3_server)
         .await;
     Mock::given(method("PUT"))
-        .and(path(put_object_path))
+        .and(path(put_object_path.clone()))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_s3_server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path(put_audit_path.clone()))
         .respond_with(ResponseTemplate::new(200))
         .mount(&mock_s3_server)
         .await;
@@ -67,22 +72,24 @@ async fn downloads_the_live_package_uploads_anonymised_package_send_to_queue() {
     let s3_requests = &mock_s3_server.received_requests().await.unwrap();
     let put_request = s3_requests
         .iter()
-        .filter(|req| req.method == Method::Put)
-        .last()
+        .rfind(|req| req.method == Method::Put && req.url.path() == put_object_path)
         .unwrap();
+    assert!(s3_requests
+        .iter()
+        .any(|req| req.method == Method::Put && req.url.path() == put_audit_path));
 
     let sqs_requests = &mock_sqs_server.received_requests().await.unwrap();
     let send_message_request = sqs_requests.last().unwrap();
     let body = &send_message_request.body;
     let sqs_message_string = String::from_utf8(body.to_vec()).unwrap();
-    let expected_string = r#"{"QueueUrl":"https://example.com","MessageBody":"{\"parameters\":{\"status\":\"ok\",\"reference\":\"test-reference\",\"s3Bucket\":\"test-output-bucket\",\"s3Key\":\"TST-2023.tar.gz\"}}"}"#;
+    let expected_string = r#"{"QueueUrl":"https://example.com","MessageBody":"{\"parameters\":{\"status\":\"ok\",\"reference\":\"TST-2023\",\"s3Bucket\":\"test-output-bucket\",\"s3Key\":\"TST-2023.tar.gz\"}}"}"#;
     assert_eq!(sqs_message_string, expected_string);
 
     let path_to_output_file = input_dir.to_owned().join("output.tar.gz");
     let output_dir = TempDir::new().unwrap();
     write(&path_to_output_file, &put_request.body[5..]).unwrap();
     decompress_test_file(&path_to_output_file, &output_dir);
-    let metadata_json = get_metadata_json_fields(&output_dir.to_owned());
+    let metadata_json = get_metadata_json_fields(&output_dir);
     assert_eq!(metadata_json.contact_email, "XXXXXXXXX");
     assert_eq!(metadata_json.contact_name, "XXXXXXXXX");
     assert_eq!(
@@ -98,7 +105,7 @@ async fn error_if_key_is_missing_from_bucket() {
     set_var("OUTPUT_BUCKET", test_output_bucket);
     set_var("OUTPUT_QUEUE", "https://example.com");
 
-    let test_download_key = "missing-key.tar.gz";
+    let test_download_key = "TDR-2023.tar.gz";
     let get_object_path = format!("/{test_input_bucket}/{test_download_key}");
     let mock_s3_server = MockServer::start().await;
     let mock_sqs_server = MockServer::start().await;
@@ -109,7 +116,7 @@ async fn error_if_key_is_missing_from_bucket() {
         .mount(&mock_s3_server)
         .await;
     let test_string = format!(
-        r#"{{"parameters": {{"status":"ok","reference":"test-reference", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#
+        r#"{{"parameters": {{"status":"ok","reference":"TDR-2023", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#
     );
     let message = SqsMessage {
         body: Some(test_string),
@@ -133,7 +140,7 @@ async fn error_if_key_is_not_a_tar_file() {
     set_var("OUTPUT_BUCKET", test_output_bucket);
     set_var("OUTPUT_QUEUE", "https://example.com");
 
-    let test_download_key = "test.tar.gz";
+    let test_download_key = "TDR-2023.tar.gz";
     let get_object_path = format!("/{test_input_bucket}/{test_download_key}");
     let mock_s3_server = MockServer::start().await;
     let mock_sqs_server = MockServer::start().await;
@@ -143,7 +150,7 @@ async fn error_if_key_is_not_a_tar_file() {
         .mount(&mock_s3_server)
         .await;
     let test_string = format!(
-        r#"{{"parameters": {{"status":"ok","reference":"test-reference", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#
+        r#"{{"parameters": {{"status":"ok","reference":"TDR-2023", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#
     );
     let message = SqsMessage {
         body: Some(test_string),
@@ -157,7 +164,44 @@ async fn error_if_key_is_not_a_tar_file() {
     )
     .await
     .unwrap_err();
-    assert_eq!(err.to_string(), "failed to iterate over archive")
+    assert_eq!(
+        err.to_string(),
+        "Cannot read the archive /tmp/TDR-2023.tar.gz: failed to iterate over archive: unexpected end of file"
+    )
+}
+
+#[tokio::test]
+async fn error_if_reference_is_invalid() {
+    let test_string = r#"{"parameters": {"status":"ok","reference":"test-reference", "s3Bucket": "bucket", "s3Key": "TDR-2023.tar.gz"}}"#;
+    let message = SqsMessage {
+        body: Some(test_string.to_string()),
+        ..Default::default()
+    };
+    let uri = Some("https://example.com");
+    let err = process_record(&message, TempDir::new().unwrap().to_owned(), uri, uri)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid batch reference 'test-reference': The source system 'test' must be upper case letters"
+    );
+}
+
+#[tokio::test]
+async fn error_if_reference_does_not_match_the_package() {
+    let test_string = r#"{"parameters": {"status":"ok","reference":"TDR-2023", "s3Bucket": "bucket", "s3Key": "TRE-TDR-2024.tar.gz"}}"#;
+    let message = SqsMessage {
+        body: Some(test_string.to_string()),
+        ..Default::default()
+    };
+    let uri = Some("https://example.com");
+    let err = process_record(&message, TempDir::new().unwrap().to_owned(), uri, uri)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The reference 'TDR-2023' doesn't match the package TRE-TDR-2024.tar.gz"
+    );
 }
 
 #[tokio::test]
@@ -175,7 +219,7 @@ async fn error_if_upload_fails() {
         .unwrap();
     let test_upload_key = test_download_key.replace("TDR", "TST");
     let test_string = format!(
-        r#"{{"parameters": {{"status":"ok","reference":"test-reference", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#
+        r#"{{"parameters": {{"status":"ok","reference":"TDR-2023", "s3Bucket": "{test_input_bucket}", "s3Key": "{test_download_key}"}}}}"#
     );
     let message = SqsMessage {
         body: Some(test_string),
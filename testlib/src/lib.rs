@@ -2,9 +2,13 @@
 //!
 //! These are common functions used in the integration tests for the script and for the lambda.
 use assert_fs::TempDir;
+use docx_rs::{Docx, Footer, Header, Paragraph, Run, Table, TableCell, TableRow};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use image::{ImageFormat, Rgb, RgbImage};
 use serde_json::Value;
+use std::collections::BTreeMap;
 use std::fs::*;
+use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
 use tar::Archive;
 use tar::Builder;
@@ -18,6 +22,18 @@ pub struct MetadataJson {
 
 /// # Creates a test tar.gz file
 pub fn create_package(input_dir: &TempDir, json: &str, file_name: Option<String>) -> PathBuf {
+    create_package_with_files(input_dir, json, file_name, &[])
+}
+
+/// # Creates a test tar.gz file with extra files in the package folder
+///
+/// An empty `test.docx` is added unless the metadata json names a different docx.
+pub fn create_package_with_files(
+    input_dir: &TempDir,
+    json: &str,
+    file_name: Option<String>,
+    files: &[(&str, &[u8])],
+) -> PathBuf {
     let package_dir: PathBuf = input_dir.join(PathBuf::from("TDR-2023"));
     let tar_path: PathBuf = input_dir.join(PathBuf::from(
         file_name.unwrap_or(String::from("TDR-2023.tar.gz")),
@@ -26,8 +42,22 @@ pub fn create_package(input_dir: &TempDir, json: &str, file_name: Option<String>
     let metadata_path: PathBuf = package_dir.join(Path::new("TRE-TDR-2023-metadata.json"));
     let docx_path: PathBuf = package_dir.join(Path::new("test.docx"));
 
+    let docx_file_name: Option<String> =
+        serde_json::from_str::<Value>(json).ok().and_then(|value| {
+            value
+                .pointer("/parameters/TRE/payload/filename")
+                .and_then(Value::as_str)
+                .map(String::from)
+        });
     write(metadata_path, json).unwrap();
-    write(docx_path, "").unwrap();
+    if docx_file_name.is_none_or(|file_name| file_name == "test.docx") {
+        write(docx_path, "").unwrap();
+    }
+    for (file_name, contents) in files {
+        let file_path: PathBuf = package_dir.join(file_name);
+        create_dir_all(file_path.parent().unwrap()).unwrap();
+        write(file_path, contents).unwrap();
+    }
     let tar_gz: File = File::create(tar_path.clone()).unwrap();
     let enc: GzEncoder<File> = GzEncoder::new(tar_gz, Compression::default());
     let mut tar: Builder<GzEncoder<File>> = Builder::new(enc);
@@ -35,6 +65,41 @@ pub fn create_package(input_dir: &TempDir, json: &str, file_name: Option<String>
     tar_path
 }
 
+/// # Creates a test docx with a header, footer, heading and table
+pub fn test_docx() -> Vec<u8> {
+    let mut docx: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    Docx::new()
+        .header(
+            Header::new().add_paragraph(
+                Paragraph::new().add_run(Run::new().add_text("Neutral Citation 2023")),
+            ),
+        )
+        .footer(Footer::new().add_paragraph(Paragraph::new().add_run(Run::new().add_text("Page"))))
+        .add_paragraph(
+            Paragraph::new()
+                .style("Heading1")
+                .add_run(Run::new().add_text("Smith v Jones")),
+        )
+        .add_table(Table::new(vec![TableRow::new(vec![TableCell::new()
+            .add_paragraph(
+                Paragraph::new().add_run(Run::new().add_text("Mr A. Smith & Co")),
+            )])]))
+        .build()
+        .pack(&mut docx)
+        .unwrap();
+    docx.into_inner()
+}
+
+/// # Creates a test image with a pattern, so it isn't the same as a placeholder
+pub fn test_image(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+    let image: RgbImage = RgbImage::from_fn(width, height, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, 0])
+    });
+    let mut image_bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    image.write_to(&mut image_bytes, format).unwrap();
+    image_bytes.into_inner()
+}
+
 /// # Decompresses the test tar.gz file
 pub fn decompress_test_file(path_to_tar: &PathBuf, output_path: &TempDir) {
     let tar_gz: File = File::open(path_to_tar).unwrap();
@@ -43,6 +108,23 @@ pub fn decompress_test_file(path_to_tar: &PathBuf, output_path: &TempDir) {
     archive.unpack(output_path).unwrap();
 }
 
+/// # Reads the path and contents of every file in a tar.gz file
+pub fn read_package_entries(path_to_tar: &Path) -> BTreeMap<String, Vec<u8>> {
+    let tar_gz: File = File::open(path_to_tar).unwrap();
+    let mut archive: Archive<GzDecoder<File>> = Archive::new(GzDecoder::new(tar_gz));
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path: String = entry.path().unwrap().to_string_lossy().to_string();
+            let mut contents: Vec<u8> = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            (path, contents)
+        })
+        .collect()
+}
+
 /// # Read the metadata.json file and parse the fields to be anonymised
 pub fn get_metadata_json_fields(output_dir: &Path) -> MetadataJson {
     let metadata: String = read_to_string(
@@ -105,3 +187,53 @@ pub fn valid_json() -> &'static str {
     }
     "#
 }
+
+/// # A valid input string with contact details
+pub fn valid_json_with_contact_details() -> &'static str {
+    r#"
+    {
+      "parameters": {
+        "PARSER": {
+          "name": "test"
+        },
+        "TDR": {
+          "Contact-Email": "test@example.com",
+          "Contact-Name": "test-name",
+          "Document-Checksum-sha256": "3c7b9ef49d36659762c34c63bae05b4cf07d6406c2736720385ed0c6f015840a"
+        },
+        "TRE": {
+          "payload": {
+            "filename": "test.docx"
+          }
+        }
+      }
+    }
+    "#
+}
+
+/// # A LegalDocML judgment with party and judge names
+pub fn judgment_xml() -> &'static str {
+    r##"<?xml version="1.0" encoding="utf-8"?>
+<akomaNtoso xmlns="http://docs.oasis-open.org/legaldocml/ns/akn/3.0">
+  <judgment name="judgment">
+    <meta>
+      <identification source="#tna">
+        <FRBRWork>
+          <FRBRthis value="https://caselaw.nationalarchives.gov.uk/id/ewca/civ/2023/123"/>
+          <FRBRuri value="https://caselaw.nationalarchives.gov.uk/id/ewca/civ/2023/123"/>
+          <FRBRdate date="2023-01-01" name="judgment"/>
+          <FRBRname value="Smith v Jones"/>
+        </FRBRWork>
+      </identification>
+      <references source="#tna">
+        <TLCPerson eId="smith" href="/smith" showAs="Mr Smith"/>
+        <TLCRole eId="appellant" href="/appellant" showAs="Appellant"/>
+      </references>
+    </meta>
+    <header>
+      <party refersTo="#smith" role="#appellant">Mr Smith</party>
+      <judge refersTo="#judge">Lord Justice Jones &amp; Others</judge>
+    </header>
+  </judgment>
+</akomaNtoso>"##
+}